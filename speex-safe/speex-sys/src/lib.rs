@@ -23,7 +23,7 @@ mod test {
         let ptr = &mut char_ptr as *mut *const c_char;
         let ptr = ptr as *mut c_void;
         let c_str = unsafe {
-            speex_lib_ctl(SPEEX_LIB_GET_VERSION_STRING as i32, ptr);
+            speex_lib_ctl(SPEEX_LIB_GET_VERSION_STRING, ptr);
             CStr::from_ptr(char_ptr)
         };
         let version_str = format!("{c_str:?}");
@@ -10,6 +10,8 @@ use std::mem::MaybeUninit;
 
 use speex_sys::SpeexBits as SysBits;
 
+use crate::{InbandError, InbandMessage};
+
 /// A struct that holds bits to be read or written to
 ///
 /// Internally packs bits.
@@ -42,17 +44,6 @@ impl<'a> SpeexBits<'a> {
         }
     }
 
-    pub fn buffer<'b>(&mut self) -> &'a mut [u8] {
-        todo!("")
-        // if let Some(buffer_ref) = &mut self.buffer_ref {
-        // buffer_ref
-        // } else {
-        // let ptr = self.backing.chars as *mut u8;
-        // let len = 0;
-        // unsafe { from_raw_parts_mut(ptr, len) }
-        // }
-    }
-
     /// Creates a new SpeexBits with an existing buffer
     pub fn new_with_buffer(buffer: &'a mut [u8]) -> Self {
         let backing = unsafe {
@@ -88,6 +79,37 @@ impl<'a> SpeexBits<'a> {
         }
     }
 
+    /// Finishes the bitstream as a packet: inserts a terminator, returns the
+    /// bytes and resets the bits for the next packet.
+    pub fn take_packet(&mut self) -> Vec<u8> {
+        self.insert_terminator();
+        let mut packet = vec![0; self.num_bytes() as usize];
+        let written = self.write(&mut packet);
+        packet.truncate(written as usize);
+        self.reset();
+        packet
+    }
+
+    /// Returns the number of bits in the bitstream
+    pub fn num_bits(&self) -> u32 {
+        self.backing.nbBits as u32
+    }
+
+    /// Reads `count` bits starting at bit `position` of the bitstream, without
+    /// moving the read pointer. Returns `None` past the end of the bitstream.
+    pub(crate) fn bits_at(&self, position: u32, count: u32) -> Option<u32> {
+        if position + count > self.num_bits() {
+            return None;
+        }
+        let chars = self.backing.chars as *const u8;
+        let mut value = 0;
+        for bit in position..position + count {
+            let byte = unsafe { *chars.add((bit / 8) as usize) };
+            value = value << 1 | (byte >> (7 - bit % 8) & 1) as u32;
+        }
+        Some(value)
+    }
+
     /// Returns the number of bytes in the bitstream, including the last partial
     /// byte
     pub fn num_bytes(&mut self) -> i32 {
@@ -101,6 +123,17 @@ impl<'a> SpeexBits<'a> {
         }
     }
 
+    /// Appends an in-band message to the bitstream
+    pub fn pack_inband(&mut self, message: &InbandMessage) -> Result<(), InbandError> {
+        message.write(self)
+    }
+
+    /// Reads an in-band message if one is next in the stream, advancing the
+    /// read pointer past it
+    pub fn unpack_inband(&mut self) -> Option<InbandMessage> {
+        InbandMessage::read(self)
+    }
+
     /// Gets the value of the next bit in the stream without advancing the read
     /// pointer
     pub fn peek(&mut self) -> i32 {
@@ -222,13 +255,34 @@ mod test {
         assert_eq!(num_bytes, 1);
     }
 
+    #[test]
+    fn counts_bits() {
+        let mut bits = SpeexBits::new();
+        bits.pack(5, 3);
+        bits.pack(1, 7);
+        assert_eq!(bits.num_bits(), 10);
+        assert_eq!(bits.num_bytes(), 2);
+    }
+
     #[test]
     fn write_arbitrary_bytes() {
         let mut bits = SpeexBits::new();
-        let mut buffer = [12u8; 4];
-        bits.write(&mut buffer);
-        bits.rewind();
-        let num_bytes = bits.num_bytes();
-        assert_eq!(num_bytes, 4);
+        for _ in 0..4 {
+            bits.pack(12, 8);
+        }
+        let mut buffer = [0u8; 4];
+        let written = bits.write(&mut buffer);
+        assert_eq!(written, 4);
+        assert_eq!(buffer, [12u8; 4]);
+    }
+
+    #[test]
+    fn takes_terminated_packet() {
+        let mut bits = SpeexBits::new();
+        bits.pack(0, 5);
+
+        // Padded with a 0 bit and then 1 bits
+        assert_eq!(bits.take_packet(), vec![0b0000_0011]);
+        assert_eq!(bits.num_bits(), 0);
     }
 }
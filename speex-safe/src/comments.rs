@@ -0,0 +1,307 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::fmt::{Display, Formatter};
+
+use crate::get_version_string;
+
+/// Error type for reading a comment packet or adding a tag
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CommentsError {
+    /// The packet ended before all the lengths it declares
+    Truncated,
+    /// A tag key is empty, or has `=` or a character outside printable ASCII
+    InvalidKey,
+}
+
+impl Display for CommentsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommentsError::Truncated => write!(f, "Comment packet is truncated"),
+            CommentsError::InvalidKey => write!(f, "Tag key is not printable ASCII without '='"),
+        }
+    }
+}
+
+impl std::error::Error for CommentsError {}
+
+/// The comment header of a speex stream, sent as the packet right after the
+/// `SpeexHeader`.
+///
+/// This is a Vorbis comment block: a vendor string followed by any number of
+/// `KEY=value` comments. Comments are kept exactly as they were read, so
+/// unknown or malformed ones, and ones that aren't valid UTF-8, survive a
+/// round trip. Text that isn't valid UTF-8 is read lossily. Keys are compared
+/// without regard to case, as the format specifies.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SpeexComments {
+    vendor: Comment,
+    comments: Vec<Comment>,
+}
+
+/// A string from the packet, as read and as text
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Comment {
+    bytes: Vec<u8>,
+    text: String,
+}
+
+impl Comment {
+    fn new(text: String) -> Self {
+        Self {
+            bytes: text.clone().into_bytes(),
+            text,
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            text: String::from_utf8_lossy(bytes).into_owned(),
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+impl SpeexComments {
+    /// Creates an empty comment header with the given vendor string.
+    pub fn new(vendor: impl Into<String>) -> Self {
+        Self {
+            vendor: Comment::new(vendor.into()),
+            comments: Vec::new(),
+        }
+    }
+
+    /// Gets the vendor string, naming the software that wrote the stream
+    pub fn vendor(&self) -> &str {
+        &self.vendor.text
+    }
+
+    /// Gets the vendor string exactly as it appears in the packet
+    pub fn vendor_bytes(&self) -> &[u8] {
+        &self.vendor.bytes
+    }
+
+    /// Sets the vendor string
+    pub fn set_vendor(&mut self, vendor: impl Into<String>) {
+        self.vendor = Comment::new(vendor.into());
+    }
+
+    /// Gets all comments as they appear in the packet
+    pub fn comments(&self) -> impl Iterator<Item = &str> {
+        self.comments.iter().map(|comment| comment.text.as_str())
+    }
+
+    /// Gets all comments exactly as they appear in the packet
+    pub fn comment_bytes(&self) -> impl Iterator<Item = &[u8]> {
+        self.comments.iter().map(|comment| comment.bytes.as_slice())
+    }
+
+    /// Gets all well-formed comments as key/value pairs
+    pub fn tags(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.comments()
+            .filter_map(|comment| comment.split_once('='))
+    }
+
+    /// Gets the value of the first tag with the given key
+    pub fn get<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        self.get_all(key).next()
+    }
+
+    /// Gets the values of every tag with the given key
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.tags()
+            .filter(move |(tag, _)| tag.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value)
+    }
+
+    /// Adds a tag, keeping any existing tags with the same key.
+    ///
+    /// Keys have to be printable ASCII (0x20 to 0x7D) other than `=`, so the
+    /// tag reads back with the same key.
+    pub fn add_tag(&mut self, key: &str, value: &str) -> Result<(), CommentsError> {
+        let valid = |byte: &u8| (0x20..=0x7D).contains(byte) && *byte != b'=';
+        if key.is_empty() || !key.bytes().all(|byte| valid(&byte)) {
+            return Err(CommentsError::InvalidKey);
+        }
+        self.comments.push(Comment::new(format!("{key}={value}")));
+        Ok(())
+    }
+
+    /// Removes every tag with the given key
+    pub fn remove(&mut self, key: &str) {
+        self.comments.retain(|comment| {
+            match comment.text.split_once('=') {
+                Some((tag, _)) => !tag.eq_ignore_ascii_case(key),
+                None => true,
+            }
+        });
+    }
+
+    /// Parses a comment header out of a packet
+    pub fn parse(packet: &[u8]) -> Result<Self, CommentsError> {
+        let mut reader = Reader { packet };
+        let vendor = Comment::from_bytes(reader.string()?);
+        let count = reader.u32()?;
+        // Every comment takes at least its length, so don't trust a count that
+        // can't possibly fit when reserving space
+        let mut comments = Vec::with_capacity((count as usize).min(packet.len() / 4));
+        for _ in 0..count {
+            comments.push(Comment::from_bytes(reader.string()?));
+        }
+        Ok(Self { vendor, comments })
+    }
+
+    /// Serializes the comment header into a packet
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, &self.vendor.bytes);
+        bytes.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            write_string(&mut bytes, &comment.bytes);
+        }
+        bytes
+    }
+}
+
+impl Default for SpeexComments {
+    /// Creates an empty comment header naming the linked speex version as the
+    /// vendor.
+    fn default() -> Self {
+        Self::new(format!("Encoded with {}", get_version_string()))
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &[u8]) {
+    bytes.extend_from_slice(&(string.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(string);
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CommentsError> {
+        if self.packet.len() < len {
+            return Err(CommentsError::Truncated);
+        }
+        let (taken, rest) = self.packet.split_at(len);
+        self.packet = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, CommentsError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], CommentsError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_tags() {
+        let mut comments = SpeexComments::new("speex-rs test");
+        comments.add_tag("CALLER_ID", "+15551234567").unwrap();
+        comments.add_tag("DATE", "2023-06-01T12:00:00Z").unwrap();
+        comments.add_tag("caller_id", "+15557654321").unwrap();
+
+        let parsed = SpeexComments::parse(&comments.to_bytes()).unwrap();
+
+        assert_eq!(parsed, comments);
+        assert_eq!(parsed.vendor(), "speex-rs test");
+        assert_eq!(parsed.get("date"), Some("2023-06-01T12:00:00Z"));
+        assert_eq!(
+            parsed.get_all("Caller_ID").collect::<Vec<_>>(),
+            ["+15551234567", "+15557654321"]
+        );
+    }
+
+    #[test]
+    fn reads_speexenc_layout() {
+        // What speexenc writes: vendor, then the comment count and comments
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&19u32.to_le_bytes());
+        packet.extend_from_slice(b"Encoded with Speex ");
+        packet.extend_from_slice(&2u32.to_le_bytes());
+        packet.extend_from_slice(&12u32.to_le_bytes());
+        packet.extend_from_slice(b"TITLE=a call");
+        packet.extend_from_slice(&9u32.to_le_bytes());
+        packet.extend_from_slice(b"no equals");
+
+        let comments = SpeexComments::parse(&packet).unwrap();
+
+        assert_eq!(comments.vendor(), "Encoded with Speex ");
+        assert_eq!(comments.tags().collect::<Vec<_>>(), [("TITLE", "a call")]);
+        assert_eq!(
+            comments.comments().collect::<Vec<_>>(),
+            ["TITLE=a call", "no equals"]
+        );
+        assert_eq!(comments.to_bytes(), packet);
+    }
+
+    #[test]
+    fn removes_tags_by_key() {
+        let mut comments = SpeexComments::default();
+        comments.add_tag("A", "1").unwrap();
+        comments.add_tag("B", "2").unwrap();
+        comments.add_tag("a", "3").unwrap();
+
+        comments.remove("A");
+
+        assert_eq!(comments.tags().collect::<Vec<_>>(), [("B", "2")]);
+        assert!(comments.vendor().contains("speex-1.2.1"));
+    }
+
+    #[test]
+    fn rejects_bad_packets() {
+        let mut comments = SpeexComments::new("vendor");
+        comments.add_tag("KEY", "value").unwrap();
+        let packet = comments.to_bytes();
+
+        for len in 0..packet.len() {
+            assert_eq!(
+                SpeexComments::parse(&packet[..len]),
+                Err(CommentsError::Truncated)
+            );
+        }
+
+        for key in ["", "A=B", "TAB\t", "CAF\u{e9}", "~"] {
+            assert_eq!(
+                comments.add_tag(key, "value"),
+                Err(CommentsError::InvalidKey)
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_tags_that_arent_utf8() {
+        // A Latin-1 tag, as some older taggers write
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&6u32.to_le_bytes());
+        packet.extend_from_slice(b"vendor");
+        packet.extend_from_slice(&1u32.to_le_bytes());
+        packet.extend_from_slice(&10u32.to_le_bytes());
+        packet.extend_from_slice(b"TITLE=Caf\xe9");
+
+        let comments = SpeexComments::parse(&packet).unwrap();
+
+        assert_eq!(comments.get("title"), Some("Caf\u{fffd}"));
+        assert_eq!(
+            comments.comment_bytes().collect::<Vec<_>>(),
+            [b"TITLE=Caf\xe9"]
+        );
+        assert_eq!(comments.to_bytes(), packet);
+    }
+}
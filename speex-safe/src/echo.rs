@@ -0,0 +1,495 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fmt::{Display, Formatter};
+
+use crate::fft::Fft;
+use crate::resampler::Sample;
+use crate::ModeId;
+
+/// Fixed step size used until the filter has seen enough far end audio
+const STARTUP_STEP: f32 = 0.25;
+
+/// Largest step size once adapted
+const MAX_STEP: f32 = 0.5;
+
+/// Far end power per sample below which the filter doesn't adapt
+const MIN_FAR_POWER: f32 = 1.0;
+
+/// Frames in a row the output can be much louder than the input before the
+/// filter is considered to have diverged and is reset
+const MAX_DIVERGED_FRAMES: u32 = 50;
+
+/// Error type for cancelling echo from a frame.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EchoError {
+    /// One of the frames did not contain exactly one frame of samples
+    WrongFrameSize { expected: usize, actual: usize },
+}
+
+impl Display for EchoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EchoError::WrongFrameSize { expected, actual } => {
+                write!(
+                    f,
+                    "Frame has {actual} samples but the frame size is {expected}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for EchoError {}
+
+/// Acoustic echo canceller, removing the far end audio that the microphone
+/// picks up back from the speaker.
+///
+/// This is a multidelay block frequency domain adaptive filter, the same
+/// approach as speexdsp's `speex_echo_*`, implemented in Rust. For each frame
+/// it is given what was played back (the far end) and what was captured (the
+/// near end), and outputs the capture with the estimated echo subtracted. The
+/// step size follows the estimated share of residual echo in the output, so
+/// the filter stops adapting while both ends talk.
+///
+/// Some echo is always left over. Passing the canceller to
+/// `Preprocessor::run_with_echo` on each output frame suppresses that too.
+pub struct EchoCanceller {
+    mode: ModeId,
+    frame_size: usize,
+    tail_length: usize,
+    fft: Fft,
+    window: Vec<f32>,
+    /// The most recent far end samples, one FFT long
+    far: Vec<f32>,
+    /// Spectra of the far end, newest first, one per filter partition
+    far_spectra: VecDeque<Vec<(f32, f32)>>,
+    /// Filter weights, one spectrum per partition of the tail
+    weights: Vec<Vec<(f32, f32)>>,
+    /// The previous echo estimate frame, followed by the current one
+    echo: Vec<f32>,
+    residual_echo: Vec<f32>,
+
+    adapted_frames: usize,
+    diverged_frames: u32,
+    /// Smoothed frame powers and their covariance, for the leak estimate
+    error_power: f32,
+    echo_power: f32,
+    error_echo: f32,
+    echo_echo: f32,
+    leak: f32,
+}
+
+impl EchoCanceller {
+    /// Creates an echo canceller for frames of the given mode, covering an
+    /// echo tail of `tail_length` samples. A tail of 100 to 500 ms is usual.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tail_length` is zero.
+    pub fn new(mode: ModeId, tail_length: usize) -> Self {
+        assert!(tail_length > 0, "tail length has to be at least one sample");
+        let frame_size = mode.get_frame_size() as usize;
+        let partitions = tail_length.div_ceil(frame_size);
+        let fft = Fft::new((frame_size * 2).next_power_of_two());
+        let bins = fft.len() / 2 + 1;
+        // The same analysis window as the preprocessor, so the residual echo
+        // lines up with its bins
+        let window = (0..frame_size * 2)
+            .map(|i| (PI * (i as f32 + 0.5) / (frame_size * 2) as f32).sin())
+            .collect();
+        Self {
+            mode,
+            frame_size,
+            tail_length,
+            far: vec![0.0; fft.len()],
+            far_spectra: (0..partitions)
+                .map(|_| vec![(0.0, 0.0); fft.len()])
+                .collect(),
+            weights: vec![vec![(0.0, 0.0); fft.len()]; partitions],
+            fft,
+            window,
+            echo: vec![0.0; frame_size * 2],
+            residual_echo: vec![0.0; bins],
+            adapted_frames: 0,
+            diverged_frames: 0,
+            error_power: 0.0,
+            echo_power: 0.0,
+            error_echo: 1.0,
+            echo_echo: 1.0,
+            leak: 1.0,
+        }
+    }
+
+    /// Gets the number of samples in a frame
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Gets the length of echo tail that is cancelled, in samples
+    pub fn tail_length(&self) -> usize {
+        self.tail_length
+    }
+
+    /// Forgets the learned echo path, as if newly created
+    pub fn reset(&mut self) {
+        *self = Self::new(self.mode, self.tail_length);
+    }
+
+    /// Cancels the echo of `far`, the frame just played back, from `near`,
+    /// the frame just captured, writing the result to `output`.
+    ///
+    /// The far end frame has to be the one played back as the near end frame
+    /// was captured. Any fixed delay between the two counts against the tail
+    /// length. All three frames have to be exactly `frame_size` samples long.
+    pub fn cancel(
+        &mut self,
+        near: &[f32],
+        far: &[f32],
+        output: &mut [f32],
+    ) -> Result<(), EchoError> {
+        for actual in [near.len(), far.len(), output.len()] {
+            if actual != self.frame_size {
+                return Err(EchoError::WrongFrameSize {
+                    expected: self.frame_size,
+                    actual,
+                });
+            }
+        }
+        let len = self.fft.len();
+        let frame_size = self.frame_size;
+
+        self.far.copy_within(frame_size.., 0);
+        self.far[len - frame_size..].copy_from_slice(far);
+        let mut spectrum = self.far_spectra.pop_back().expect("at least one partition");
+        for (value, &sample) in spectrum.iter_mut().zip(&self.far) {
+            *value = (sample, 0.0);
+        }
+        self.fft.forward(&mut spectrum);
+        self.far_spectra.push_front(spectrum);
+
+        // Echo estimate, the far end history filtered by every partition
+        let mut echo = vec![(0.0, 0.0); len];
+        for (weights, spectrum) in self.weights.iter().zip(&self.far_spectra) {
+            for ((echo, &(wr, wi)), &(xr, xi)) in echo.iter_mut().zip(weights).zip(spectrum) {
+                echo.0 += wr * xr - wi * xi;
+                echo.1 += wr * xi + wi * xr;
+            }
+        }
+        self.fft.inverse(&mut echo);
+        let echo = &echo[len - frame_size..];
+        for ((output, &near), &(echo, _)) in output.iter_mut().zip(near).zip(echo) {
+            *output = near - echo;
+        }
+        self.echo.copy_within(frame_size.., 0);
+        for (sample, &(echo, _)) in self.echo[frame_size..].iter_mut().zip(echo) {
+            *sample = echo;
+        }
+
+        let near_power = power(near);
+        let far_power = power(far);
+        let error_power = power(output);
+        let echo_power = power(&self.echo[frame_size..]);
+        self.update_leak(error_power, echo_power);
+
+        if error_power > 4.0 * near_power + 1.0 {
+            self.diverged_frames += 1;
+            if self.diverged_frames > MAX_DIVERGED_FRAMES {
+                self.reset();
+                output.copy_from_slice(near);
+                return Ok(());
+            }
+        } else {
+            self.diverged_frames = 0;
+        }
+
+        if far_power > MIN_FAR_POWER * frame_size as f32 {
+            let step = if self.adapted_frames < 2 * self.weights.len() {
+                self.adapted_frames += 1;
+                STARTUP_STEP
+            } else {
+                // Residual echo to error ratio: large when the output is
+                // mostly echo, small while the near end talks
+                let ratio =
+                    (0.0001 * far_power + 3.0 * self.leak * echo_power) / (error_power + 1.0);
+                ratio.min(MAX_STEP)
+            };
+            self.adapt(output, step);
+        }
+        self.update_residual_echo();
+        Ok(())
+    }
+
+    /// Cancels echo from frames of i16 samples.
+    ///
+    /// See `cancel` for details.
+    pub fn cancel_int(
+        &mut self,
+        near: &[i16],
+        far: &[i16],
+        output: &mut [i16],
+    ) -> Result<(), EchoError> {
+        let near: Vec<f32> = near.iter().map(|&sample| sample.to_f32()).collect();
+        let far: Vec<f32> = far.iter().map(|&sample| sample.to_f32()).collect();
+        let mut samples = vec![0.0; output.len()];
+        self.cancel(&near, &far, &mut samples)?;
+        for (sample, cancelled) in output.iter_mut().zip(samples) {
+            *sample = i16::from_f32(cancelled);
+        }
+        Ok(())
+    }
+
+    /// Gets the estimated power of the echo left in the last output frame,
+    /// per bin of the preprocessor's spectrum
+    pub(crate) fn residual_echo(&self) -> &[f32] {
+        &self.residual_echo
+    }
+
+    /// Normalised LMS update of every partition from the error frame, keeping
+    /// each partition's impulse response to a frame long
+    fn adapt(&mut self, error: &[f32], step: f32) {
+        let len = self.fft.len();
+        let mut error_spectrum = vec![(0.0, 0.0); len];
+        for (value, &sample) in error_spectrum[len - self.frame_size..]
+            .iter_mut()
+            .zip(error)
+        {
+            value.0 = sample;
+        }
+        self.fft.forward(&mut error_spectrum);
+
+        let mut far_power = vec![0.0; len];
+        for spectrum in &self.far_spectra {
+            for (power, (re, im)) in far_power.iter_mut().zip(spectrum) {
+                *power += re * re + im * im;
+            }
+        }
+        let regularisation = far_power.iter().sum::<f32>() / len as f32 * 0.01 + 1.0;
+
+        let mut gradient = vec![(0.0, 0.0); len];
+        for (weights, spectrum) in self.weights.iter_mut().zip(&self.far_spectra) {
+            for (bin, value) in gradient.iter_mut().enumerate() {
+                let (xr, xi) = spectrum[bin];
+                let (er, ei) = error_spectrum[bin];
+                let scale = step / (far_power[bin] + regularisation);
+                *value = ((xr * er + xi * ei) * scale, (xr * ei - xi * er) * scale);
+            }
+            self.fft.inverse(&mut gradient);
+            for value in &mut gradient[self.frame_size..] {
+                *value = (0.0, 0.0);
+            }
+            self.fft.forward(&mut gradient);
+            for (weight, change) in weights.iter_mut().zip(&gradient) {
+                weight.0 += change.0;
+                weight.1 += change.1;
+            }
+        }
+    }
+
+    /// Estimates the share of the echo that's left in the output, from how
+    /// changes in output power follow changes in echo power. Near end speech
+    /// doesn't follow the echo, so it doesn't affect the estimate.
+    fn update_leak(&mut self, error_power: f32, echo_power: f32) {
+        self.error_power = 0.9 * self.error_power + 0.1 * error_power;
+        self.echo_power = 0.9 * self.echo_power + 0.1 * echo_power;
+        let error_change = error_power - self.error_power;
+        let echo_change = echo_power - self.echo_power;
+        let rate = (0.35 / self.weights.len() as f32).max(0.05);
+        self.error_echo = (1.0 - rate) * self.error_echo + rate * error_change * echo_change;
+        self.echo_echo = (1.0 - rate) * self.echo_echo + rate * echo_change * echo_change;
+        self.leak = (self.error_echo.max(0.0) / (self.echo_echo + 1.0)).clamp(0.005, 1.0);
+    }
+
+    fn update_residual_echo(&mut self) {
+        let len = self.fft.len();
+        let mut spectrum = vec![(0.0, 0.0); len];
+        for ((value, &sample), &window) in spectrum.iter_mut().zip(&self.echo).zip(&self.window) {
+            value.0 = sample * window;
+        }
+        self.fft.forward(&mut spectrum);
+        for (residual, (re, im)) in self.residual_echo.iter_mut().zip(&spectrum) {
+            *residual = self.leak * (re * re + im * im);
+        }
+    }
+}
+
+fn power(samples: &[f32]) -> f32 {
+    samples.iter().map(|sample| sample * sample).sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{energy, noise};
+    use crate::{PreprocessError, Preprocessor};
+
+    /// Noise with a speech-like downward tilt, from a fixed seed
+    fn signal(len: usize, level: f32, seed: &mut u32) -> Vec<f32> {
+        let mut previous = 0.0;
+        noise(len, 1.0, seed)
+            .into_iter()
+            .map(|white| {
+                previous = 0.8 * previous + white;
+                previous * level
+            })
+            .collect()
+    }
+
+    /// A room: a short delay, then exponentially decaying reflections
+    fn echo_path(seed: &mut u32) -> Vec<f32> {
+        let reflections = signal(600, 1.0, seed);
+        let mut path = vec![0.0; 40];
+        path.extend(
+            reflections
+                .iter()
+                .enumerate()
+                .map(|(i, r)| r * 0.1 * (-(i as f32) / 120.0).exp()),
+        );
+        path[40] = 0.5;
+        path
+    }
+
+    fn convolve(signal: &[f32], path: &[f32]) -> Vec<f32> {
+        (0..signal.len())
+            .map(|n| {
+                path.iter()
+                    .enumerate()
+                    .take(n + 1)
+                    .map(|(k, tap)| tap * signal[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn cancel(canceller: &mut EchoCanceller, near: &[f32], far: &[f32]) -> Vec<f32> {
+        let mut output = vec![0.0; near.len()];
+        for ((near, far), output) in near
+            .chunks_exact(160)
+            .zip(far.chunks_exact(160))
+            .zip(output.chunks_exact_mut(160))
+        {
+            canceller.cancel(near, far, output).unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn cancels_echo() {
+        let mut seed = 1;
+        let far = signal(8000 * 6, 3000.0, &mut seed);
+        let near = convolve(&far, &echo_path(&mut seed));
+        let mut canceller = EchoCanceller::new(ModeId::NarrowBand, 1024);
+        let output = cancel(&mut canceller, &near, &far);
+
+        let erle = 10.0 * (energy(&near[8000 * 4..]) / energy(&output[8000 * 4..])).log10();
+        assert!(erle > 25.0, "echo only down {erle} dB");
+    }
+
+    #[test]
+    fn keeps_near_end_during_double_talk() {
+        let mut seed = 2;
+        let far = signal(8000 * 8, 3000.0, &mut seed);
+        let echo = convolve(&far, &echo_path(&mut seed));
+        let talker = signal(8000 * 8, 2000.0, &mut seed);
+        let mut near = echo.clone();
+        for (near, talker) in near[8000 * 4..].iter_mut().zip(&talker[8000 * 4..]) {
+            *near += talker;
+        }
+        let mut canceller = EchoCanceller::new(ModeId::NarrowBand, 1024);
+        let output = cancel(&mut canceller, &near, &far);
+
+        // What's left over, besides the near end talker
+        let leftover: Vec<f32> = output[8000 * 6..]
+            .iter()
+            .zip(&talker[8000 * 6..])
+            .map(|(output, talker)| output - talker)
+            .collect();
+        let erle = 10.0 * (energy(&echo[8000 * 6..]) / energy(&leftover)).log10();
+        assert!(erle > 15.0, "echo only down {erle} dB while both talk");
+    }
+
+    #[test]
+    fn preprocessor_suppresses_residual_echo() {
+        let mut seed = 3;
+        let far = signal(8000 * 4, 3000.0, &mut seed);
+        let mut near = convolve(&far, &echo_path(&mut seed));
+        // A little background noise, as any capture would have
+        for (near, noise) in near.iter_mut().zip(signal(8000 * 4, 5.0, &mut seed)) {
+            *near += noise;
+        }
+        let mut canceller = EchoCanceller::new(ModeId::NarrowBand, 1024);
+        let mut preprocessor = Preprocessor::new(ModeId::NarrowBand);
+        let mut cancelled = vec![0.0; near.len()];
+        let mut suppressed = vec![0.0; near.len()];
+        for (i, (near, far)) in near
+            .chunks_exact(160)
+            .zip(far.chunks_exact(160))
+            .enumerate()
+        {
+            let output = &mut cancelled[i * 160..(i + 1) * 160];
+            canceller.cancel(near, far, output).unwrap();
+            let frame = &mut suppressed[i * 160..(i + 1) * 160];
+            frame.copy_from_slice(output);
+            preprocessor.run_with_echo(frame, &canceller).unwrap();
+        }
+
+        let before = energy(&cancelled[8000 * 2..8000 * 4 - 160]);
+        let after = energy(&suppressed[8000 * 2 + 160..]);
+        assert!(after < before * 0.5);
+    }
+
+    #[test]
+    fn resets_path() {
+        let mut seed = 4;
+        let far = signal(8000 * 2, 3000.0, &mut seed);
+        let near = convolve(&far, &echo_path(&mut seed));
+        let mut canceller = EchoCanceller::new(ModeId::NarrowBand, 1024);
+        cancel(&mut canceller, &near, &far);
+        canceller.reset();
+
+        // Nothing learned, so the first frame passes straight through
+        let mut output = vec![0.0; 160];
+        canceller
+            .cancel(&near[..160], &far[..160], &mut output)
+            .unwrap();
+        assert_eq!(output, &near[..160]);
+        assert_eq!(canceller.tail_length(), 1024);
+    }
+
+    #[test]
+    fn rejects_wrong_frame_sizes() {
+        let mut canceller = EchoCanceller::new(ModeId::NarrowBand, 1024);
+        let mut output = [0; 160];
+
+        assert_eq!(
+            canceller.cancel_int(&[0; 160], &[0; 80], &mut output),
+            Err(EchoError::WrongFrameSize {
+                expected: 160,
+                actual: 80
+            })
+        );
+        assert_eq!(
+            canceller.cancel(&[0.0; 160], &[0.0; 160], &mut [0.0; 320]),
+            Err(EchoError::WrongFrameSize {
+                expected: 160,
+                actual: 320
+            })
+        );
+
+        // Frames for another mode are rejected by the preprocessor
+        let mut preprocessor = Preprocessor::new(ModeId::WideBand);
+        assert_eq!(
+            preprocessor.run_int_with_echo(&mut [0; 320], &canceller),
+            Err(PreprocessError::EchoMismatch {
+                expected: 320,
+                actual: 160
+            })
+        );
+    }
+}
@@ -0,0 +1,178 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::fmt::{Display, Formatter};
+
+use crate::{
+    CommentsError,
+    ControlError,
+    DecoderError,
+    EchoError,
+    EncoderError,
+    HeaderError,
+    InbandError,
+    PreprocessError,
+    RtpError,
+    SdpError,
+};
+
+/// Error type for converting raw integers from speex into typed values.
+///
+/// These values usually come from untrusted headers or bitstreams, so an
+/// unknown value is reported rather than treated as a bug.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConversionError {
+    /// The value is not a known mode id
+    /// The parameter is the value that was passed
+    InvalidMode(i32),
+    /// The value is not a known submode id for the mode
+    /// The parameter is the value that was passed
+    InvalidSubmode(i32),
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversionError::InvalidMode(value) => write!(f, "Invalid mode id ({value})"),
+            ConversionError::InvalidSubmode(value) => write!(f, "Invalid submode id ({value})"),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Unified error type for everything in this crate that can fail.
+///
+/// Each of the more specific error types converts into this one, so `?` can
+/// be used freely when mixing control, decoding and header operations.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// A control request was rejected by the encoder/decoder
+    Control(ControlError),
+    /// A frame failed to encode
+    Encoder(EncoderError),
+    /// A frame failed to decode
+    Decoder(DecoderError),
+    /// Echo failed to cancel from a frame
+    Echo(EchoError),
+    /// A stream header was invalid
+    Header(HeaderError),
+    /// A stream comment header was invalid
+    Comments(CommentsError),
+    /// A raw value could not be converted into a typed one
+    Conversion(ConversionError),
+    /// An in-band message could not be written
+    Inband(InbandError),
+    /// A frame failed to preprocess
+    Preprocess(PreprocessError),
+    /// An RTP packet was invalid
+    Rtp(RtpError),
+    /// An SDP description of a speex stream was invalid
+    Sdp(SdpError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Control(err) => write!(f, "{err}"),
+            Error::Encoder(err) => write!(f, "{err}"),
+            Error::Decoder(err) => write!(f, "{err}"),
+            Error::Echo(err) => write!(f, "{err}"),
+            Error::Header(err) => write!(f, "{err}"),
+            Error::Comments(err) => write!(f, "{err}"),
+            Error::Conversion(err) => write!(f, "{err}"),
+            Error::Inband(err) => write!(f, "{err}"),
+            Error::Preprocess(err) => write!(f, "{err}"),
+            Error::Rtp(err) => write!(f, "{err}"),
+            Error::Sdp(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Control(err) => Some(err),
+            Error::Encoder(err) => Some(err),
+            Error::Decoder(err) => Some(err),
+            Error::Echo(err) => Some(err),
+            Error::Header(err) => Some(err),
+            Error::Comments(err) => Some(err),
+            Error::Conversion(err) => Some(err),
+            Error::Inband(err) => Some(err),
+            Error::Preprocess(err) => Some(err),
+            Error::Rtp(err) => Some(err),
+            Error::Sdp(err) => Some(err),
+        }
+    }
+}
+
+impl From<ControlError> for Error {
+    fn from(value: ControlError) -> Self {
+        Error::Control(value)
+    }
+}
+
+impl From<EncoderError> for Error {
+    fn from(value: EncoderError) -> Self {
+        Error::Encoder(value)
+    }
+}
+
+impl From<DecoderError> for Error {
+    fn from(value: DecoderError) -> Self {
+        Error::Decoder(value)
+    }
+}
+
+impl From<EchoError> for Error {
+    fn from(value: EchoError) -> Self {
+        Error::Echo(value)
+    }
+}
+
+impl From<HeaderError> for Error {
+    fn from(value: HeaderError) -> Self {
+        Error::Header(value)
+    }
+}
+
+impl From<CommentsError> for Error {
+    fn from(value: CommentsError) -> Self {
+        Error::Comments(value)
+    }
+}
+
+impl From<ConversionError> for Error {
+    fn from(value: ConversionError) -> Self {
+        Error::Conversion(value)
+    }
+}
+
+impl From<InbandError> for Error {
+    fn from(value: InbandError) -> Self {
+        Error::Inband(value)
+    }
+}
+
+impl From<PreprocessError> for Error {
+    fn from(value: PreprocessError) -> Self {
+        Error::Preprocess(value)
+    }
+}
+
+impl From<RtpError> for Error {
+    fn from(value: RtpError) -> Self {
+        Error::Rtp(value)
+    }
+}
+
+impl From<SdpError> for Error {
+    fn from(value: SdpError) -> Self {
+        Error::Sdp(value)
+    }
+}
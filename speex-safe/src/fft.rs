@@ -0,0 +1,117 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::f64::consts::PI;
+
+/// Radix-2 complex FFT over a fixed power of two length, with its twiddle
+/// factors computed up front. Values are `(re, im)` pairs.
+pub(crate) struct Fft {
+    len: usize,
+    twiddles: Vec<(f32, f32)>,
+}
+
+impl Fft {
+    pub(crate) fn new(len: usize) -> Self {
+        assert!(len.is_power_of_two(), "FFT length has to be a power of two");
+        let twiddles = (0..len / 2)
+            .map(|i| {
+                let angle = -2.0 * PI * i as f64 / len as f64;
+                (angle.cos() as f32, angle.sin() as f32)
+            })
+            .collect();
+        Self { len, twiddles }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Transforms to the frequency domain, in place
+    pub(crate) fn forward(&self, data: &mut [(f32, f32)]) {
+        self.transform(data, false);
+    }
+
+    /// Transforms back to the time domain, in place, scaled so it undoes
+    /// `forward`
+    pub(crate) fn inverse(&self, data: &mut [(f32, f32)]) {
+        self.transform(data, true);
+        let scale = 1.0 / self.len as f32;
+        for (re, im) in data.iter_mut() {
+            *re *= scale;
+            *im *= scale;
+        }
+    }
+
+    fn transform(&self, data: &mut [(f32, f32)], inverse: bool) {
+        assert_eq!(data.len(), self.len);
+        let bits = self.len.trailing_zeros();
+        if bits == 0 {
+            return;
+        }
+        for i in 0..self.len {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+
+        let mut size = 2;
+        while size <= self.len {
+            let stride = self.len / size;
+            for start in (0..self.len).step_by(size) {
+                for k in 0..size / 2 {
+                    let (wr, wi) = self.twiddles[k * stride];
+                    let wi = if inverse { -wi } else { wi };
+                    let (ar, ai) = data[start + k];
+                    let (br, bi) = data[start + k + size / 2];
+                    let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+                    data[start + k] = (ar + tr, ai + ti);
+                    data[start + k + size / 2] = (ar - tr, ai - ti);
+                }
+            }
+            size *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_tone_bin() {
+        let fft = Fft::new(64);
+        let mut data: Vec<_> = (0..64)
+            .map(|i| ((2.0 * PI * 5.0 * i as f64 / 64.0).cos() as f32, 0.0))
+            .collect();
+        fft.forward(&mut data);
+
+        for (bin, (re, im)) in data.iter().enumerate() {
+            let magnitude = (re * re + im * im).sqrt();
+            if bin == 5 || bin == 59 {
+                assert!((magnitude - 32.0).abs() < 1e-3);
+            } else {
+                assert!(magnitude < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_forward() {
+        let fft = Fft::new(256);
+        let original: Vec<_> = (0..256)
+            .map(|i| ((i as f32 * 0.37).sin(), (i as f32 * 0.11).cos()))
+            .collect();
+        let mut data = original.clone();
+        fft.forward(&mut data);
+        fft.inverse(&mut data);
+
+        for (a, b) in original.iter().zip(&data) {
+            assert!((a.0 - b.0).abs() < 1e-5 && (a.1 - b.1).abs() < 1e-5);
+        }
+    }
+}
@@ -5,10 +5,74 @@
 // obtain one at http://mozilla.org/MPL/2.0/.                                  /
 ////////////////////////////////////////////////////////////////////////////////
 
+use std::ffi::{c_char, CStr};
+use std::fmt::{Display, Formatter};
 use std::mem::MaybeUninit;
 
 use speex_sys::{SpeexHeader as SysHeader, SpeexMode};
 
+use crate::{ConversionError, ModeId};
+
+/// Error type for reading a speex header out of a packet
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HeaderError {
+    /// The packet is shorter than a header
+    /// The parameter is the length of the packet
+    TooShort(usize),
+    /// The packet doesn't start with the speex magic
+    BadMagic,
+    /// The header is in a format version this crate doesn't understand
+    /// The parameter is the version that was found
+    UnsupportedVersion(i32),
+    /// The mode in the header doesn't exist
+    /// The parameter is the mode that was found
+    InvalidMode(i32),
+    /// The stream was encoded with a different version of the bitstream for
+    /// its mode
+    BitstreamMismatch { expected: i32, actual: i32 },
+    /// A field of the header is out of range, e.g. a negative sampling rate
+    InvalidField { name: &'static str, value: i64 },
+}
+
+impl Display for HeaderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::TooShort(len) => {
+                write!(f, "Packet is {len} bytes, too short to be a speex header")
+            }
+            HeaderError::BadMagic => write!(f, "Packet is not a speex header"),
+            HeaderError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported speex header version {version}")
+            }
+            HeaderError::InvalidMode(mode) => write!(f, "Invalid mode {mode} in speex header"),
+            HeaderError::BitstreamMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Stream uses bitstream version {actual} but the mode has version {expected}"
+                )
+            }
+            HeaderError::InvalidField { name, value } => {
+                write!(f, "Invalid {name} {value} in speex header")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// Offset of the first integer field, after the magic and version string
+const FIELDS_OFFSET: usize = 28;
+
+/// Converts a value to a header field, which is stored as an `i32`
+fn to_field(name: &'static str, value: u32) -> Result<i32, HeaderError> {
+    i32::try_from(value).map_err(|_| {
+        HeaderError::InvalidField {
+            name,
+            value: value.into(),
+        }
+    })
+}
+
 /// Standard speex stream header
 ///
 /// ## Why doesn't this implement `Drop`?
@@ -16,50 +80,546 @@ use speex_sys::{SpeexHeader as SysHeader, SpeexMode};
 /// You may notice in `speex_sys` there is a `free` function for headers.
 /// The data within `SpeexHeader` is actually entirely stack allocated. There is
 /// nothing to be freed. The `free` is for the arrays/pointers allocated by
-/// `packet_to_header` and `header_to_packet`. For `packet_to_header` instead of
-/// using a manual call to free, it is wrapped in a `Vec` which can manage the
-/// memory just fine.
+/// `packet_to_header` and `header_to_packet`, which aren't used here as the
+/// packet layout is simple enough to read and write directly.
 #[derive(Debug, Clone, Copy)]
 pub struct SpeexHeader {
     backing: SysHeader,
 }
 
 impl SpeexHeader {
-    pub fn new(rate: i32, num_channels: i32, mode: &SpeexMode) -> Self {
+    /// The magic bytes every speex header starts with
+    pub const MAGIC: &'static [u8; 8] = b"Speex   ";
+    /// The size of a serialized header in bytes
+    pub const SIZE: usize = 80;
+
+    /// Creates a new header for a stream with the given rate, channel count
+    /// and mode.
+    ///
+    /// Returns `HeaderError::InvalidField` if the rate is 0, or the rate or
+    /// channel count doesn't fit in the header.
+    pub fn new(rate: u32, num_channels: u32, mode: ModeId) -> Result<Self, HeaderError> {
+        if rate == 0 {
+            return Err(HeaderError::InvalidField {
+                name: "rate",
+                value: 0,
+            });
+        }
+        let rate = to_field("rate", rate)?;
+        let num_channels = to_field("channel count", num_channels)?;
         let backing = unsafe {
             let mut uninit: MaybeUninit<SysHeader> = MaybeUninit::uninit();
             let ptr = uninit.as_mut_ptr();
 
-            let mode_ptr = mode as *const SpeexMode;
+            let mode_ptr = mode.get_mode() as *const SpeexMode;
             speex_sys::speex_init_header(ptr, rate, num_channels, mode_ptr);
 
             let initialized: SysHeader = uninit.assume_init();
             initialized
         };
-        Self { backing }
+        Ok(Self { backing })
     }
 
-    // TODO: NONE of this is safe. It's all just a guess.
+    /// Gets the version of speex that wrote the header, e.g. "speex-1.2.1"
+    pub fn version(&self) -> String {
+        let bytes: Vec<u8> = self
+            .backing
+            .speex_version
+            .iter()
+            .map(|&c| c as u8)
+            .collect();
+        match CStr::from_bytes_until_nul(&bytes) {
+            Ok(version) => version.to_string_lossy().into_owned(),
+            Err(_) => String::from_utf8_lossy(&bytes).into_owned(),
+        }
+    }
 
-    pub unsafe fn from_packet(packet: &mut [u8]) -> Self {
-        let backing = unsafe {
-            let ptr = packet.as_mut_ptr() as *mut i8;
-            let length = packet.len() as i32;
-            let header_ptr = speex_sys::speex_packet_to_header(ptr, length);
-            let derefed = *header_ptr;
-            speex_sys::speex_header_free(header_ptr as *mut std::ffi::c_void);
-            derefed
+    /// Gets the version of the header format itself
+    pub fn version_id(&self) -> u32 {
+        self.backing.speex_version_id as u32
+    }
+
+    /// Gets the size of the header in bytes
+    pub fn header_size(&self) -> u32 {
+        self.backing.header_size as u32
+    }
+
+    /// Gets the sampling rate of the stream
+    pub fn rate(&self) -> u32 {
+        self.backing.rate as u32
+    }
+
+    /// Gets the mode the stream was encoded with
+    pub fn mode_id(&self) -> Result<ModeId, ConversionError> {
+        ModeId::try_from(self.backing.mode)
+    }
+
+    /// Gets the version of the bitstream for the mode
+    pub fn mode_bitstream_version(&self) -> u32 {
+        self.backing.mode_bitstream_version as u32
+    }
+
+    /// Gets the number of channels in the stream
+    pub fn num_channels(&self) -> u32 {
+        self.backing.nb_channels as u32
+    }
+
+    /// Gets the bitrate of the stream, `None` if it isn't known
+    pub fn bitrate(&self) -> Option<u32> {
+        u32::try_from(self.backing.bitrate).ok()
+    }
+
+    /// Gets the size (in samples) of a frame
+    pub fn frame_size(&self) -> u32 {
+        self.backing.frame_size as u32
+    }
+
+    /// Gets whether the stream uses Variable BitRate
+    pub fn vbr(&self) -> bool {
+        self.backing.vbr != 0
+    }
+
+    /// Gets the number of frames in each packet
+    pub fn frames_per_packet(&self) -> u32 {
+        self.backing.frames_per_packet as u32
+    }
+
+    /// Gets the number of extra headers following the comment header
+    pub fn extra_headers(&self) -> u32 {
+        self.backing.extra_headers as u32
+    }
+
+    /// Parses a header out of a packet
+    ///
+    /// Like speex itself, the channel count is clamped to 1 or 2 and any bytes
+    /// after the header are ignored.
+    pub fn parse(packet: &[u8]) -> Result<Self, HeaderError> {
+        if packet.len() < Self::SIZE {
+            return Err(HeaderError::TooShort(packet.len()));
+        }
+        if &packet[..8] != Self::MAGIC {
+            return Err(HeaderError::BadMagic);
+        }
+        let field = |index: usize| {
+            let start = FIELDS_OFFSET + index * 4;
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&packet[start..start + 4]);
+            i32::from_le_bytes(bytes)
+        };
+
+        let speex_version_id = field(0);
+        if speex_version_id != 1 {
+            return Err(HeaderError::UnsupportedVersion(speex_version_id));
+        }
+        let mode = field(3);
+        let mode_id = ModeId::try_from(mode).map_err(|_| HeaderError::InvalidMode(mode))?;
+        let expected = mode_id.get_mode().bitstream_version;
+        let mode_bitstream_version = field(4);
+        if mode_bitstream_version != expected {
+            return Err(HeaderError::BitstreamMismatch {
+                expected,
+                actual: mode_bitstream_version,
+            });
+        }
+
+        // Rate and frame size have to be positive, while zero frames per packet
+        // or extra headers just means there are none
+        let checked = |index: usize, name: &'static str, min: i32| {
+            let value = field(index);
+            if value < min {
+                return Err(HeaderError::InvalidField {
+                    name,
+                    value: value.into(),
+                });
+            }
+            Ok(value)
+        };
+        let rate = checked(2, "rate", 1)?;
+        let frame_size = checked(7, "frame size", 1)?;
+        let frames_per_packet = checked(9, "frames per packet", 0)?;
+        let extra_headers = checked(10, "extra header count", 0)?;
+
+        let mut speex_string = [0; 8];
+        for (dst, &src) in speex_string.iter_mut().zip(&packet[..8]) {
+            *dst = src as c_char;
+        }
+        let mut speex_version = [0; 20];
+        for (dst, &src) in speex_version.iter_mut().zip(&packet[8..FIELDS_OFFSET]) {
+            *dst = src as c_char;
+        }
+        let backing = SysHeader {
+            speex_string,
+            speex_version,
+            speex_version_id,
+            header_size: field(1),
+            rate,
+            mode,
+            mode_bitstream_version,
+            nb_channels: field(5).clamp(1, 2),
+            bitrate: field(6),
+            frame_size,
+            vbr: field(8),
+            frames_per_packet,
+            extra_headers,
+            reserved1: field(11),
+            reserved2: field(12),
         };
-        Self { backing }
+        Ok(Self { backing })
     }
 
-    pub unsafe fn make_packet(&mut self) -> Vec<u8> {
-        let ptr = &mut self.backing as *mut SysHeader;
-        let mut size: i32 = 0;
-        let size_ptr = &mut size as *mut i32;
+    /// Serializes the header into a packet
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = &self.backing;
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend(header.speex_string.iter().map(|&c| c as u8));
+        bytes.extend(header.speex_version.iter().map(|&c| c as u8));
+        for field in [
+            header.speex_version_id,
+            header.header_size,
+            header.rate,
+            header.mode,
+            header.mode_bitstream_version,
+            header.nb_channels,
+            header.bitrate,
+            header.frame_size,
+            header.vbr,
+            header.frames_per_packet,
+            header.extra_headers,
+            header.reserved1,
+            header.reserved2,
+        ] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Builder for a `SpeexHeader` with the stream details filled in.
+///
+/// Starts out with one frame per packet, no VBR, an unknown bitrate and no
+/// extra headers.
+#[derive(Debug, Clone, Copy)]
+pub struct SpeexHeaderBuilder {
+    rate: u32,
+    num_channels: u32,
+    mode: ModeId,
+    frames_per_packet: u32,
+    vbr: bool,
+    bitrate: Option<u32>,
+    extra_headers: u32,
+}
+
+impl SpeexHeaderBuilder {
+    /// Creates a new builder for a stream with the given rate, channel count
+    /// and mode.
+    pub fn new(rate: u32, num_channels: u32, mode: ModeId) -> Self {
+        Self {
+            rate,
+            num_channels,
+            mode,
+            frames_per_packet: 1,
+            vbr: false,
+            bitrate: None,
+            extra_headers: 0,
+        }
+    }
+
+    /// Sets the number of frames in each packet
+    pub fn frames_per_packet(mut self, frames_per_packet: u32) -> Self {
+        self.frames_per_packet = frames_per_packet;
+        self
+    }
+
+    /// Sets whether the stream uses Variable BitRate
+    pub fn vbr(mut self, vbr: bool) -> Self {
+        self.vbr = vbr;
+        self
+    }
+
+    /// Sets the bitrate of the stream
+    pub fn bitrate(mut self, bitrate: u32) -> Self {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    /// Sets the number of extra headers following the comment header
+    pub fn extra_headers(mut self, extra_headers: u32) -> Self {
+        self.extra_headers = extra_headers;
+        self
+    }
+
+    /// Finishes building the header
+    ///
+    /// Returns `HeaderError::InvalidField` if the rate is 0, or any of the
+    /// values doesn't fit in the header.
+    pub fn build(self) -> Result<SpeexHeader, HeaderError> {
+        let mut header = SpeexHeader::new(self.rate, self.num_channels, self.mode)?;
+        let backing = &mut header.backing;
+        backing.frames_per_packet = to_field("frames per packet", self.frames_per_packet)?;
+        backing.vbr = self.vbr as i32;
+        if let Some(bitrate) = self.bitrate {
+            backing.bitrate = to_field("bitrate", bitrate)?;
+        }
+        backing.extra_headers = to_field("extra header count", self.extra_headers)?;
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Serializes through speex itself, to check against
+    fn speex_packet(header: &SpeexHeader) -> Vec<u8> {
+        let mut backing = header.backing;
+        let mut size = 0;
         unsafe {
-            let buff_ptr = speex_sys::speex_header_to_packet(ptr, size_ptr) as *mut u8;
-            Vec::from_raw_parts(buff_ptr, size as usize, size as usize)
+            let ptr = speex_sys::speex_header_to_packet(&mut backing, &mut size);
+            let bytes = std::slice::from_raw_parts(ptr as *const u8, size as usize).to_vec();
+            speex_sys::speex_header_free(ptr as *mut std::ffi::c_void);
+            bytes
+        }
+    }
+
+    #[test]
+    fn reads_fields_of_new_header() {
+        let header = SpeexHeader::new(16000, 1, ModeId::WideBand).unwrap();
+
+        assert_eq!(header.version(), "speex-1.2.1");
+        assert_eq!(header.version_id(), 1);
+        assert_eq!(header.header_size(), 80);
+        assert_eq!(header.rate(), 16000);
+        assert_eq!(header.mode_id(), Ok(ModeId::WideBand));
+        assert_eq!(header.num_channels(), 1);
+        assert_eq!(header.bitrate(), None);
+        assert_eq!(header.frame_size(), 320);
+        assert!(!header.vbr());
+        assert_eq!(header.frames_per_packet(), 0);
+        assert_eq!(header.extra_headers(), 0);
+    }
+
+    #[test]
+    fn builds_header() {
+        let header = SpeexHeaderBuilder::new(32000, 2, ModeId::UltraWideBand)
+            .frames_per_packet(3)
+            .vbr(true)
+            .bitrate(28000)
+            .extra_headers(1)
+            .build()
+            .unwrap();
+
+        assert_eq!(header.rate(), 32000);
+        assert_eq!(header.num_channels(), 2);
+        assert_eq!(header.mode_id(), Ok(ModeId::UltraWideBand));
+        assert_eq!(header.frame_size(), 640);
+        assert_eq!(header.frames_per_packet(), 3);
+        assert!(header.vbr());
+        assert_eq!(header.bitrate(), Some(28000));
+        assert_eq!(header.extra_headers(), 1);
+    }
+
+    #[test]
+    fn builder_defaults_to_one_frame_per_packet() {
+        let header = SpeexHeaderBuilder::new(8000, 1, ModeId::NarrowBand)
+            .build()
+            .unwrap();
+
+        assert_eq!(header.frames_per_packet(), 1);
+        assert_eq!(header.bitrate(), None);
+    }
+
+    #[test]
+    fn serializes_like_speex() {
+        let headers = [
+            SpeexHeader::new(8000, 1, ModeId::NarrowBand).unwrap(),
+            SpeexHeaderBuilder::new(16000, 2, ModeId::WideBand)
+                .vbr(true)
+                .bitrate(24600)
+                .build()
+                .unwrap(),
+            SpeexHeaderBuilder::new(32000, 1, ModeId::UltraWideBand)
+                .frames_per_packet(4)
+                .extra_headers(2)
+                .build()
+                .unwrap(),
+        ];
+
+        for header in headers {
+            let bytes = header.to_bytes();
+            assert_eq!(bytes.len(), SpeexHeader::SIZE);
+            assert_eq!(bytes, speex_packet(&header));
         }
     }
+
+    #[test]
+    fn parses_speex_packet() {
+        let header = SpeexHeaderBuilder::new(16000, 1, ModeId::WideBand)
+            .frames_per_packet(2)
+            .vbr(true)
+            .build()
+            .unwrap();
+        let mut packet = speex_packet(&header);
+        packet.extend_from_slice(&[0xFF; 4]);
+
+        let parsed = SpeexHeader::parse(&packet).unwrap();
+
+        assert_eq!(parsed.mode_id(), Ok(ModeId::WideBand));
+        assert_eq!(parsed.rate(), 16000);
+        assert_eq!(parsed.frames_per_packet(), 2);
+        assert!(parsed.vbr());
+        assert_eq!(parsed.version(), "speex-1.2.1");
+        assert_eq!(parsed.to_bytes(), packet[..SpeexHeader::SIZE]);
+    }
+
+    #[test]
+    fn agrees_with_speex_on_channels() {
+        let mut packet = SpeexHeader::new(8000, 1, ModeId::NarrowBand)
+            .unwrap()
+            .to_bytes();
+        packet[48..52].copy_from_slice(&7i32.to_le_bytes());
+
+        let parsed = SpeexHeader::parse(&packet).unwrap();
+        let from_speex = unsafe {
+            let ptr = speex_sys::speex_packet_to_header(
+                packet.as_mut_ptr() as *mut c_char,
+                packet.len() as i32,
+            );
+            let header = *ptr;
+            speex_sys::speex_header_free(ptr as *mut std::ffi::c_void);
+            header
+        };
+
+        assert_eq!(parsed.num_channels(), 2);
+        assert_eq!(parsed.num_channels(), from_speex.nb_channels as u32);
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        let valid = SpeexHeader::new(8000, 1, ModeId::NarrowBand)
+            .unwrap()
+            .to_bytes();
+
+        assert_eq!(
+            SpeexHeader::parse(&valid[..79]).unwrap_err(),
+            HeaderError::TooShort(79)
+        );
+
+        let mut packet = valid.clone();
+        packet[0] = b's';
+        assert_eq!(
+            SpeexHeader::parse(&packet).unwrap_err(),
+            HeaderError::BadMagic
+        );
+
+        let mut packet = valid.clone();
+        packet[28..32].copy_from_slice(&2i32.to_le_bytes());
+        assert_eq!(
+            SpeexHeader::parse(&packet).unwrap_err(),
+            HeaderError::UnsupportedVersion(2)
+        );
+
+        let mut packet = valid.clone();
+        packet[40..44].copy_from_slice(&3i32.to_le_bytes());
+        assert_eq!(
+            SpeexHeader::parse(&packet).unwrap_err(),
+            HeaderError::InvalidMode(3)
+        );
+
+        let mut packet = valid.clone();
+        packet[44..48].copy_from_slice(&99i32.to_le_bytes());
+        assert_eq!(
+            SpeexHeader::parse(&packet).unwrap_err(),
+            HeaderError::BitstreamMismatch {
+                expected: 4,
+                actual: 99
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_fields() {
+        let valid = SpeexHeader::new(8000, 1, ModeId::NarrowBand)
+            .unwrap()
+            .to_bytes();
+        let with_field = |offset: usize, value: i32| {
+            let mut packet = valid.clone();
+            packet[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            SpeexHeader::parse(&packet)
+        };
+
+        assert_eq!(
+            with_field(36, 0).unwrap_err(),
+            HeaderError::InvalidField {
+                name: "rate",
+                value: 0
+            }
+        );
+        assert_eq!(
+            with_field(56, -160).unwrap_err(),
+            HeaderError::InvalidField {
+                name: "frame size",
+                value: -160
+            }
+        );
+        assert_eq!(
+            with_field(64, -1).unwrap_err(),
+            HeaderError::InvalidField {
+                name: "frames per packet",
+                value: -1
+            }
+        );
+        assert_eq!(
+            with_field(68, i32::MIN).unwrap_err(),
+            HeaderError::InvalidField {
+                name: "extra header count",
+                value: i32::MIN.into()
+            }
+        );
+        assert_eq!(with_field(64, 0).unwrap().frames_per_packet(), 0);
+    }
+
+    #[test]
+    fn rejects_values_that_dont_fit() {
+        let too_big = i32::MAX as u32 + 1;
+        let invalid = |name| {
+            HeaderError::InvalidField {
+                name,
+                value: too_big.into(),
+            }
+        };
+
+        assert_eq!(
+            SpeexHeader::new(0, 1, ModeId::NarrowBand).unwrap_err(),
+            HeaderError::InvalidField {
+                name: "rate",
+                value: 0
+            }
+        );
+        assert_eq!(
+            SpeexHeader::new(too_big, 1, ModeId::NarrowBand).unwrap_err(),
+            invalid("rate")
+        );
+        assert_eq!(
+            SpeexHeader::new(8000, too_big, ModeId::NarrowBand).unwrap_err(),
+            invalid("channel count")
+        );
+
+        let builder = SpeexHeaderBuilder::new(8000, 1, ModeId::NarrowBand);
+        assert_eq!(
+            builder.frames_per_packet(too_big).build().unwrap_err(),
+            invalid("frames per packet")
+        );
+        assert_eq!(
+            builder.bitrate(too_big).build().unwrap_err(),
+            invalid("bitrate")
+        );
+        assert_eq!(
+            builder.extra_headers(too_big).build().unwrap_err(),
+            invalid("extra header count")
+        );
+
+        let header = builder.bitrate(i32::MAX as u32).build().unwrap();
+        let parsed = SpeexHeader::parse(&header.to_bytes()).unwrap();
+        assert_eq!(parsed.bitrate(), Some(i32::MAX as u32));
+    }
 }
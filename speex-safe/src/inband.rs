@@ -0,0 +1,547 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::any::Any;
+use std::ffi::{c_int, c_void};
+use std::fmt::{Display, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use speex_sys::SpeexBits as SysBits;
+
+use crate::SpeexBits;
+
+/// Number of in-band message ids speex reserves, ids are packed in 4 bits.
+pub(crate) const MAX_INBAND_ID: u8 = 15;
+
+/// Returned from a trampoline when the closure panicked. Speex hands any
+/// non-zero return straight back out of `speex_decode`, which is where the
+/// panic gets picked back up.
+pub(crate) const HANDLER_PANICKED: c_int = -100;
+
+/// Gets the size (in bits) of the payload following the given in-band id.
+///
+/// This matches how speex skips over messages it has no handler for.
+pub(crate) fn payload_bits(id: u8) -> u32 {
+    match id {
+        0..=1 => 1,
+        2..=7 => 4,
+        8..=9 => 8,
+        10..=11 => 16,
+        12..=13 => 32,
+        _ => 64,
+    }
+}
+
+/// Pseudo-mode marking a standard in-band message, packed where the mode of a
+/// frame would normally be.
+const INBAND_MODE: i32 = 14;
+
+/// Pseudo-mode marking a user-defined in-band message.
+const USER_INBAND_MODE: i32 = 13;
+
+/// Error type for writing in-band messages.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InbandError {
+    /// A value does not fit in the bits the message has for it
+    ValueOutOfRange,
+    /// The id has its own variant and can't be sent as `Custom`
+    /// The parameter is the id that was used
+    ReservedId(u8),
+}
+
+impl Display for InbandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InbandError::ValueOutOfRange => write!(f, "Value is too large for the in-band message"),
+            InbandError::ReservedId(id) => {
+                write!(f, "In-band id {id} can't be used for a custom message")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InbandError {}
+
+/// An in-band message, sent in the bitstream between frames.
+///
+/// Most of these are requests to the other end of a call, which is free to
+/// ignore them. See `SpeexDecoder::set_inband_handler` for receiving them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum InbandMessage {
+    /// Asks the decoder to turn perceptual enhancement off or on
+    EnhancementRequest(bool),
+    /// Asks the encoder to be less aggressive due to high packet loss
+    LossRequest(bool),
+    /// Asks the encoder to switch to the given mode (4 bits)
+    ModeRequest(u8),
+    /// Asks the encoder to switch to the given mode for the low band (4 bits)
+    LowModeRequest(u8),
+    /// Asks the encoder to switch to the given mode for the high band (4 bits)
+    HighModeRequest(u8),
+    /// Asks the encoder to switch to the given VBR quality (4 bits)
+    VbrQualityRequest(u8),
+    /// Asks for acknowledgement (0 = none, 1 = all, 2 = only in-band data)
+    AcknowledgeRequest(u8),
+    /// Asks the encoder to use CBR (0), VAD (1), DTX (3), VBR (5) or VBR and
+    /// DTX (7)
+    VbrRequest(u8),
+    /// A single character sent to the other end
+    Char(u8),
+    /// Intensity stereo information
+    Stereo(u8),
+    /// The maximum acceptable bitrate, in bytes per second
+    MaxBitrate(u16),
+    /// Acknowledges receiving the given packet
+    Acknowledge(u32),
+    /// A message with one of the ids speex leaves reserved (11, 13, 14 and 15)
+    Custom { id: u8, payload: u64 },
+    /// A user-defined message, with a 5 bit type and up to 15 bytes of data
+    User { kind: u8, payload: Vec<u8> },
+}
+
+impl InbandMessage {
+    /// Gets the in-band id of the message, `None` for user-defined messages.
+    pub fn id(&self) -> Option<u8> {
+        let id = match self {
+            InbandMessage::EnhancementRequest(_) => speex_sys::SPEEX_INBAND_ENH_REQUEST,
+            InbandMessage::LossRequest(_) => speex_sys::SPEEX_INBAND_RESERVED1,
+            InbandMessage::ModeRequest(_) => speex_sys::SPEEX_INBAND_MODE_REQUEST,
+            InbandMessage::LowModeRequest(_) => speex_sys::SPEEX_INBAND_LOW_MODE_REQUEST,
+            InbandMessage::HighModeRequest(_) => speex_sys::SPEEX_INBAND_HIGH_MODE_REQUEST,
+            InbandMessage::VbrQualityRequest(_) => speex_sys::SPEEX_INBAND_VBR_QUALITY_REQUEST,
+            InbandMessage::AcknowledgeRequest(_) => speex_sys::SPEEX_INBAND_ACKNOWLEDGE_REQUEST,
+            InbandMessage::VbrRequest(_) => speex_sys::SPEEX_INBAND_VBR_REQUEST,
+            InbandMessage::Char(_) => speex_sys::SPEEX_INBAND_CHAR,
+            InbandMessage::Stereo(_) => speex_sys::SPEEX_INBAND_STEREO,
+            InbandMessage::MaxBitrate(_) => speex_sys::SPEEX_INBAND_MAX_BITRATE,
+            InbandMessage::Acknowledge(_) => speex_sys::SPEEX_INBAND_ACKNOWLEDGE,
+            InbandMessage::Custom { id, .. } => *id as i32,
+            InbandMessage::User { .. } => return None,
+        };
+        Some(id as u8)
+    }
+
+    fn payload(&self) -> u64 {
+        match self {
+            InbandMessage::EnhancementRequest(value) | InbandMessage::LossRequest(value) => {
+                *value as u64
+            }
+            InbandMessage::ModeRequest(value)
+            | InbandMessage::LowModeRequest(value)
+            | InbandMessage::HighModeRequest(value)
+            | InbandMessage::VbrQualityRequest(value)
+            | InbandMessage::AcknowledgeRequest(value)
+            | InbandMessage::VbrRequest(value)
+            | InbandMessage::Char(value)
+            | InbandMessage::Stereo(value) => *value as u64,
+            InbandMessage::MaxBitrate(value) => *value as u64,
+            InbandMessage::Acknowledge(value) => *value as u64,
+            InbandMessage::Custom { payload, .. } => *payload,
+            InbandMessage::User { .. } => 0,
+        }
+    }
+
+    fn from_payload(id: u8, payload: u64) -> Self {
+        match id as i32 {
+            speex_sys::SPEEX_INBAND_ENH_REQUEST => InbandMessage::EnhancementRequest(payload != 0),
+            speex_sys::SPEEX_INBAND_RESERVED1 => InbandMessage::LossRequest(payload != 0),
+            speex_sys::SPEEX_INBAND_MODE_REQUEST => InbandMessage::ModeRequest(payload as u8),
+            speex_sys::SPEEX_INBAND_LOW_MODE_REQUEST => {
+                InbandMessage::LowModeRequest(payload as u8)
+            }
+            speex_sys::SPEEX_INBAND_HIGH_MODE_REQUEST => {
+                InbandMessage::HighModeRequest(payload as u8)
+            }
+            speex_sys::SPEEX_INBAND_VBR_QUALITY_REQUEST => {
+                InbandMessage::VbrQualityRequest(payload as u8)
+            }
+            speex_sys::SPEEX_INBAND_ACKNOWLEDGE_REQUEST => {
+                InbandMessage::AcknowledgeRequest(payload as u8)
+            }
+            speex_sys::SPEEX_INBAND_VBR_REQUEST => InbandMessage::VbrRequest(payload as u8),
+            speex_sys::SPEEX_INBAND_CHAR => InbandMessage::Char(payload as u8),
+            speex_sys::SPEEX_INBAND_STEREO => InbandMessage::Stereo(payload as u8),
+            speex_sys::SPEEX_INBAND_MAX_BITRATE => InbandMessage::MaxBitrate(payload as u16),
+            speex_sys::SPEEX_INBAND_ACKNOWLEDGE => InbandMessage::Acknowledge(payload as u32),
+            _ => InbandMessage::Custom { id, payload },
+        }
+    }
+
+    /// Appends the message to the bitstream.
+    ///
+    /// Nothing is written if the message is invalid.
+    pub fn write(&self, bits: &mut SpeexBits) -> Result<(), InbandError> {
+        if let InbandMessage::User { kind, payload } = self {
+            if *kind > 31 || payload.len() > 15 {
+                return Err(InbandError::ValueOutOfRange);
+            }
+            bits.pack(USER_INBAND_MODE, 5);
+            bits.pack(payload.len() as i32, 4);
+            bits.pack(*kind as i32, 5);
+            for &byte in payload {
+                bits.pack(byte as i32, 8);
+            }
+            return Ok(());
+        }
+
+        // Only `User` has no id
+        let id = self.id().unwrap_or_default();
+        if let InbandMessage::Custom { .. } = self {
+            if !matches!(id, 11 | 13 | 14 | 15) {
+                return Err(InbandError::ReservedId(id));
+            }
+        }
+        let size = payload_bits(id);
+        let payload = self.payload();
+        if size < 64 && payload >> size != 0 {
+            return Err(InbandError::ValueOutOfRange);
+        }
+        bits.pack(INBAND_MODE, 5);
+        bits.pack(id as i32, 4);
+        let mut left = size;
+        while left > 0 {
+            let chunk = left.min(32);
+            left -= chunk;
+            bits.pack((payload >> left) as u32 as i32, chunk as i32);
+        }
+        Ok(())
+    }
+
+    /// Reads a message from the current position of the bitstream.
+    ///
+    /// Returns `None`, without advancing, if what follows is not an in-band
+    /// message or is cut short.
+    pub fn read(bits: &mut SpeexBits) -> Option<Self> {
+        let remaining = bits.remaining();
+        if remaining < 9 {
+            return None;
+        }
+        // mode and id/length are peeked together, so nothing is consumed until
+        // the whole message is known to be there
+        let header = bits.peek_unsigned(9);
+        let mode = (header >> 4) as i32;
+        let low = (header & 0xF) as u8;
+        match mode {
+            INBAND_MODE => {
+                let size = payload_bits(low);
+                if remaining < 9 + size {
+                    return None;
+                }
+                bits.advance(9);
+                let mut payload = 0u64;
+                let mut left = size;
+                while left > 0 {
+                    let chunk = left.min(32);
+                    payload = (payload << chunk) | bits.unpacked_unsigned(chunk as i32) as u64;
+                    left -= chunk;
+                }
+                Some(Self::from_payload(low, payload))
+            }
+            USER_INBAND_MODE => {
+                let len = low as u32;
+                if remaining < 14 + 8 * len {
+                    return None;
+                }
+                bits.advance(9);
+                let kind = bits.unpacked_unsigned(5) as u8;
+                let payload = (0..len).map(|_| bits.unpacked_unsigned(8) as u8).collect();
+                Some(InbandMessage::User { kind, payload })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A closure handling one in-band message id, passed to speex as the callback
+/// data.
+pub(crate) struct InbandHandler {
+    id: u8,
+    func: Box<dyn FnMut(u64)>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl InbandHandler {
+    pub(crate) fn new(id: u8, func: impl FnMut(u64) + 'static) -> Box<Self> {
+        Box::new(Self {
+            id,
+            func: Box::new(func),
+            panic: None,
+        })
+    }
+
+    pub(crate) fn callback(self: &mut Box<Self>) -> speex_sys::SpeexCallback {
+        speex_sys::SpeexCallback {
+            callback_id: self.id as c_int,
+            func: Some(inband_trampoline),
+            data: self.as_mut() as *mut Self as *mut c_void,
+            reserved1: std::ptr::null_mut(),
+            reserved2: 0,
+        }
+    }
+
+    pub(crate) fn take_panic(&mut self) -> Option<Box<dyn Any + Send>> {
+        self.panic.take()
+    }
+}
+
+type UserFn = dyn FnMut(u8, &[u8]);
+
+/// A closure handling user-defined in-band messages (mode 13), passed to speex
+/// as the callback data.
+pub(crate) struct UserHandler {
+    func: Box<UserFn>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl UserHandler {
+    pub(crate) fn new(func: impl FnMut(u8, &[u8]) + 'static) -> Box<Self> {
+        Box::new(Self {
+            func: Box::new(func),
+            panic: None,
+        })
+    }
+
+    pub(crate) fn callback(self: &mut Box<Self>) -> speex_sys::SpeexCallback {
+        speex_sys::SpeexCallback {
+            callback_id: 0,
+            func: Some(user_trampoline),
+            data: self.as_mut() as *mut Self as *mut c_void,
+            reserved1: std::ptr::null_mut(),
+            reserved2: 0,
+        }
+    }
+
+    pub(crate) fn take_panic(&mut self) -> Option<Box<dyn Any + Send>> {
+        self.panic.take()
+    }
+}
+
+unsafe fn unpack(bits: *mut SysBits, num_bits: u32) -> u64 {
+    let mut value = 0;
+    let mut left = num_bits;
+    while left > 0 {
+        let chunk = left.min(32);
+        let read = unsafe { speex_sys::speex_bits_unpack_unsigned(bits, chunk as c_int) };
+        value = (value << chunk) | read as u64;
+        left -= chunk;
+    }
+    value
+}
+
+unsafe extern "C" fn inband_trampoline(
+    bits: *mut SysBits,
+    _state: *mut c_void,
+    data: *mut c_void,
+) -> c_int {
+    let handler = unsafe { &mut *(data as *mut InbandHandler) };
+    // The payload is always read, so the stream stays in sync even when the
+    // closure panics
+    let value = unsafe { unpack(bits, payload_bits(handler.id)) };
+    match catch_unwind(AssertUnwindSafe(|| (handler.func)(value))) {
+        Ok(()) => 0,
+        Err(panic) => {
+            handler.panic = Some(panic);
+            HANDLER_PANICKED
+        }
+    }
+}
+
+unsafe extern "C" fn user_trampoline(
+    bits: *mut SysBits,
+    _state: *mut c_void,
+    data: *mut c_void,
+) -> c_int {
+    let handler = unsafe { &mut *(data as *mut UserHandler) };
+    // Same layout `speex_default_user_handler` skips over: 4 bits of length,
+    // 5 bits of message type, then the payload bytes
+    let len = unsafe { unpack(bits, 4) } as usize;
+    let kind = unsafe { unpack(bits, 5) } as u8;
+    let payload: Vec<u8> = (0..len).map(|_| unsafe { unpack(bits, 8) } as u8).collect();
+    match catch_unwind(AssertUnwindSafe(|| (handler.func)(kind, &payload))) {
+        Ok(()) => 0,
+        Err(panic) => {
+            handler.panic = Some(panic);
+            HANDLER_PANICKED
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn all_messages() -> Vec<InbandMessage> {
+        vec![
+            InbandMessage::EnhancementRequest(true),
+            InbandMessage::LossRequest(false),
+            InbandMessage::ModeRequest(6),
+            InbandMessage::LowModeRequest(3),
+            InbandMessage::HighModeRequest(2),
+            InbandMessage::VbrQualityRequest(15),
+            InbandMessage::AcknowledgeRequest(2),
+            InbandMessage::VbrRequest(7),
+            InbandMessage::Char(b'z'),
+            InbandMessage::Stereo(0x9C),
+            InbandMessage::MaxBitrate(4000),
+            InbandMessage::Custom {
+                id: 11,
+                payload: 0xBEEF,
+            },
+            InbandMessage::Acknowledge(123_456),
+            InbandMessage::Custom {
+                id: 13,
+                payload: u32::MAX as u64,
+            },
+            InbandMessage::Custom {
+                id: 14,
+                payload: 0x0123_4567_89AB_CDEF,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let mut messages = all_messages();
+        messages.push(InbandMessage::User {
+            kind: 31,
+            payload: b"hello".to_vec(),
+        });
+        let mut bits = SpeexBits::new();
+        for message in &messages {
+            bits.pack_inband(message).unwrap();
+        }
+        bits.rewind();
+
+        for message in &messages {
+            assert_eq!(bits.unpack_inband().as_ref(), Some(message));
+        }
+        assert_eq!(bits.remaining(), 0);
+        assert_eq!(bits.unpack_inband(), None);
+    }
+
+    #[test]
+    fn libspeex_skips_written_messages() {
+        for message in all_messages() {
+            let mut bits = SpeexBits::new();
+            bits.pack_inband(&message).unwrap();
+            bits.rewind();
+
+            assert_eq!(bits.unpacked_unsigned(5) as i32, INBAND_MODE);
+            let mut callbacks = [speex_sys::SpeexCallback {
+                callback_id: 0,
+                func: None,
+                data: std::ptr::null_mut(),
+                reserved1: std::ptr::null_mut(),
+                reserved2: 0,
+            }; 16];
+            let result = unsafe {
+                speex_sys::speex_inband_handler(
+                    bits.backing_mut_ptr(),
+                    callbacks.as_mut_ptr(),
+                    std::ptr::null_mut(),
+                )
+            };
+
+            assert_eq!(result, 0);
+            assert_eq!(bits.remaining(), 0, "{message:?}");
+        }
+    }
+
+    #[test]
+    fn libspeex_skips_user_messages() {
+        let mut bits = SpeexBits::new();
+        let message = InbandMessage::User {
+            kind: 4,
+            payload: vec![1, 2, 3],
+        };
+        bits.pack_inband(&message).unwrap();
+        bits.rewind();
+
+        assert_eq!(bits.unpacked_unsigned(5) as i32, USER_INBAND_MODE);
+        let result = unsafe {
+            speex_sys::speex_default_user_handler(
+                bits.backing_mut_ptr(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+
+        assert_eq!(result, 0);
+        assert_eq!(bits.remaining(), 0);
+    }
+
+    #[test]
+    fn libspeex_acts_on_mode_request() {
+        let mut bits = SpeexBits::new();
+        bits.pack_inband(&InbandMessage::ModeRequest(3)).unwrap();
+        bits.rewind();
+        let mode = crate::ModeId::NarrowBand.get_mode();
+        let encoder = unsafe { speex_sys::speex_encoder_init(mode) };
+        let mut callbacks = [speex_sys::SpeexCallback {
+            callback_id: 0,
+            func: None,
+            data: std::ptr::null_mut(),
+            reserved1: std::ptr::null_mut(),
+            reserved2: 0,
+        }; 16];
+        let id = speex_sys::SPEEX_INBAND_MODE_REQUEST as usize;
+        callbacks[id].func = Some(speex_sys::speex_std_mode_request_handler);
+        callbacks[id].data = encoder;
+
+        bits.advance(5);
+        let mut submode = 0;
+        unsafe {
+            speex_sys::speex_inband_handler(
+                bits.backing_mut_ptr(),
+                callbacks.as_mut_ptr(),
+                std::ptr::null_mut(),
+            );
+            speex_sys::speex_encoder_ctl(
+                encoder,
+                speex_sys::SPEEX_GET_MODE,
+                &mut submode as *mut i32 as *mut c_void,
+            );
+            speex_sys::speex_encoder_destroy(encoder);
+        }
+
+        assert_eq!(submode, 3);
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        let mut bits = SpeexBits::new();
+
+        assert_eq!(
+            bits.pack_inband(&InbandMessage::ModeRequest(16)),
+            Err(InbandError::ValueOutOfRange)
+        );
+        assert_eq!(
+            bits.pack_inband(&InbandMessage::Custom { id: 2, payload: 1 }),
+            Err(InbandError::ReservedId(2))
+        );
+        assert_eq!(
+            bits.pack_inband(&InbandMessage::User {
+                kind: 0,
+                payload: vec![0; 16]
+            }),
+            Err(InbandError::ValueOutOfRange)
+        );
+        assert_eq!(bits.num_bits(), 0);
+    }
+
+    #[test]
+    fn ignores_regular_frames() {
+        let mut bits = SpeexBits::new();
+        bits.pack(0, 1);
+        bits.pack(5, 4);
+        bits.pack(0, 16);
+        bits.rewind();
+
+        assert_eq!(bits.unpack_inband(), None);
+        assert_eq!(bits.remaining(), 21);
+    }
+}
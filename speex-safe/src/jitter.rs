@@ -0,0 +1,532 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::resampler::Sample;
+use crate::{ControlError, DecoderError, DynamicDecoder, RtpPacket};
+
+/// Number of recent packets the jitter is measured over
+const JITTER_WINDOW: usize = 64;
+
+/// Share of recent packets that should arrive in time, in percent
+const ON_TIME_PERCENTILE: usize = 95;
+
+/// Packets in a row outside the buffer's range that make it start over on
+/// their timeline, rather than treating them as strays
+const RESYNC_PACKETS: u32 = 3;
+
+/// What `JitterBuffer::get` produced
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum JitterOutput {
+    /// A frame was decoded from a packet
+    Decoded,
+    /// The frame was missing or late, and was concealed by the decoder
+    Concealed,
+    /// Playback hasn't started yet, so the frame is silence
+    Buffering,
+}
+
+/// Buffer that turns speex RTP packets arriving at irregular times, out of
+/// order, duplicated or not at all, into a steady stream of frames.
+///
+/// Packets are ordered by RTP timestamp. `get` is called once per frame
+/// duration, and always produces exactly one frame: decoded if its packet
+/// arrived in time, and concealed by the decoder otherwise. Packets arriving
+/// after their frames were played are dropped.
+///
+/// The buffer never holds more than the maximum delay: packets further ahead
+/// than that are dropped. When the SSRC changes, or several packets in a row
+/// are that far from the playout point, the sender is taken to have started
+/// over, and playback restarts on the new timeline.
+///
+/// The delay is adapted to the jitter measured on recent packets, counting
+/// time in calls to `get`. When a packet is missing while less than the
+/// target delay is buffered, a concealed frame is inserted to wait for it,
+/// which adds a frame of delay. When more is buffered than needed, a frame is
+/// dropped to catch up.
+///
+/// Only mono streams are supported.
+pub struct JitterBuffer {
+    decoder: DynamicDecoder,
+    frame_size: i64,
+    ssrc: Option<u32>,
+    /// Packets in a row that were outside the buffer's range
+    out_of_range: u32,
+    /// Packets waiting to be decoded, by extended timestamp
+    packets: BTreeMap<i64, Vec<u8>>,
+    /// Frames decoded from the packet being played
+    ready: VecDeque<f32>,
+    /// Last timestamp seen, with its extended value, to undo wrapping
+    last_timestamp: Option<(u32, i64)>,
+    /// Extended timestamp of the next frame to play
+    playout: Option<i64>,
+    /// Latest extended timestamp received
+    newest: i64,
+    /// Time in samples, advanced by every call to `get`
+    clock: i64,
+    /// How late recent packets arrived, relative to their timestamps
+    transit: VecDeque<i64>,
+    min_delay: i64,
+    max_delay: i64,
+    target_delay: i64,
+    late_packets: u64,
+}
+
+impl JitterBuffer {
+    /// Creates a jitter buffer decoding with `decoder`.
+    pub fn new(decoder: DynamicDecoder) -> Self {
+        let frame_size = decoder.mode_id().get_frame_size() as i64;
+        Self {
+            decoder,
+            frame_size,
+            ssrc: None,
+            out_of_range: 0,
+            packets: BTreeMap::new(),
+            ready: VecDeque::new(),
+            last_timestamp: None,
+            playout: None,
+            newest: i64::MIN,
+            clock: 0,
+            transit: VecDeque::new(),
+            min_delay: frame_size * 2,
+            max_delay: frame_size * 25,
+            target_delay: frame_size * 2,
+            late_packets: 0,
+        }
+    }
+
+    /// Gets the underlying decoder
+    pub fn decoder_mut(&mut self) -> &mut DynamicDecoder {
+        &mut self.decoder
+    }
+
+    /// Sets the range the delay is adapted within, in frames. The defaults
+    /// are 2 and 25 (40 ms and 500 ms).
+    ///
+    /// Returns `ControlError::InvalidParameter` if `max_frames` is 0 or less
+    /// than `min_frames`.
+    pub fn set_delay_range(
+        &mut self,
+        min_frames: u32,
+        max_frames: u32,
+    ) -> Result<(), ControlError> {
+        if max_frames == 0 || min_frames > max_frames {
+            return Err(ControlError::InvalidParameter);
+        }
+        self.min_delay = min_frames as i64 * self.frame_size;
+        self.max_delay = max_frames as i64 * self.frame_size;
+        self.target_delay = self.target_delay.clamp(self.min_delay, self.max_delay);
+        Ok(())
+    }
+
+    /// Gets the delay being aimed for, in samples
+    pub fn target_delay(&self) -> u32 {
+        self.target_delay as u32
+    }
+
+    /// Gets how much audio is buffered ahead of playback, in samples
+    pub fn buffered(&self) -> u32 {
+        match self.playout {
+            Some(playout) => (self.newest + self.frame_size - playout).max(0) as u32,
+            None => {
+                match self.packets.first_key_value() {
+                    Some((&first, _)) => (self.newest + self.frame_size - first) as u32,
+                    None => 0,
+                }
+            }
+        }
+    }
+
+    /// Gets the number of packets dropped for arriving too late
+    pub fn late_packets(&self) -> u64 {
+        self.late_packets
+    }
+
+    /// Adds a received packet.
+    ///
+    /// Duplicates of buffered packets, packets whose time has already been
+    /// played, and packets further ahead than the maximum delay are dropped.
+    pub fn put(&mut self, packet: RtpPacket) {
+        if self.ssrc != Some(packet.ssrc) {
+            self.resync();
+            self.ssrc = Some(packet.ssrc);
+        }
+        let mut timestamp = self.extend_timestamp(packet.timestamp);
+        if !self.in_range(timestamp) {
+            self.out_of_range += 1;
+            if self.out_of_range < RESYNC_PACKETS {
+                return;
+            }
+            self.resync();
+            timestamp = self.extend_timestamp(packet.timestamp);
+        }
+        self.out_of_range = 0;
+        self.last_timestamp = self
+            .last_timestamp
+            .filter(|&(_, last_extended)| last_extended >= timestamp)
+            .or(Some((packet.timestamp, timestamp)));
+        self.measure_transit(timestamp);
+
+        if self.playout.is_some_and(|playout| timestamp < playout) {
+            self.late_packets += 1;
+            return;
+        }
+        self.newest = self.newest.max(timestamp);
+        self.packets.entry(timestamp).or_insert(packet.payload);
+    }
+
+    /// Whether a packet is close enough to what's being played or buffered
+    /// to belong to the same timeline, without taking the buffer past the
+    /// maximum delay
+    fn in_range(&self, timestamp: i64) -> bool {
+        match (self.playout, self.packets.first_key_value()) {
+            (Some(playout), _) => {
+                timestamp < playout + self.max_delay && timestamp > playout - self.max_delay
+            }
+            (None, Some((&first, _))) => {
+                timestamp.max(self.newest) - timestamp.min(first) < self.max_delay
+            }
+            (None, None) => true,
+        }
+    }
+
+    /// Forgets the current timeline, so playback starts over from the next
+    /// packets
+    fn resync(&mut self) {
+        self.packets.clear();
+        self.ready.clear();
+        self.last_timestamp = None;
+        self.playout = None;
+        self.newest = i64::MIN;
+        self.transit.clear();
+        self.out_of_range = 0;
+    }
+
+    /// Produces the next frame, appending it to `out`.
+    pub fn get(&mut self, out: &mut Vec<f32>) -> Result<JitterOutput, DecoderError> {
+        self.clock += self.frame_size;
+        let start = out.len();
+        out.resize(start + self.frame_size as usize, 0.0);
+
+        let Some(mut playout) = self.playout else {
+            self.start_playout();
+            return Ok(JitterOutput::Buffering);
+        };
+
+        // Catch up when more is buffered than the jitter calls for
+        let excess = self.buffered() as i64 - self.target_delay;
+        if excess > self.frame_size * 2 && self.has_frame(playout) {
+            self.next_frame(playout, &mut out[start..])?;
+            playout += self.frame_size;
+            self.playout = Some(playout);
+        }
+
+        if !self.has_frame(playout) && (self.buffered() as i64) < self.target_delay {
+            // The packet may still be on its way, so wait a frame for it,
+            // adding to the delay
+            self.decoder.decode_lost(&mut out[start..])?;
+            return Ok(JitterOutput::Concealed);
+        }
+        let output = self.next_frame(playout, &mut out[start..])?;
+        self.playout = Some(playout + self.frame_size);
+        Ok(output)
+    }
+
+    /// Produces the next frame as i16, appending it to `out`.
+    ///
+    /// See `get` for details.
+    pub fn get_int(&mut self, out: &mut Vec<i16>) -> Result<JitterOutput, DecoderError> {
+        let mut frame = Vec::new();
+        let output = self.get(&mut frame)?;
+        out.extend(frame.into_iter().map(i16::from_f32));
+        Ok(output)
+    }
+
+    /// Whether the frame at `playout` has been decoded or can be
+    fn has_frame(&self, playout: i64) -> bool {
+        !self.ready.is_empty() || self.packets.contains_key(&playout)
+    }
+
+    /// Plays the frame at `playout` into `out`, concealing it if its packet
+    /// is missing or can't be decoded.
+    fn next_frame(&mut self, playout: i64, out: &mut [f32]) -> Result<JitterOutput, DecoderError> {
+        let frame_size = self.frame_size as usize;
+        if self.ready.is_empty() {
+            // Anything before the playout point is too late to be played
+            self.packets = self.packets.split_off(&playout);
+            if let Some(packet) = self.packets.remove(&playout) {
+                let mut frames = Vec::new();
+                if self
+                    .decoder
+                    .decode_packet(&packet, &mut frames, None)
+                    .is_ok()
+                {
+                    self.ready.extend(frames);
+                }
+            }
+        }
+        if self.ready.len() < frame_size {
+            self.ready.clear();
+            self.decoder.decode_lost(out)?;
+            return Ok(JitterOutput::Concealed);
+        }
+        for (sample, decoded) in out.iter_mut().zip(self.ready.drain(..frame_size)) {
+            *sample = decoded;
+        }
+        Ok(JitterOutput::Decoded)
+    }
+
+    /// Starts playing once enough is buffered to cover the target delay
+    fn start_playout(&mut self) {
+        if self.buffered() as i64 >= self.target_delay {
+            self.playout = self.packets.first_key_value().map(|(&first, _)| first);
+        }
+    }
+
+    /// Turns a timestamp into one that keeps counting up past `u32::MAX`
+    fn extend_timestamp(&self, timestamp: u32) -> i64 {
+        match self.last_timestamp {
+            Some((last, last_extended)) => {
+                last_extended + timestamp.wrapping_sub(last) as i32 as i64
+            }
+            None => timestamp as i64,
+        }
+    }
+
+    /// Records how late a packet arrived, and sets the target delay to cover
+    /// most of the recent spread in arrival times.
+    fn measure_transit(&mut self, timestamp: i64) {
+        if self.transit.len() == JITTER_WINDOW {
+            self.transit.pop_front();
+        }
+        self.transit.push_back(self.clock - timestamp);
+
+        let mut transit: Vec<i64> = self.transit.iter().copied().collect();
+        transit.sort_unstable();
+        let earliest = transit[0];
+        let on_time = transit[(transit.len() - 1) * ON_TIME_PERCENTILE / 100];
+        let jitter = on_time - earliest;
+        // Round up to whole frames, with a frame to spare
+        let frames = (jitter + self.frame_size - 1) / self.frame_size + 1;
+        self.target_delay = (frames * self.frame_size).clamp(self.min_delay, self.max_delay);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DynamicEncoder, ModeId, RtpPacketizer, StreamEncoder};
+
+    const FRAMES: usize = 100;
+
+    /// Encodes a tone into single frame RTP packets, along with the result
+    /// of decoding them all in order
+    fn packets() -> (Vec<RtpPacket>, Vec<f32>) {
+        let input: Vec<f32> = (0..160 * FRAMES)
+            .map(|i| (i as f32 * 0.07).sin() * 6000.0)
+            .collect();
+        let mut stream = StreamEncoder::new(DynamicEncoder::new(ModeId::NarrowBand), 1).unwrap();
+        let mut packetizer =
+            RtpPacketizer::with_start(ModeId::NarrowBand, 97, 1, 0, u32::MAX - 800);
+        let packets: Vec<RtpPacket> = stream
+            .push(&input)
+            .unwrap()
+            .iter()
+            .map(|payload| packetizer.packetize(payload, 1))
+            .collect();
+
+        let mut decoder = DynamicDecoder::new(ModeId::NarrowBand);
+        let mut expected = Vec::new();
+        for packet in &packets {
+            decoder
+                .decode_packet(&packet.payload, &mut expected, None)
+                .unwrap();
+        }
+        (packets, expected)
+    }
+
+    /// Plays a trace of `(tick, packet index)` arrivals, calling `get` once
+    /// per tick
+    fn play(trace: &[(usize, usize)], ticks: usize) -> (JitterBuffer, Vec<f32>, Vec<JitterOutput>) {
+        let (packets, _) = packets();
+        let trace: Vec<(usize, RtpPacket)> = trace
+            .iter()
+            .map(|&(tick, index)| (tick, packets[index].clone()))
+            .collect();
+        play_packets(&trace, ticks)
+    }
+
+    /// Plays a trace of `(tick, packet)` arrivals, calling `get` once per
+    /// tick
+    fn play_packets(
+        trace: &[(usize, RtpPacket)],
+        ticks: usize,
+    ) -> (JitterBuffer, Vec<f32>, Vec<JitterOutput>) {
+        let mut buffer = JitterBuffer::new(DynamicDecoder::new(ModeId::NarrowBand));
+        let mut out = Vec::new();
+        let mut outputs = Vec::new();
+        for tick in 0..ticks {
+            for (_, packet) in trace.iter().filter(|(arrival, _)| *arrival == tick) {
+                buffer.put(packet.clone());
+            }
+            outputs.push(buffer.get(&mut out).unwrap());
+        }
+        (buffer, out, outputs)
+    }
+
+    /// A steady trace where every packet from `from` on is changed by `change`
+    fn changed_from(from: usize, change: impl Fn(&mut RtpPacket)) -> Vec<(usize, RtpPacket)> {
+        let (packets, _) = packets();
+        packets
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut packet)| {
+                if i >= from {
+                    change(&mut packet);
+                }
+                (i, packet)
+            })
+            .collect()
+    }
+
+    fn decoded(outputs: &[JitterOutput]) -> usize {
+        outputs
+            .iter()
+            .filter(|&&output| output == JitterOutput::Decoded)
+            .count()
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let (_, expected) = packets();
+        // Pairs of packets swapped, arriving a tick apart
+        let trace: Vec<(usize, usize)> = (0..FRAMES).map(|i| (i, i ^ 1)).collect();
+        let (buffer, out, outputs) = play(&trace, FRAMES + 10);
+
+        let start = outputs
+            .iter()
+            .position(|&output| output != JitterOutput::Buffering)
+            .unwrap();
+        assert_eq!(decoded(&outputs), FRAMES);
+        assert!(outputs[start..start + FRAMES]
+            .iter()
+            .all(|&output| output == JitterOutput::Decoded));
+        assert_eq!(out[start * 160..(start + FRAMES) * 160], expected[..]);
+        assert_eq!(buffer.late_packets(), 0);
+    }
+
+    #[test]
+    fn conceals_loss_and_ignores_duplicates() {
+        let trace: Vec<(usize, usize)> = (0..FRAMES)
+            .filter(|i| i % 10 != 5)
+            .flat_map(|i| [(i, i), (i + 1, i)])
+            .collect();
+        let (buffer, out, outputs) = play(&trace, FRAMES + 10);
+
+        assert_eq!(out.len(), (FRAMES + 10) * 160);
+        assert_eq!(decoded(&outputs), FRAMES - FRAMES / 10);
+        let lost = outputs
+            .iter()
+            .skip_while(|&&output| output == JitterOutput::Buffering)
+            .take(FRAMES)
+            .filter(|&&output| output == JitterOutput::Concealed)
+            .count();
+        assert_eq!(lost, FRAMES / 10);
+        assert_eq!(buffer.late_packets(), 0);
+    }
+
+    #[test]
+    fn adapts_delay_to_jitter() {
+        let steady: Vec<(usize, usize)> = (0..FRAMES).map(|i| (i, i)).collect();
+        let (buffer, _, outputs) = play(&steady, FRAMES + 10);
+
+        assert_eq!(buffer.target_delay(), 2 * 160);
+        assert_eq!(decoded(&outputs), FRAMES);
+
+        // Every fourth packet held up by three ticks
+        let jittery: Vec<(usize, usize)> = (0..FRAMES)
+            .map(|i| (if i % 4 == 0 { i + 3 } else { i }, i))
+            .collect();
+        let (buffer, _, outputs) = play(&jittery, FRAMES + 10);
+
+        assert!(buffer.target_delay() >= 3 * 160);
+        // Only packets arriving before the delay settled are too late
+        assert!(buffer.late_packets() <= 3, "{} late", buffer.late_packets());
+        assert!(decoded(&outputs) >= FRAMES - 3);
+    }
+
+    #[test]
+    fn drops_packets_beyond_max_delay() {
+        let (packets, _) = packets();
+        let mut buffer = JitterBuffer::new(DynamicDecoder::new(ModeId::NarrowBand));
+        buffer.set_delay_range(2, 5).unwrap();
+        // Everything at once, far more than the maximum delay
+        for packet in &packets {
+            buffer.put(packet.clone());
+            assert!(buffer.buffered() <= 5 * 160);
+        }
+
+        // A couple of stray packets far ahead don't disturb playback
+        let mut trace: Vec<(usize, RtpPacket)> = packets.iter().cloned().enumerate().collect();
+        for tick in [30, 31, 60] {
+            let mut stray = packets[tick].clone();
+            stray.timestamp = stray.timestamp.wrapping_add(1_000_000);
+            trace.push((tick, stray));
+        }
+        let (buffer, _, outputs) = play_packets(&trace, FRAMES + 10);
+
+        assert_eq!(decoded(&outputs), FRAMES);
+        assert_eq!(buffer.late_packets(), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_delay_range() {
+        let mut buffer = JitterBuffer::new(DynamicDecoder::new(ModeId::NarrowBand));
+        assert_eq!(
+            buffer.set_delay_range(5, 2),
+            Err(ControlError::InvalidParameter)
+        );
+        assert_eq!(
+            buffer.set_delay_range(0, 0),
+            Err(ControlError::InvalidParameter)
+        );
+        assert_eq!(buffer.set_delay_range(0, 1), Ok(()));
+    }
+
+    #[test]
+    fn resyncs_on_ssrc_change() {
+        // The sender restarts with a new SSRC and timestamps further back
+        let trace = changed_from(FRAMES / 2, |packet| {
+            packet.ssrc = 2;
+            packet.timestamp = packet.timestamp.wrapping_sub(50_000);
+        });
+        let (buffer, _, outputs) = play_packets(&trace, FRAMES + 10);
+
+        // Only what was buffered at the switch is lost
+        assert!(decoded(&outputs) >= FRAMES - 3, "{}", decoded(&outputs));
+        assert_eq!(buffer.late_packets(), 0);
+    }
+
+    #[test]
+    fn resyncs_on_timestamp_jump() {
+        for jump in [10_000_000, 0u32.wrapping_sub(10_000_000)] {
+            let trace = changed_from(FRAMES / 2, |packet| {
+                packet.timestamp = packet.timestamp.wrapping_add(jump);
+            });
+            let (buffer, _, outputs) = play_packets(&trace, FRAMES + 10);
+
+            // The packets before the jump is believed are lost too
+            assert!(
+                decoded(&outputs) >= FRAMES - 3 - RESYNC_PACKETS as usize,
+                "{}",
+                decoded(&outputs)
+            );
+            assert_eq!(buffer.late_packets(), 0);
+        }
+    }
+}
@@ -6,20 +6,45 @@
 ////////////////////////////////////////////////////////////////////////////////
 
 pub(crate) mod bits;
+pub(crate) mod comments;
+pub(crate) mod echo;
+pub(crate) mod error;
+pub(crate) mod fft;
 pub(crate) mod header;
+pub(crate) mod inband;
+pub(crate) mod jitter;
 pub(crate) mod mode;
+pub(crate) mod ogg;
+pub(crate) mod preprocess;
+pub(crate) mod resampler;
+pub(crate) mod rtp;
+pub(crate) mod sdp;
 pub(crate) mod stereo_state;
+pub(crate) mod stream;
+#[cfg(test)]
+pub(crate) mod test_util;
 
+use std::collections::hash_map::RandomState;
 use std::ffi::{c_char, c_void, CStr};
+use std::hash::{BuildHasher, Hasher};
 use std::ptr::null;
 
 pub use bits::SpeexBits;
-pub use header::SpeexHeader;
+pub use comments::{CommentsError, SpeexComments};
+pub use echo::{EchoCanceller, EchoError};
+pub use error::{ConversionError, Error};
+pub use header::{HeaderError, SpeexHeader, SpeexHeaderBuilder};
+pub use inband::{InbandError, InbandMessage};
+pub use jitter::{JitterBuffer, JitterOutput};
 pub use mode::{
     ControlError,
     ControlFunctions,
+    DecoderError,
+    DtxStatus,
     DynamicDecoder,
     DynamicEncoder,
+    EncodeReport,
+    EncoderError,
     ModeId,
     NbMode,
     NbSubmodeId,
@@ -30,6 +55,11 @@ pub use mode::{
     WbMode,
     WbSubmodeId,
 };
+pub use ogg::{OggError, OggSpeexReader, OggSpeexWriter};
+pub use preprocess::{PreprocessError, Preprocessor};
+pub use resampler::Resampler;
+pub use rtp::{RtpError, RtpPacket, RtpPacketizer};
+pub use sdp::{SdpError, SdpMode, SdpVbr, SpeexSdp};
 use speex_sys::{
     speex_lib_ctl,
     SPEEX_LIB_GET_EXTRA_VERSION,
@@ -39,6 +69,7 @@ use speex_sys::{
     SPEEX_LIB_GET_VERSION_STRING,
 };
 pub use stereo_state::SpeexStereoState;
+pub use stream::StreamEncoder;
 
 pub fn get_major_version() -> i32 {
     let mut major_version = 0;
@@ -90,6 +121,14 @@ pub fn get_version_string() -> String {
     cstr.to_string_lossy().into_owned()
 }
 
+/// Gets a random number, for the parts of a stream that should differ between
+/// streams, like Ogg serial numbers and RTP sequence numbers. Not suitable
+/// for anything that needs to be unpredictable.
+pub(crate) fn random_u64() -> u64 {
+    // Every RandomState is seeded differently
+    RandomState::new().build_hasher().finish()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -11,15 +11,21 @@ use std::marker::{PhantomData, PhantomPinned};
 
 use speex_sys::SpeexMode;
 
+use crate::inband::{self, InbandHandler, UserHandler};
 use crate::mode::{CoderMode, ControlFunctions, ModeId};
+use crate::resampler::Sample;
 use crate::{
     dynamic_mapping,
     mode,
     shared_functions,
     ControlError,
+    Error,
     NbMode,
     NbSubmodeId,
+    Resampler,
     SpeexBits,
+    SpeexHeader,
+    SpeexStereoState,
     UwbMode,
     WbMode,
     WbSubmodeId,
@@ -65,7 +71,15 @@ impl SpeexDecoderHandle {
 /// A struct representing a speex decoder.
 pub struct SpeexDecoder<T: CoderMode> {
     encoder_handle: *mut SpeexDecoderHandle,
-    pub mode: &'static SpeexMode,
+    mode_id: ModeId,
+    frames_per_packet: Option<usize>,
+    // speex holds pointers into these, so they have to outlive the handle
+    inband_handlers: [Option<Box<InbandHandler>>; 16],
+    user_handler: Option<Box<UserHandler>>,
+    stereo: Option<SpeexStereoState>,
+    /// Rate and quality to resample decoded packets to
+    output_rate: Option<(u32, u32)>,
+    resampler: Option<Resampler>,
     _phantom: PhantomData<T>,
 }
 
@@ -74,7 +88,7 @@ impl<T: CoderMode> mode::private::Sealed for SpeexDecoder<T> {}
 impl<T: CoderMode> ControlFunctions for SpeexDecoder<T> {
     unsafe fn ctl(&mut self, request: i32, ptr: *mut c_void) -> Result<(), ControlError> {
         let result = speex_sys::speex_decoder_ctl(self.encoder_handle as *mut c_void, request, ptr);
-        Self::check_error(result, Some(request))
+        Self::check_error(result, request)
     }
 }
 
@@ -83,6 +97,16 @@ pub enum DecoderError {
     TooSmallBuffer,
     EndOfStream,
     CorruptStream,
+    /// The decoder returned a code that is not documented
+    /// The parameter is the code that was returned
+    UnexpectedReturn(i32),
+    /// A control request made while decoding was rejected by the decoder
+    Control(ControlError),
+    /// A packet held more frames than the stream header says it should
+    TooManyFrames {
+        expected: usize,
+        actual: usize,
+    },
 }
 
 impl Display for DecoderError {
@@ -91,33 +115,287 @@ impl Display for DecoderError {
             DecoderError::TooSmallBuffer => write!(f, "Buffer is too small to decode into"),
             DecoderError::EndOfStream => write!(f, "End of stream reached while decoding"),
             DecoderError::CorruptStream => write!(f, "Corrupt stream was unable to be decoded"),
+            DecoderError::UnexpectedReturn(code) => {
+                write!(f, "Unexpected return code from the decoder ({code})")
+            }
+            DecoderError::Control(err) => write!(f, "Control request failed while decoding: {err}"),
+            DecoderError::TooManyFrames { expected, actual } => {
+                write!(
+                    f,
+                    "Packet held {actual} frames but at most {expected} were expected"
+                )
+            }
         }
     }
 }
 
+impl std::error::Error for DecoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecoderError::Control(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ControlError> for DecoderError {
+    fn from(value: ControlError) -> Self {
+        DecoderError::Control(value)
+    }
+}
+
 impl<T: CoderMode> SpeexDecoder<T> {
+    fn create(mode_id: ModeId) -> Self {
+        let mode = mode_id.get_mode();
+        let encoder_handle = unsafe { SpeexDecoderHandle::create(mode) };
+        Self {
+            encoder_handle,
+            mode_id,
+            frames_per_packet: None,
+            inband_handlers: Default::default(),
+            user_handler: None,
+            stereo: None,
+            output_rate: None,
+            resampler: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Gets the mode of the decoder
+    pub fn mode_id(&self) -> ModeId {
+        self.mode_id
+    }
+
+    /// Sets the number of frames expected in each packet, used by
+    /// `decode_packet` when no other count is given.
+    pub fn set_frames_per_packet(&mut self, frames_per_packet: Option<usize>) {
+        self.frames_per_packet = frames_per_packet;
+    }
+
+    /// Gets the number of frames expected in each packet.
+    pub fn frames_per_packet(&self) -> Option<usize> {
+        self.frames_per_packet
+    }
+
+    /// Sets a rate to resample the output of `decode_packet` (and its integer
+    /// and stereo variants) to, with the given quality (see `Resampler`).
+    /// `None` turns resampling off.
+    ///
+    /// Decoding single frames isn't affected. The resampler holds back a few
+    /// samples, which `flush_output` gets once the stream has ended.
+    ///
+    /// Returns `ControlError::InvalidParameter` if the rate is 0 or the
+    /// quality is above `Resampler::QUALITY_MAX`.
+    pub fn set_output_rate(&mut self, rate: Option<u32>, quality: u32) -> Result<(), ControlError> {
+        if let Some(rate) = rate {
+            Resampler::check_parameters(rate, rate, 1, quality)?;
+        }
+        self.output_rate = rate.map(|rate| (rate, quality));
+        self.resampler = None;
+        Ok(())
+    }
+
+    /// Gets the rate decoded packets are resampled to, if any.
+    pub fn output_rate(&self) -> Option<u32> {
+        self.output_rate.map(|(rate, _)| rate)
+    }
+
+    /// Drops the samples the output resampler is holding back, e.g. after
+    /// seeking, so the next packet starts a new stream.
+    pub fn reset_output(&mut self) {
+        self.resampler = None;
+    }
+
+    /// Appends the samples the output resampler is still holding back, at
+    /// the end of the stream.
+    ///
+    /// Returns the number of samples (per channel) appended.
+    pub fn flush_output(&mut self, out: &mut Vec<f32>) -> usize {
+        self.resampler
+            .as_mut()
+            .map_or(0, |resampler| resampler.flush(out))
+    }
+
+    /// Appends the samples the output resampler is still holding back, as
+    /// i16.
+    ///
+    /// See `flush_output` for details.
+    pub fn flush_output_int(&mut self, out: &mut Vec<i16>) -> usize {
+        self.resampler
+            .as_mut()
+            .map_or(0, |resampler| resampler.flush_int(out))
+    }
+
+    /// Routes in-band messages with the given id (0 to 15, see the
+    /// `SPEEX_INBAND_*` constants) to a closure.
+    ///
+    /// The closure receives the payload of the message, which is between 1
+    /// and 64 bits long depending on the id. Messages without a handler are
+    /// skipped. If the closure panics, the panic is resumed once the frame
+    /// has been decoded.
+    pub fn set_inband_handler(
+        &mut self,
+        id: u8,
+        handler: impl FnMut(u64) + 'static,
+    ) -> Result<(), ControlError> {
+        if id > inband::MAX_INBAND_ID {
+            return Err(ControlError::InvalidParameter);
+        }
+        let mut handler = InbandHandler::new(id, handler);
+        let mut callback = handler.callback();
+        let ptr = &mut callback as *mut speex_sys::SpeexCallback as *mut c_void;
+        unsafe { self.ctl(speex_sys::SPEEX_SET_HANDLER, ptr)? };
+        self.inband_handlers[id as usize] = Some(handler);
+        Ok(())
+    }
+
+    /// Removes the handler for the given in-band message id, so those
+    /// messages are skipped again.
+    pub fn clear_inband_handler(&mut self, id: u8) -> Result<(), ControlError> {
+        if id > inband::MAX_INBAND_ID {
+            return Err(ControlError::InvalidParameter);
+        }
+        self.set_raw_inband_handler(id, None, std::ptr::null_mut())?;
+        self.inband_handlers[id as usize] = None;
+        Ok(())
+    }
+
+    /// Routes user-defined in-band messages to a closure.
+    ///
+    /// The closure receives the 5 bit message type and the payload bytes. If
+    /// the closure panics, the panic is resumed once the frame has been
+    /// decoded.
+    pub fn set_user_handler(
+        &mut self,
+        handler: impl FnMut(u8, &[u8]) + 'static,
+    ) -> Result<(), ControlError> {
+        let mut handler = UserHandler::new(handler);
+        let mut callback = handler.callback();
+        let ptr = &mut callback as *mut speex_sys::SpeexCallback as *mut c_void;
+        unsafe { self.ctl(speex_sys::SPEEX_SET_USER_HANDLER, ptr)? };
+        self.user_handler = Some(handler);
+        Ok(())
+    }
+
+    /// Removes the user-defined in-band message handler, so those messages are
+    /// skipped again.
+    pub fn clear_user_handler(&mut self) -> Result<(), ControlError> {
+        let mut callback = speex_sys::SpeexCallback {
+            callback_id: 0,
+            func: Some(speex_sys::speex_default_user_handler),
+            data: std::ptr::null_mut(),
+            reserved1: std::ptr::null_mut(),
+            reserved2: 0,
+        };
+        let ptr = &mut callback as *mut speex_sys::SpeexCallback as *mut c_void;
+        unsafe { self.ctl(speex_sys::SPEEX_SET_USER_HANDLER, ptr)? };
+        self.user_handler = None;
+        Ok(())
+    }
+
+    /// Lets the standard speex handlers act on requests meant for the decoder.
+    ///
+    /// Currently this is only `SPEEX_INBAND_ENH_REQUEST`, which turns
+    /// enhancement on or off. The other standard requests (mode, VBR, ...)
+    /// are meant for the encoder on this side of the call, so they need to be
+    /// routed there with `set_inband_handler`.
+    pub fn use_standard_handlers(&mut self) -> Result<(), ControlError> {
+        let id = speex_sys::SPEEX_INBAND_ENH_REQUEST as u8;
+        let data = self.encoder_handle as *mut c_void;
+        self.set_raw_inband_handler(id, Some(speex_sys::speex_std_enh_request_handler), data)?;
+        self.inband_handlers[id as usize] = None;
+        Ok(())
+    }
+
+    /// Sets the stereo state used by `decode_stereo`, installing the standard
+    /// handler that keeps it updated from the in-band stereo information.
+    ///
+    /// This replaces any closure set for `SPEEX_INBAND_STEREO`.
+    pub fn set_stereo_state(&mut self, mut state: SpeexStereoState) -> Result<(), ControlError> {
+        let id = speex_sys::SPEEX_INBAND_STEREO as u8;
+        let data = state.backing_mut_ptr() as *mut c_void;
+        self.set_raw_inband_handler(id, Some(speex_sys::speex_std_stereo_request_handler), data)?;
+        self.inband_handlers[id as usize] = None;
+        self.stereo = Some(state);
+        Ok(())
+    }
+
+    /// Gets the stereo state used by `decode_stereo`, if one is set.
+    pub fn stereo_state(&self) -> Option<&SpeexStereoState> {
+        self.stereo.as_ref()
+    }
+
+    fn stereo_ptr(&mut self) -> Result<*mut speex_sys::SpeexStereoState, ControlError> {
+        if self.stereo.is_none() {
+            self.set_stereo_state(SpeexStereoState::new())?;
+        }
+        // Just set above if it was missing
+        Ok(self.stereo.as_mut().unwrap().backing_mut_ptr())
+    }
+
+    fn set_raw_inband_handler(
+        &mut self,
+        id: u8,
+        func: speex_sys::speex_callback_func,
+        data: *mut c_void,
+    ) -> Result<(), ControlError> {
+        let mut callback = speex_sys::SpeexCallback {
+            callback_id: id as i32,
+            func,
+            data,
+            reserved1: std::ptr::null_mut(),
+            reserved2: 0,
+        };
+        let ptr = &mut callback as *mut speex_sys::SpeexCallback as *mut c_void;
+        unsafe { self.ctl(speex_sys::SPEEX_SET_HANDLER, ptr) }
+    }
+
+    fn resume_handler_panic(&mut self) {
+        let panic = self
+            .inband_handlers
+            .iter_mut()
+            .flatten()
+            .find_map(|handler| handler.take_panic())
+            .or_else(|| self.user_handler.as_mut().and_then(|h| h.take_panic()));
+        if let Some(panic) = panic {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
     /// Set whether to use enhancement.
-    pub fn set_enhancement(&mut self, state: bool) {
+    pub fn set_enhancement(&mut self, state: bool) -> Result<(), ControlError> {
         let state = state as i32;
         let ptr = &state as *const i32 as *mut c_void;
+        unsafe { self.ctl(speex_sys::SPEEX_SET_ENH, ptr) }
+    }
+
+    /// Get whether enhancement is turned on or not.
+    pub fn get_enhancement(&mut self) -> Result<bool, ControlError> {
+        let mut state = 0;
+        let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_SET_ENH, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_ENH, ptr)?;
         }
+        Ok(state != 0)
     }
 
-    /// Get whether enhancement is turned on or not.
-    pub fn get_enhancement(&mut self) -> bool {
+    /// Gets whether the decoder is in DTX mode, meaning the encoder signalled
+    /// that the last decoded frame was silence.
+    ///
+    /// While this is true, lost frames are filled with comfort noise rather
+    /// than being concealed.
+    pub fn get_dtx_status(&mut self) -> Result<bool, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_ENH, ptr).unwrap();
+            self.ctl(mode::SPEEX_GET_DTX_STATUS, ptr)?;
         }
-        state != 0
+        Ok(state != 0)
     }
 
     /// Decode one frame of speex data from the bitstream
     pub fn decode(&mut self, bits: &mut SpeexBits, out: &mut [f32]) -> Result<(), DecoderError> {
-        let frame_size = self.get_frame_size() as usize;
+        let frame_size = self.get_frame_size()? as usize;
         if out.len() < frame_size {
             return Err(DecoderError::TooSmallBuffer);
         }
@@ -130,13 +408,18 @@ impl<T: CoderMode> SpeexDecoder<T> {
             0 => Ok(()),
             -1 => Err(DecoderError::EndOfStream),
             -2 => Err(DecoderError::CorruptStream),
-            _ => panic!("Unexpected return value from speex_decode"),
+            code => {
+                if code == inband::HANDLER_PANICKED {
+                    self.resume_handler_panic();
+                }
+                Err(DecoderError::UnexpectedReturn(code))
+            }
         }
     }
 
     /// Decode one frame of speex data from the bitstream into a new Vec<f32>
     pub fn decode_to_owned(&mut self, bits: &mut SpeexBits) -> Result<Vec<f32>, DecoderError> {
-        let frame_size = self.get_frame_size() as usize;
+        let frame_size = self.get_frame_size()? as usize;
         let mut out = vec![0.0; frame_size];
         self.decode(bits, &mut out)?;
         Ok(out)
@@ -148,7 +431,7 @@ impl<T: CoderMode> SpeexDecoder<T> {
         bits: &mut SpeexBits,
         out: &mut [i16],
     ) -> Result<(), DecoderError> {
-        let frame_size = self.get_frame_size() as usize;
+        let frame_size = self.get_frame_size()? as usize;
         if out.len() < frame_size {
             return Err(DecoderError::TooSmallBuffer);
         }
@@ -161,72 +444,345 @@ impl<T: CoderMode> SpeexDecoder<T> {
             0 => Ok(()),
             -1 => Err(DecoderError::EndOfStream),
             -2 => Err(DecoderError::CorruptStream),
-            _ => panic!("Unexpected return value from speex_decode"),
+            code => {
+                if code == inband::HANDLER_PANICKED {
+                    self.resume_handler_panic();
+                }
+                Err(DecoderError::UnexpectedReturn(code))
+            }
         }
     }
 
     /// Decode one frame of speex data from the bitstream into a new Vec<i16>
     pub fn decode_int_to_owned(&mut self, bits: &mut SpeexBits) -> Result<Vec<i16>, DecoderError> {
-        let frame_size = self.get_frame_size() as usize;
+        let frame_size = self.get_frame_size()? as usize;
         let mut out = vec![0; frame_size];
         self.decode_int(bits, &mut out)?;
         Ok(out)
     }
 
-    fn get_low_submode_internal(&mut self) -> NbSubmodeId {
+    /// Decode one frame of speex data from the bitstream as interleaved
+    /// stereo.
+    ///
+    /// `out` must hold a frame for each channel (twice `get_frame_size`
+    /// samples). If no stereo state was set with `set_stereo_state`, a new one
+    /// is installed first.
+    pub fn decode_stereo(
+        &mut self,
+        bits: &mut SpeexBits,
+        out: &mut [f32],
+    ) -> Result<(), DecoderError> {
+        let frame_size = self.get_frame_size()? as usize;
+        if out.len() < frame_size * 2 {
+            return Err(DecoderError::TooSmallBuffer);
+        }
+        let stereo = self.stereo_ptr()?;
+        self.decode(bits, out)?;
+        unsafe { speex_sys::speex_decode_stereo(out.as_mut_ptr(), frame_size as i32, stereo) };
+        Ok(())
+    }
+
+    /// Decode one frame of speex data from the bitstream as interleaved
+    /// stereo, as i16
+    ///
+    /// See `decode_stereo` for details.
+    pub fn decode_stereo_int(
+        &mut self,
+        bits: &mut SpeexBits,
+        out: &mut [i16],
+    ) -> Result<(), DecoderError> {
+        let frame_size = self.get_frame_size()? as usize;
+        if out.len() < frame_size * 2 {
+            return Err(DecoderError::TooSmallBuffer);
+        }
+        let stereo = self.stereo_ptr()?;
+        self.decode_int(bits, out)?;
+        unsafe { speex_sys::speex_decode_stereo_int(out.as_mut_ptr(), frame_size as i32, stereo) };
+        Ok(())
+    }
+
+    /// Synthesize one frame of audio for a packet that was lost, using packet
+    /// loss concealment.
+    ///
+    /// Repeated calls fade the output out, so this should only be used to
+    /// cover short gaps. While the decoder is in DTX mode (see
+    /// `get_dtx_status`) comfort noise is generated instead.
+    pub fn decode_lost(&mut self, out: &mut [f32]) -> Result<(), DecoderError> {
+        let frame_size = self.get_frame_size()? as usize;
+        if out.len() < frame_size {
+            return Err(DecoderError::TooSmallBuffer);
+        }
+        let out_ptr = out.as_mut_ptr();
+        let result = unsafe {
+            speex_sys::speex_decode(
+                self.encoder_handle as *mut c_void,
+                std::ptr::null_mut(),
+                out_ptr,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            code => Err(DecoderError::UnexpectedReturn(code)),
+        }
+    }
+
+    /// Synthesize one frame of audio for a packet that was lost, as i16
+    ///
+    /// See `decode_lost` for details.
+    pub fn decode_lost_int(&mut self, out: &mut [i16]) -> Result<(), DecoderError> {
+        let frame_size = self.get_frame_size()? as usize;
+        if out.len() < frame_size {
+            return Err(DecoderError::TooSmallBuffer);
+        }
+        let out_ptr = out.as_mut_ptr();
+        let result = unsafe {
+            speex_sys::speex_decode_int(
+                self.encoder_handle as *mut c_void,
+                std::ptr::null_mut(),
+                out_ptr,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            code => Err(DecoderError::UnexpectedReturn(code)),
+        }
+    }
+
+    /// Decode every frame in a packet, appending the decoded samples to `out`.
+    ///
+    /// Frames are decoded until the terminator or the end of the packet is
+    /// reached. If `expected_frames` is given (falling back to
+    /// `frames_per_packet`), packets with more frames than that are rejected.
+    /// Packets with fewer frames are accepted, as the last packet of a stream
+    /// is often short.
+    ///
+    /// Returns the number of frames decoded. On error, `out` is left as it was.
+    pub fn decode_packet(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<f32>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        self.decode_packet_with(packet, out, expected_frames, 1, Self::decode)
+    }
+
+    /// Decode every frame in a packet as i16, appending the decoded samples to
+    /// `out`.
+    ///
+    /// See `decode_packet` for how frames are counted.
+    pub fn decode_packet_int(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<i16>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        self.decode_packet_with(packet, out, expected_frames, 1, Self::decode_int)
+    }
+
+    /// Decode every frame in a packet as interleaved stereo, appending the
+    /// decoded samples to `out`.
+    ///
+    /// See `decode_packet` for how frames are counted and `decode_stereo` for
+    /// how the stereo state is used.
+    pub fn decode_packet_stereo(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<f32>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        self.decode_packet_with(packet, out, expected_frames, 2, Self::decode_stereo)
+    }
+
+    /// Decode every frame in a packet as interleaved stereo i16, appending the
+    /// decoded samples to `out`.
+    ///
+    /// See `decode_packet_stereo` for details.
+    pub fn decode_packet_stereo_int(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<i16>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        self.decode_packet_with(packet, out, expected_frames, 2, Self::decode_stereo_int)
+    }
+
+    /// Decodes every frame in a packet at the decoder's own sampling rate,
+    /// for the Ogg reader to trim before resampling.
+    pub(crate) fn decode_packet_unresampled(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<f32>,
+        channels: usize,
+    ) -> Result<usize, DecoderError> {
+        let decode_frame = if channels == 2 {
+            Self::decode_stereo
+        } else {
+            Self::decode
+        };
+        self.decode_frames(packet, out, None, channels, decode_frame)
+    }
+
+    /// Resamples decoded samples to the output rate, if one is set, appending
+    /// them to `out`.
+    ///
+    /// Returns the number of samples (per channel) appended.
+    pub(crate) fn resample_output(
+        &mut self,
+        decoded: &[f32],
+        out: &mut Vec<f32>,
+        channels: usize,
+    ) -> Result<usize, ControlError> {
+        match self.output_resampler(channels)? {
+            Some(resampler) => Ok(resampler.process(decoded, out)),
+            None => {
+                out.extend_from_slice(decoded);
+                Ok(decoded.len() / channels)
+            }
+        }
+    }
+
+    fn decode_packet_with<S: Sample>(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<S>,
+        expected_frames: Option<usize>,
+        channels: usize,
+        decode_frame: fn(&mut Self, &mut SpeexBits, &mut [S]) -> Result<(), DecoderError>,
+    ) -> Result<usize, DecoderError> {
+        let original_len = out.len();
+        let frames = self.decode_frames(packet, out, expected_frames, channels, decode_frame)?;
+        let resampler = match self.output_resampler(channels) {
+            Ok(resampler) => resampler,
+            Err(err) => {
+                out.truncate(original_len);
+                return Err(err.into());
+            }
+        };
+        if let Some(resampler) = resampler {
+            let decoded = out.split_off(original_len);
+            resampler.process_with(&decoded, out, Resampler::process);
+        }
+        Ok(frames)
+    }
+
+    fn decode_frames<S: Sample>(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<S>,
+        expected_frames: Option<usize>,
+        channels: usize,
+        decode_frame: fn(&mut Self, &mut SpeexBits, &mut [S]) -> Result<(), DecoderError>,
+    ) -> Result<usize, DecoderError> {
+        let expected_frames = expected_frames.or(self.frames_per_packet);
+        let frame_size = self.get_frame_size()? as usize * channels;
+        let mut bits = SpeexBits::new();
+        bits.read_from(&mut packet.to_vec());
+
+        let original_len = out.len();
+        let mut frames = 0;
+        let result = loop {
+            let start = out.len();
+            out.resize(start + frame_size, S::default());
+            match decode_frame(self, &mut bits, &mut out[start..]) {
+                Ok(()) => {}
+                Err(DecoderError::EndOfStream) => {
+                    out.truncate(start);
+                    break Ok(frames);
+                }
+                Err(err) => break Err(err),
+            }
+            // speex reads past the end of the buffer instead of failing when
+            // a frame is cut short
+            let remaining = unsafe { speex_sys::speex_bits_remaining(bits.backing_mut_ptr()) };
+            if remaining < 0 {
+                break Err(DecoderError::CorruptStream);
+            }
+            frames += 1;
+            if let Some(expected) = expected_frames {
+                if frames > expected {
+                    break Err(DecoderError::TooManyFrames {
+                        expected,
+                        actual: frames,
+                    });
+                }
+            }
+        };
+        if result.is_err() {
+            out.truncate(original_len);
+        }
+        result
+    }
+
+    /// Gets the resampler for the output rate, matching the current sampling
+    /// rate and number of channels
+    fn output_resampler(
+        &mut self,
+        channels: usize,
+    ) -> Result<Option<&mut Resampler>, ControlError> {
+        let Some((rate, quality)) = self.output_rate else {
+            return Ok(None);
+        };
+        let decoder_rate = self.get_sampling_rate()?;
+        if decoder_rate <= 0 {
+            return Err(ControlError::InvalidParameter);
+        }
+        let decoder_rate = decoder_rate as u32;
+        if decoder_rate == rate {
+            return Ok(None);
+        }
+        let matches = self.resampler.as_ref().is_some_and(|resampler| {
+            resampler.in_rate() == decoder_rate && resampler.channels() == channels
+        });
+        if !matches {
+            self.resampler = Some(Resampler::new(decoder_rate, rate, channels, quality)?);
+        }
+        Ok(self.resampler.as_mut())
+    }
+
+    fn get_low_submode_internal(&mut self) -> Result<NbSubmodeId, Error> {
         let mut low_mode = 0;
         let ptr = &mut low_mode as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_LOW_MODE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_LOW_MODE, ptr)?;
         }
-        low_mode.into()
+        Ok(NbSubmodeId::try_from(low_mode)?)
     }
 
-    fn set_low_submode_internal(&mut self, low_mode: NbSubmodeId) {
+    fn set_low_submode_internal(&mut self, low_mode: NbSubmodeId) -> Result<(), ControlError> {
         let low_mode = low_mode as i32;
         let ptr = &low_mode as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_LOW_MODE, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_LOW_MODE, ptr) }
     }
 
-    fn set_high_submode_internal(&mut self, high_mode: WbSubmodeId) {
+    fn set_high_submode_internal(&mut self, high_mode: WbSubmodeId) -> Result<(), ControlError> {
         let high_mode = high_mode as i32;
         let ptr = &high_mode as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_HIGH_MODE, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_HIGH_MODE, ptr) }
     }
 
-    fn get_high_submode_internal(&mut self) -> WbSubmodeId {
+    fn get_high_submode_internal(&mut self) -> Result<WbSubmodeId, Error> {
         let mut high_mode = 0;
         let ptr = &mut high_mode as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_HIGH_MODE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_HIGH_MODE, ptr)?;
         }
-        high_mode.into()
+        Ok(WbSubmodeId::try_from(high_mode)?)
     }
 }
 
 impl SpeexDecoder<NbMode> {
     /// Create a new narrowband encoder.
     pub fn new() -> SpeexDecoder<NbMode> {
-        let mode = ModeId::NarrowBand.get_mode();
-        let encoder_handle = unsafe { SpeexDecoderHandle::create(mode) };
-        Self {
-            encoder_handle,
-            mode,
-            _phantom: PhantomData,
-        }
+        Self::create(ModeId::NarrowBand)
     }
 
     /// Sets the submode to use for encoding.
-    pub fn set_submode(&mut self, submode: NbSubmodeId) {
-        self.set_low_submode_internal(submode);
+    pub fn set_submode(&mut self, submode: NbSubmodeId) -> Result<(), ControlError> {
+        self.set_low_submode_internal(submode)
     }
 
     /// Gets the submode currently in use for encoding.
-    pub fn get_submode(&mut self) -> NbSubmodeId {
+    pub fn get_submode(&mut self) -> Result<NbSubmodeId, Error> {
         self.get_low_submode_internal()
     }
 }
@@ -240,32 +796,26 @@ impl Default for SpeexDecoder<NbMode> {
 impl SpeexDecoder<WbMode> {
     /// Create a new WideBand encoder.
     pub fn new() -> SpeexDecoder<WbMode> {
-        let mode = ModeId::WideBand.get_mode();
-        let encoder_handle = unsafe { SpeexDecoderHandle::create(mode) };
-        Self {
-            encoder_handle,
-            mode,
-            _phantom: PhantomData,
-        }
+        Self::create(ModeId::WideBand)
     }
 
     /// Sets the submode of the narrowband part of the encoder.
-    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) {
-        self.set_low_submode_internal(low_mode);
+    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) -> Result<(), ControlError> {
+        self.set_low_submode_internal(low_mode)
     }
 
     /// Gets the submode of the narrowband part of the encoder.
-    pub fn get_low_submode(&mut self) -> NbSubmodeId {
+    pub fn get_low_submode(&mut self) -> Result<NbSubmodeId, Error> {
         self.get_low_submode_internal()
     }
 
     /// Sets the submode of the wideband part of the encoder.
-    pub fn set_high_submode(&mut self, high_mode: WbSubmodeId) {
-        self.set_high_submode_internal(high_mode);
+    pub fn set_high_submode(&mut self, high_mode: WbSubmodeId) -> Result<(), ControlError> {
+        self.set_high_submode_internal(high_mode)
     }
 
     /// Gets the submode of the wideband part of the encoder.
-    pub fn get_high_submode(&mut self) -> WbSubmodeId {
+    pub fn get_high_submode(&mut self) -> Result<WbSubmodeId, Error> {
         self.get_high_submode_internal()
     }
 }
@@ -279,22 +829,16 @@ impl Default for SpeexDecoder<WbMode> {
 impl SpeexDecoder<UwbMode> {
     /// Create a new Ultra WideBand encoder.
     pub fn new() -> SpeexDecoder<UwbMode> {
-        let mode = ModeId::UltraWideBand.get_mode();
-        let encoder_handle = unsafe { SpeexDecoderHandle::create(mode) };
-        Self {
-            encoder_handle,
-            mode,
-            _phantom: PhantomData,
-        }
+        Self::create(ModeId::UltraWideBand)
     }
 
     /// Sets the submode of the narrowband part of the encoder.
-    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) {
-        self.set_low_submode_internal(low_mode);
+    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) -> Result<(), ControlError> {
+        self.set_low_submode_internal(low_mode)
     }
 
     /// Gets the submode of the narrowband part of the encoder.
-    pub fn get_low_submode(&mut self) -> NbSubmodeId {
+    pub fn get_low_submode(&mut self) -> Result<NbSubmodeId, Error> {
         self.get_low_submode_internal()
     }
 }
@@ -323,15 +867,21 @@ impl DynamicDecoder {
     shared_functions!(DynamicDecoder);
 
     /// Set whether to use enhancement.
-    pub fn set_enhancement(&mut self, state: bool) {
+    pub fn set_enhancement(&mut self, state: bool) -> Result<(), ControlError> {
         dynamic_mapping!(self, DynamicDecoder, inner => inner.set_enhancement(state))
     }
 
     /// Get whether enhancement is turned on or not.
-    pub fn get_enhancement(&mut self) -> bool {
+    pub fn get_enhancement(&mut self) -> Result<bool, ControlError> {
         dynamic_mapping!(self, DynamicDecoder, inner => inner.get_enhancement())
     }
 
+    /// Gets whether the decoder is in DTX mode, meaning the encoder signalled
+    /// that the last decoded frame was silence.
+    pub fn get_dtx_status(&mut self) -> Result<bool, ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.get_dtx_status())
+    }
+
     /// Decode one frame of speex data from the bitstream
     pub fn decode(&mut self, bits: &mut SpeexBits, out: &mut [f32]) -> Result<(), DecoderError> {
         match self {
@@ -372,6 +922,122 @@ impl DynamicDecoder {
         }
     }
 
+    /// Routes in-band messages with the given id (0 to 15) to a closure.
+    pub fn set_inband_handler(
+        &mut self,
+        id: u8,
+        handler: impl FnMut(u64) + 'static,
+    ) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.set_inband_handler(id, handler))
+    }
+
+    /// Removes the handler for the given in-band message id.
+    pub fn clear_inband_handler(&mut self, id: u8) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.clear_inband_handler(id))
+    }
+
+    /// Routes user-defined in-band messages to a closure.
+    pub fn set_user_handler(
+        &mut self,
+        handler: impl FnMut(u8, &[u8]) + 'static,
+    ) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.set_user_handler(handler))
+    }
+
+    /// Removes the user-defined in-band message handler.
+    pub fn clear_user_handler(&mut self) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.clear_user_handler())
+    }
+
+    /// Lets the standard speex handlers act on requests meant for the decoder.
+    pub fn use_standard_handlers(&mut self) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.use_standard_handlers())
+    }
+
+    /// Sets the stereo state used by `decode_stereo`.
+    pub fn set_stereo_state(&mut self, state: SpeexStereoState) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.set_stereo_state(state))
+    }
+
+    /// Gets the stereo state used by `decode_stereo`, if one is set.
+    pub fn stereo_state(&self) -> Option<&SpeexStereoState> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.stereo_state())
+    }
+
+    /// Decode one frame of speex data from the bitstream as interleaved
+    /// stereo.
+    pub fn decode_stereo(
+        &mut self,
+        bits: &mut SpeexBits,
+        out: &mut [f32],
+    ) -> Result<(), DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_stereo(bits, out))
+    }
+
+    /// Decode one frame of speex data from the bitstream as interleaved
+    /// stereo, as i16
+    pub fn decode_stereo_int(
+        &mut self,
+        bits: &mut SpeexBits,
+        out: &mut [i16],
+    ) -> Result<(), DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_stereo_int(bits, out))
+    }
+
+    /// Synthesize one frame of audio for a packet that was lost, using packet
+    /// loss concealment.
+    pub fn decode_lost(&mut self, out: &mut [f32]) -> Result<(), DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_lost(out))
+    }
+
+    /// Synthesize one frame of audio for a packet that was lost, as i16
+    pub fn decode_lost_int(&mut self, out: &mut [i16]) -> Result<(), DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_lost_int(out))
+    }
+
+    /// Decode every frame in a packet, appending the decoded samples to `out`.
+    pub fn decode_packet(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<f32>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_packet(packet, out, expected_frames))
+    }
+
+    /// Decode every frame in a packet as i16, appending the decoded samples to
+    /// `out`.
+    pub fn decode_packet_int(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<i16>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_packet_int(packet, out, expected_frames))
+    }
+
+    /// Decode every frame in a packet as interleaved stereo, appending the
+    /// decoded samples to `out`.
+    pub fn decode_packet_stereo(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<f32>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_packet_stereo(packet, out, expected_frames))
+    }
+
+    /// Decode every frame in a packet as interleaved stereo i16, appending the
+    /// decoded samples to `out`.
+    pub fn decode_packet_stereo_int(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<i16>,
+        expected_frames: Option<usize>,
+    ) -> Result<usize, DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_packet_stereo_int(packet, out, expected_frames))
+    }
+
     pub fn new(mode: ModeId) -> DynamicDecoder {
         match mode {
             ModeId::NarrowBand => DynamicDecoder::Nb(SpeexDecoder::<NbMode>::new()),
@@ -380,6 +1046,77 @@ impl DynamicDecoder {
         }
     }
 
+    /// Creates a decoder for the stream described by the header, with its
+    /// sampling rate and frames per packet applied.
+    pub fn from_header(header: &SpeexHeader) -> Result<DynamicDecoder, Error> {
+        let mut decoder = DynamicDecoder::new(header.mode_id()?);
+        decoder.set_sampling_rate(header.rate() as i32)?;
+        let frames_per_packet = header.frames_per_packet() as usize;
+        decoder.set_frames_per_packet((frames_per_packet > 0).then_some(frames_per_packet));
+        Ok(decoder)
+    }
+
+    /// Gets the mode of the decoder
+    pub fn mode_id(&self) -> ModeId {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.mode_id())
+    }
+
+    /// Sets the number of frames expected in each packet.
+    pub fn set_frames_per_packet(&mut self, frames_per_packet: Option<usize>) {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.set_frames_per_packet(frames_per_packet))
+    }
+
+    /// Gets the number of frames expected in each packet.
+    pub fn frames_per_packet(&self) -> Option<usize> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.frames_per_packet())
+    }
+
+    /// Sets a rate to resample the output of `decode_packet` to.
+    ///
+    /// See `SpeexDecoder::set_output_rate` for details.
+    pub fn set_output_rate(&mut self, rate: Option<u32>, quality: u32) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.set_output_rate(rate, quality))
+    }
+
+    /// Gets the rate decoded packets are resampled to, if any.
+    pub fn output_rate(&self) -> Option<u32> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.output_rate())
+    }
+
+    /// Drops the samples the output resampler is holding back.
+    pub fn reset_output(&mut self) {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.reset_output())
+    }
+
+    /// Appends the samples the output resampler is still holding back.
+    pub fn flush_output(&mut self, out: &mut Vec<f32>) -> usize {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.flush_output(out))
+    }
+
+    pub(crate) fn decode_packet_unresampled(
+        &mut self,
+        packet: &[u8],
+        out: &mut Vec<f32>,
+        channels: usize,
+    ) -> Result<usize, DecoderError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.decode_packet_unresampled(packet, out, channels))
+    }
+
+    pub(crate) fn resample_output(
+        &mut self,
+        decoded: &[f32],
+        out: &mut Vec<f32>,
+        channels: usize,
+    ) -> Result<usize, ControlError> {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.resample_output(decoded, out, channels))
+    }
+
+    /// Appends the samples the output resampler is still holding back, as
+    /// i16.
+    pub fn flush_output_int(&mut self, out: &mut Vec<i16>) -> usize {
+        dynamic_mapping!(self, DynamicDecoder, inner => inner.flush_output_int(out))
+    }
+
     pub fn into_nb(self) -> Option<SpeexDecoder<NbMode>> {
         match self {
             DynamicDecoder::Nb(nb) => Some(nb),
@@ -401,3 +1138,446 @@ impl DynamicDecoder {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{DynamicEncoder, SpeexEncoder, StreamEncoder};
+
+    macro_rules! rejected_test {
+        ($name:ident, $mode:ty, $call:ident($($arg:expr),*), $request:ident) => {
+            #[test]
+            fn $name() {
+                let mut decoder = SpeexDecoder::<$mode>::new();
+                let result = decoder.$call($($arg),*);
+
+                assert_eq!(
+                    result.map(|_| ()),
+                    Err(ControlError::UnknownRequest(speex_sys::$request))
+                );
+            }
+        };
+    }
+
+    rejected_test!(nb_rejects_set_vbr, NbMode, set_vbr(true), SPEEX_SET_VBR);
+
+    rejected_test!(nb_rejects_get_vbr, NbMode, get_vbr(), SPEEX_GET_VBR);
+
+    rejected_test!(wb_rejects_set_vad, WbMode, set_vad(true), SPEEX_SET_VAD);
+
+    rejected_test!(uwb_rejects_set_abr, UwbMode, set_abr(2000), SPEEX_SET_ABR);
+
+    rejected_test!(
+        nb_rejects_set_quality,
+        NbMode,
+        set_quality(8),
+        SPEEX_SET_QUALITY
+    );
+
+    rejected_test!(
+        nb_rejects_set_bitrate,
+        NbMode,
+        set_bitrate(8000),
+        SPEEX_SET_BITRATE
+    );
+
+    rejected_test!(
+        wb_rejects_set_plc_tuning,
+        WbMode,
+        set_plc_tuning(10),
+        SPEEX_SET_PLC_TUNING
+    );
+
+    #[test]
+    fn nb_rejects_get_high_submode() {
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        let result = decoder.get_high_submode_internal();
+
+        assert_eq!(
+            result,
+            Err(Error::Control(ControlError::UnknownRequest(
+                speex_sys::SPEEX_GET_HIGH_MODE
+            )))
+        );
+    }
+
+    #[test]
+    fn wb_accepts_set_quality() {
+        let mut decoder = SpeexDecoder::<WbMode>::new();
+        assert_eq!(decoder.set_quality(8), Ok(()));
+    }
+
+    #[test]
+    fn set_get_enhancement() {
+        let mut decoder = SpeexDecoder::<WbMode>::new();
+        decoder.set_enhancement(false).unwrap();
+        assert_eq!(decoder.get_enhancement(), Ok(false));
+    }
+
+    #[test]
+    fn dtx_status_follows_encoder() {
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        encoder.set_vad(true).unwrap();
+        encoder.set_dtx(true).unwrap();
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        assert_eq!(decoder.get_dtx_status(), Ok(false));
+
+        let frame_size = encoder.get_frame_size().unwrap() as usize;
+        let mut bits = SpeexBits::new();
+        let mut out = vec![0i16; frame_size];
+        for _ in 0..10 {
+            bits.reset();
+            let input = vec![0i16; frame_size];
+            if encoder
+                .encode_int(&input, &mut bits)
+                .unwrap()
+                .needs_transmission()
+            {
+                bits.rewind();
+                decoder.decode_int(&mut bits, &mut out).unwrap();
+            }
+        }
+
+        assert_eq!(decoder.get_dtx_status(), Ok(true));
+    }
+
+    #[test]
+    fn dynamic_decoder_forwards_errors() {
+        let mut decoder = DynamicDecoder::new(ModeId::UltraWideBand);
+        assert_eq!(decoder.get_frame_size(), Ok(640));
+        assert_eq!(
+            decoder.set_vbr(true),
+            Err(ControlError::UnknownRequest(speex_sys::SPEEX_SET_VBR))
+        );
+    }
+
+    #[test]
+    fn decodes_all_frames_in_packet() {
+        let mut stream = StreamEncoder::new(SpeexEncoder::<WbMode>::new(), 3).unwrap();
+        let packets = stream.push_int(&vec![500i16; 320 * 3]).unwrap();
+        let mut decoder = DynamicDecoder::new(ModeId::WideBand);
+
+        let mut out = Vec::new();
+        let frames = decoder
+            .decode_packet_int(&packets[0], &mut out, Some(3))
+            .unwrap();
+
+        assert_eq!(frames, 3);
+        assert_eq!(out.len(), 320 * 3);
+    }
+
+    #[test]
+    fn resamples_decoded_packets() {
+        let mut stream = StreamEncoder::new(DynamicEncoder::new(ModeId::WideBand), 2).unwrap();
+        let packets = stream.push(&[0.0; 320 * 10]).unwrap();
+        let mut decoder = DynamicDecoder::new(ModeId::WideBand);
+        assert_eq!(
+            decoder.set_output_rate(Some(0), 4),
+            Err(ControlError::InvalidParameter)
+        );
+        assert_eq!(
+            decoder.set_output_rate(Some(44100), 11),
+            Err(ControlError::InvalidParameter)
+        );
+        decoder.set_output_rate(Some(44100), 4).unwrap();
+
+        let mut out = Vec::new();
+        for packet in &packets {
+            assert_eq!(decoder.decode_packet(packet, &mut out, None), Ok(2));
+        }
+        let held_back = decoder.flush_output(&mut out);
+
+        assert_eq!(decoder.output_rate(), Some(44100));
+        assert!(held_back > 0);
+        assert_eq!(out.len(), (320 * 10 * 441usize).div_ceil(160));
+
+        // A decoder rate of 0, as a bad header could give, is an error
+        decoder.set_sampling_rate(0).unwrap();
+        assert_eq!(
+            decoder.decode_packet(&packets[0], &mut out, None),
+            Err(DecoderError::Control(ControlError::InvalidParameter))
+        );
+        assert_eq!(out.len(), (320 * 10 * 441usize).div_ceil(160));
+    }
+
+    #[test]
+    fn accepts_short_packet() {
+        let mut stream = StreamEncoder::new(SpeexEncoder::<NbMode>::new(), 4).unwrap();
+        stream.push(&[0.0; 160]).unwrap();
+        let packet = stream.flush().unwrap().remove(0);
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+
+        let mut out = vec![1.0];
+        let frames = decoder.decode_packet(&packet, &mut out, Some(4)).unwrap();
+
+        assert_eq!(frames, 1);
+        assert_eq!(out.len(), 161);
+        assert_eq!(out[0], 1.0);
+    }
+
+    #[test]
+    fn rejects_too_many_frames() {
+        let mut stream = StreamEncoder::new(SpeexEncoder::<NbMode>::new(), 2).unwrap();
+        let packets = stream.push(&[0.0; 320]).unwrap();
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+
+        let mut out = Vec::new();
+        let result = decoder.decode_packet(&packets[0], &mut out, Some(1));
+
+        assert_eq!(
+            result,
+            Err(DecoderError::TooManyFrames {
+                expected: 1,
+                actual: 2
+            })
+        );
+        assert!(out.is_empty());
+    }
+
+    fn energy(frame: &[i16]) -> f64 {
+        frame.iter().map(|&s| (s as f64) * (s as f64)).sum()
+    }
+
+    #[test]
+    fn lost_frames_decay() {
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        let mut bits = SpeexBits::new();
+        let mut out = vec![0i16; 160];
+
+        let tone: Vec<i16> = (0..160 * 10)
+            .map(|i| ((i as f32 * 0.15).sin() * 8000.0) as i16)
+            .collect();
+        for frame in tone.chunks(160) {
+            bits.reset();
+            encoder.encode_int(frame, &mut bits).unwrap();
+            bits.rewind();
+            decoder.decode_int(&mut bits, &mut out).unwrap();
+        }
+        let received = energy(&out);
+
+        let lost: Vec<f64> = (0..10)
+            .map(|_| {
+                decoder.decode_lost_int(&mut out).unwrap();
+                energy(&out)
+            })
+            .collect();
+
+        assert!(lost[0] > 0.0);
+        assert!(lost[0] < received * 2.0);
+        assert!(lost[9] < lost[0] / 10.0);
+        assert!(lost[9] < lost[4]);
+    }
+
+    #[test]
+    fn dynamic_decode_lost_fills_frame() {
+        let mut decoder = DynamicDecoder::new(ModeId::UltraWideBand);
+
+        let mut short = vec![0.0; 100];
+        assert_eq!(
+            decoder.decode_lost(&mut short),
+            Err(DecoderError::TooSmallBuffer)
+        );
+        let mut out = vec![f32::NAN; 640];
+        decoder.decode_lost(&mut out).unwrap();
+        assert!(out.iter().all(|s| s.is_finite()));
+    }
+
+    /// Packs an in-band message in front of a real frame, the way an encoder
+    /// on the other end would.
+    fn frame_after(bits: &mut SpeexBits, mode: ModeId, pack: impl FnOnce(&mut SpeexBits)) {
+        pack(bits);
+        let input = vec![0i16; mode.get_frame_size() as usize];
+        DynamicEncoder::new(mode).encode_int(&input, bits).unwrap();
+        bits.rewind();
+    }
+
+    #[test]
+    fn routes_inband_to_closure() {
+        let received = Rc::new(Cell::new(None));
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        let sink = received.clone();
+        decoder
+            .set_inband_handler(speex_sys::SPEEX_INBAND_CHAR as u8, move |value| {
+                sink.set(Some(value))
+            })
+            .unwrap();
+
+        let mut bits = SpeexBits::new();
+        frame_after(&mut bits, ModeId::NarrowBand, |bits| {
+            bits.pack(0, 1);
+            bits.pack(14, 4);
+            bits.pack(speex_sys::SPEEX_INBAND_CHAR, 4);
+            bits.pack(b'A' as i32, 8);
+        });
+        decoder.decode_to_owned(&mut bits).unwrap();
+
+        assert_eq!(received.get(), Some(b'A' as u64));
+    }
+
+    #[test]
+    fn routes_user_message_to_closure() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let mut decoder = DynamicDecoder::new(ModeId::WideBand);
+        let sink = received.clone();
+        decoder
+            .set_user_handler(move |kind, payload| sink.borrow_mut().push((kind, payload.to_vec())))
+            .unwrap();
+
+        let mut bits = SpeexBits::new();
+        frame_after(&mut bits, ModeId::WideBand, |bits| {
+            bits.pack(0, 1);
+            bits.pack(13, 4);
+            bits.pack(2, 4);
+            bits.pack(7, 5);
+            bits.pack(0xAB, 8);
+            bits.pack(0xCD, 8);
+        });
+        decoder.decode_int_to_owned(&mut bits).unwrap();
+
+        assert_eq!(*received.borrow(), vec![(7, vec![0xAB, 0xCD])]);
+    }
+
+    #[test]
+    fn skips_inband_after_clearing() {
+        let calls = Rc::new(Cell::new(0));
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        let sink = calls.clone();
+        decoder
+            .set_inband_handler(12, move |_| sink.set(sink.get() + 1))
+            .unwrap();
+        decoder.clear_inband_handler(12).unwrap();
+
+        let mut bits = SpeexBits::new();
+        frame_after(&mut bits, ModeId::NarrowBand, |bits| {
+            bits.pack(0, 1);
+            bits.pack(14, 4);
+            bits.pack(12, 4);
+            bits.pack(-1, 32);
+        });
+        decoder.decode_to_owned(&mut bits).unwrap();
+
+        assert_eq!(calls.get(), 0);
+        assert_eq!(
+            decoder.set_inband_handler(16, |_| {}),
+            Err(ControlError::InvalidParameter)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "handler blew up")]
+    fn resumes_handler_panic() {
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        decoder
+            .set_inband_handler(0, |_| panic!("handler blew up"))
+            .unwrap();
+
+        let mut bits = SpeexBits::new();
+        frame_after(&mut bits, ModeId::NarrowBand, |bits| {
+            bits.pack(0, 1);
+            bits.pack(14, 4);
+            bits.pack(0, 4);
+            bits.pack(1, 1);
+        });
+        let _ = decoder.decode_to_owned(&mut bits);
+    }
+
+    #[test]
+    fn standard_handlers_set_enhancement() {
+        let mut decoder = SpeexDecoder::<WbMode>::new();
+        decoder.use_standard_handlers().unwrap();
+        decoder.set_enhancement(true).unwrap();
+
+        let mut bits = SpeexBits::new();
+        frame_after(&mut bits, ModeId::WideBand, |bits| {
+            bits.pack(0, 1);
+            bits.pack(14, 4);
+            bits.pack(speex_sys::SPEEX_INBAND_ENH_REQUEST, 4);
+            bits.pack(0, 1);
+        });
+        decoder.decode_to_owned(&mut bits).unwrap();
+
+        assert!(!decoder.get_enhancement().unwrap());
+    }
+
+    #[test]
+    fn stereo_round_trip_keeps_balance() {
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        let mut decoder = DynamicDecoder::new(ModeId::NarrowBand);
+        let mut out = vec![0i16; 320];
+
+        // left channel much louder than the right
+        let input: Vec<i16> = (0..160)
+            .flat_map(|i| {
+                let sample = (i as f32 * 0.2).sin() * 8000.0;
+                [sample as i16, (sample / 8.0) as i16]
+            })
+            .collect();
+        for _ in 0..5 {
+            let mut bits = SpeexBits::new();
+            encoder.encode_stereo_int(&input, &mut bits).unwrap();
+            bits.rewind();
+            decoder.decode_stereo_int(&mut bits, &mut out).unwrap();
+        }
+
+        let left: f64 = out.iter().step_by(2).map(|&s| (s as f64).powi(2)).sum();
+        let right: f64 = out
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .map(|&s| (s as f64).powi(2))
+            .sum();
+        assert!(decoder.stereo_state().unwrap().balance() > 1.0);
+        assert!(left > right * 10.0, "left {left} right {right}");
+    }
+
+    #[test]
+    fn stereo_needs_room_for_both_channels() {
+        let mut encoder = SpeexEncoder::<WbMode>::new();
+        let mut decoder = SpeexDecoder::<WbMode>::new();
+        decoder.set_stereo_state(SpeexStereoState::new()).unwrap();
+        let mut bits = SpeexBits::new();
+
+        assert_eq!(
+            encoder.encode_stereo(&[0.0; 320], &mut bits),
+            Err(crate::EncoderError::WrongFrameSize {
+                expected: 640,
+                actual: 320
+            })
+        );
+        let mut out = vec![0.0; 320];
+        assert_eq!(
+            decoder.decode_stereo(&mut bits, &mut out),
+            Err(DecoderError::TooSmallBuffer)
+        );
+    }
+
+    #[test]
+    fn creates_from_header() {
+        let header = crate::SpeexHeaderBuilder::new(32000, 1, ModeId::UltraWideBand)
+            .frames_per_packet(2)
+            .build()
+            .unwrap();
+        let mut stream = StreamEncoder::from_header(&header).unwrap();
+        let packets = stream.push(&[0.0; 640 * 3]).unwrap();
+
+        let mut decoder = DynamicDecoder::from_header(&header).unwrap();
+
+        assert_eq!(decoder.mode_id(), ModeId::UltraWideBand);
+        assert_eq!(decoder.get_sampling_rate(), Ok(32000));
+        assert_eq!(decoder.frames_per_packet(), Some(2));
+        let mut out = Vec::new();
+        assert_eq!(decoder.decode_packet(&packets[0], &mut out, None), Ok(2));
+        decoder.set_frames_per_packet(Some(1));
+        assert_eq!(
+            decoder.decode_packet(&packets[0], &mut out, None),
+            Err(DecoderError::TooManyFrames {
+                expected: 1,
+                actual: 2
+            })
+        );
+    }
+}
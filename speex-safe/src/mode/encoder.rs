@@ -6,12 +6,23 @@
 ////////////////////////////////////////////////////////////////////////////////
 
 use std::ffi::c_void;
+use std::fmt::{Display, Formatter};
 use std::marker::{PhantomData, PhantomPinned};
 
 use speex_sys::SpeexMode;
 
 use crate::mode::{CoderMode, ControlError, ControlFunctions, ModeId, NbMode, UwbMode, WbMode};
-use crate::{dynamic_mapping, mode, shared_functions, NbSubmodeId, SpeexBits, WbSubmodeId};
+use crate::{
+    dynamic_mapping,
+    mode,
+    shared_functions,
+    ConversionError,
+    Error,
+    NbSubmodeId,
+    SpeexBits,
+    SpeexHeader,
+    WbSubmodeId,
+};
 
 /// Handle for the encoder, speex represents this as an opaque pointer so this
 /// is an unconstructable type that is always intended to be behind a pointer.
@@ -50,10 +61,150 @@ impl SpeexEncoderHandle {
     }
 }
 
+/// Whether a frame needs to be sent after encoding.
+///
+/// With discontinuous transmission (DTX) enabled, the encoder marks frames of
+/// silence that the decoder can fill in on its own. Those frames can be
+/// skipped on the wire entirely.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DtxStatus {
+    /// The frame has to be transmitted
+    Transmit,
+    /// The frame is silence and does not need to be transmitted
+    Skip,
+}
+
+impl DtxStatus {
+    fn from_encode_result(result: i32) -> Self {
+        if result == 0 {
+            DtxStatus::Skip
+        } else {
+            DtxStatus::Transmit
+        }
+    }
+
+    /// Returns true if the frame has to be transmitted
+    pub fn needs_transmission(self) -> bool {
+        self == DtxStatus::Transmit
+    }
+}
+
+/// Error type for encoding a frame.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EncoderError {
+    /// The input did not contain exactly one frame of samples
+    WrongFrameSize { expected: usize, actual: usize },
+    /// A control request made while encoding was rejected by the encoder
+    Control(ControlError),
+    /// The encoder reported a submode this crate doesn't know
+    Conversion(ConversionError),
+}
+
+impl Display for EncoderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncoderError::WrongFrameSize { expected, actual } => {
+                write!(
+                    f,
+                    "Input has {actual} samples but the frame size is {expected}"
+                )
+            }
+            EncoderError::Control(err) => write!(f, "Control request failed while encoding: {err}"),
+            EncoderError::Conversion(err) => write!(f, "Unexpected value while encoding: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for EncoderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EncoderError::Control(err) => Some(err),
+            EncoderError::Conversion(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ControlError> for EncoderError {
+    fn from(value: ControlError) -> Self {
+        EncoderError::Control(value)
+    }
+}
+
+impl From<ConversionError> for EncoderError {
+    fn from(value: ConversionError) -> Self {
+        EncoderError::Conversion(value)
+    }
+}
+
+/// Information about a single encoded frame.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct EncodeReport {
+    /// Number of bits appended to the `SpeexBits`
+    pub bits: u32,
+    /// Number of bytes the `SpeexBits` grew by, counting partial bytes
+    pub bytes: u32,
+    /// The narrowband submode the frame was encoded with. For wideband and
+    /// ultra-wideband this is the submode of the embedded narrowband layer.
+    ///
+    /// This is `None` when the null submode was used, which happens for
+    /// silent frames under DTX.
+    pub submode: Option<NbSubmodeId>,
+    /// The submode of the wideband layer, for wideband and ultra-wideband.
+    ///
+    /// This is `None` for narrowband, and when the null submode was used.
+    pub high_submode: Option<WbSubmodeId>,
+    /// Instantaneous bitrate of the encoder after encoding the frame
+    pub bitrate: i32,
+    /// Relative quality of the frame as estimated by the encoder. This is
+    /// -1 unless VBR or VAD is enabled.
+    pub relative_quality: f32,
+    /// Whether the frame needs to be transmitted
+    pub status: DtxStatus,
+}
+
+impl EncodeReport {
+    /// Returns true if the frame has to be transmitted
+    pub fn needs_transmission(&self) -> bool {
+        self.status.needs_transmission()
+    }
+}
+
+/// Bits in a narrowband frame for each submode, including the wideband bit and
+/// the submode id
+const NB_FRAME_BITS: [u32; 9] = [5, 43, 119, 160, 220, 300, 364, 492, 79];
+
+/// Reads the submode of the first high band layer of the wideband or
+/// ultra-wideband frame starting at `frame_start`.
+///
+/// The encoders reject `SPEEX_GET_HIGH_MODE`, so this is read back from the
+/// frame instead.
+fn high_submode_of(
+    bits: &SpeexBits,
+    frame_start: u32,
+) -> Result<Option<WbSubmodeId>, ConversionError> {
+    // The narrowband layer starts with a 0 bit and its submode id
+    let low = bits.bits_at(frame_start + 1, 4).unwrap_or(0) as i32;
+    let low_bits = NB_FRAME_BITS
+        .get(low as usize)
+        .ok_or(ConversionError::InvalidSubmode(low))?;
+    // The high band layer follows, starting with a 1 bit and its submode id
+    let submode = bits.bits_at(frame_start + low_bits + 1, 3).unwrap_or(0) as i32;
+    (submode != 0)
+        .then(|| WbSubmodeId::try_from(submode))
+        .transpose()
+}
+
 /// A struct representing a speex encoder.
 pub struct SpeexEncoder<T: CoderMode> {
     encoder_handle: *mut SpeexEncoderHandle,
-    pub mode: &'static SpeexMode,
+    mode_id: ModeId,
+    frame_size: usize,
+    // speex scribbles over the input while encoding, so input passed by
+    // reference is copied into these first. They hold a stereo frame, mono
+    // frames only use the first half.
+    scratch: Vec<f32>,
+    scratch_int: Vec<i16>,
     _phantom: PhantomData<T>,
 }
 
@@ -62,105 +213,320 @@ impl<T: CoderMode> mode::private::Sealed for SpeexEncoder<T> {}
 impl<T: CoderMode> ControlFunctions for SpeexEncoder<T> {
     unsafe fn ctl(&mut self, request: i32, ptr: *mut c_void) -> Result<(), ControlError> {
         let result = speex_sys::speex_encoder_ctl(self.encoder_handle as *mut c_void, request, ptr);
-        Self::check_error(result, Some(request))
+        Self::check_error(result, request)
     }
 }
 
 impl<T: CoderMode> SpeexEncoder<T> {
-    fn get_low_submode_internal(&mut self) -> NbSubmodeId {
+    fn create(mode_id: ModeId) -> Self {
+        let mode = mode_id.get_mode();
+        let frame_size = mode_id.get_frame_size() as usize;
+        let encoder_handle = unsafe { SpeexEncoderHandle::create(mode) };
+        Self {
+            encoder_handle,
+            mode_id,
+            frame_size,
+            scratch: vec![0.0; frame_size * 2],
+            scratch_int: vec![0; frame_size * 2],
+            _phantom: PhantomData,
+        }
+    }
+
+    fn get_low_submode_internal(&mut self) -> Result<NbSubmodeId, Error> {
         let mut low_mode = 0;
         let ptr = &mut low_mode as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_LOW_MODE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_LOW_MODE, ptr)?;
         }
-        low_mode.into()
+        Ok(NbSubmodeId::try_from(low_mode)?)
     }
 
-    fn set_low_submode_internal(&mut self, low_mode: NbSubmodeId) {
+    fn set_low_submode_internal(&mut self, low_mode: NbSubmodeId) -> Result<(), ControlError> {
         let low_mode = low_mode as i32;
         let ptr = &low_mode as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_LOW_MODE, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_LOW_MODE, ptr) }
     }
 
-    fn set_high_submode_internal(&mut self, high_mode: WbSubmodeId) {
+    fn set_high_submode_internal(&mut self, high_mode: WbSubmodeId) -> Result<(), ControlError> {
         let high_mode = high_mode as i32;
         let ptr = &high_mode as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_HIGH_MODE, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_HIGH_MODE, ptr) }
     }
 
-    fn get_high_submode_internal(&mut self) -> WbSubmodeId {
+    fn get_high_submode_internal(&mut self) -> Result<WbSubmodeId, Error> {
         let mut high_mode = 0;
         let ptr = &mut high_mode as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_HIGH_MODE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_HIGH_MODE, ptr)?;
         }
-        high_mode.into()
+        Ok(WbSubmodeId::try_from(high_mode)?)
     }
 
     /// Sets the analysis complexity of the encoder.
-    pub fn set_complexity(&mut self, complexity: i32) {
+    pub fn set_complexity(&mut self, complexity: i32) -> Result<(), ControlError> {
         let ptr = &complexity as *const i32 as *mut c_void;
+        unsafe { self.ctl(speex_sys::SPEEX_SET_COMPLEXITY, ptr) }
+    }
+
+    /// Gets the analysis complexity of the encoder.
+    pub fn get_complexity(&mut self) -> Result<i32, ControlError> {
+        let mut state = 0;
+        let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_SET_COMPLEXITY, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_COMPLEXITY, ptr)?;
         }
+        Ok(state)
     }
 
-    /// Gets the analysis complexity of the encoder.
-    pub fn get_complexity(&mut self) -> i32 {
+    /// Sets whether Discontinuous Transmission is enabled or not
+    ///
+    /// DTX only has an effect when VBR or VAD is also enabled.
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<(), ControlError> {
+        let state = dtx as i32;
+        let ptr = &state as *const i32 as *mut c_void;
+        unsafe { self.ctl(speex_sys::SPEEX_SET_DTX, ptr) }
+    }
+
+    /// Gets whether Discontinuous Transmission is enabled or not
+    pub fn get_dtx(&mut self) -> Result<bool, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_COMPLEXITY, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_DTX, ptr)?;
+        }
+        Ok(state != 0)
+    }
+
+    /// Gets the relative quality of the last encoded frame, as estimated by
+    /// the encoder. This is -1 unless VBR or VAD is enabled.
+    pub fn get_relative_quality(&mut self) -> Result<f32, ControlError> {
+        let mut state = 0.0;
+        let ptr = &mut state as *mut f32 as *mut c_void;
+        unsafe {
+            self.ctl(speex_sys::SPEEX_GET_RELATIVE_QUALITY, ptr)?;
+        }
+        Ok(state)
+    }
+
+    fn make_report(
+        &mut self,
+        result: i32,
+        bits: &mut SpeexBits,
+        bits_before: u32,
+        bytes_before: i32,
+        frame_start: u32,
+    ) -> Result<EncodeReport, EncoderError> {
+        let mut submode = 0;
+        let ptr = &mut submode as *mut i32 as *mut c_void;
+        unsafe {
+            self.ctl(speex_sys::SPEEX_GET_LOW_MODE, ptr)?;
+        }
+        let high_submode = match self.mode_id {
+            ModeId::NarrowBand => None,
+            _ => high_submode_of(bits, frame_start)?,
+        };
+        Ok(EncodeReport {
+            bits: bits.num_bits() - bits_before,
+            bytes: (bits.num_bytes() - bytes_before) as u32,
+            submode: (submode != 0)
+                .then(|| NbSubmodeId::try_from(submode))
+                .transpose()?,
+            high_submode,
+            bitrate: self.get_bitrate()?,
+            relative_quality: self.get_relative_quality()?,
+            status: DtxStatus::from_encode_result(result),
+        })
+    }
+
+    fn check_frame_size(&self, len: usize) -> Result<(), EncoderError> {
+        if len == self.frame_size {
+            Ok(())
+        } else {
+            Err(EncoderError::WrongFrameSize {
+                expected: self.frame_size,
+                actual: len,
+            })
+        }
+    }
+
+    /// Gets the mode of the encoder
+    pub fn mode_id(&self) -> ModeId {
+        self.mode_id
+    }
+
+    fn check_stereo_frame_size(&self, len: usize) -> Result<(), EncoderError> {
+        if len == self.frame_size * 2 {
+            Ok(())
+        } else {
+            Err(EncoderError::WrongFrameSize {
+                expected: self.frame_size * 2,
+                actual: len,
+            })
         }
-        state
     }
 
     /// Encode one frame of audio into the given bits.
-    pub fn encode(&mut self, input: &mut [f32], bits: &mut SpeexBits) {
-        let input_ptr = input.as_mut_ptr();
+    ///
+    /// The input must be exactly one frame (see `get_frame_size`) long. It is
+    /// copied before encoding, use `encode_in_place` to avoid the copy.
+    ///
+    /// Returns a report on the encoded frame, including whether it needs to be
+    /// transmitted at all.
+    pub fn encode(
+        &mut self,
+        input: &[f32],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.check_frame_size(input.len())?;
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let frame = &mut scratch[..input.len()];
+        frame.copy_from_slice(input);
+        let result = self.encode_in_place(frame, bits);
+        self.scratch = scratch;
+        result
+    }
+
+    /// Encode one frame of audio into the given bits, using an integer
+    /// representation.
+    ///
+    /// The input must be exactly one frame (see `get_frame_size`) long. It is
+    /// copied before encoding, use `encode_int_in_place` to avoid the copy.
+    ///
+    /// Returns a report on the encoded frame, including whether it needs to be
+    /// transmitted at all.
+    pub fn encode_int(
+        &mut self,
+        input: &[i16],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.check_frame_size(input.len())?;
+        let mut scratch = std::mem::take(&mut self.scratch_int);
+        let frame = &mut scratch[..input.len()];
+        frame.copy_from_slice(input);
+        let result = self.encode_int_in_place(frame, bits);
+        self.scratch_int = scratch;
+        result
+    }
+
+    /// Encode one frame of interleaved stereo audio into the given bits.
+    ///
+    /// The input must hold exactly one frame for each channel (twice
+    /// `get_frame_size` samples). It is downmixed to mono, with the stereo
+    /// information sent in-band ahead of the frame for `decode_stereo` to
+    /// restore.
+    pub fn encode_stereo(
+        &mut self,
+        input: &[f32],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.check_stereo_frame_size(input.len())?;
+        let bits_before = bits.num_bits();
+        let bytes_before = bits.num_bytes();
+        self.scratch.copy_from_slice(input);
+        let data = self.scratch.as_mut_ptr();
         unsafe {
+            speex_sys::speex_encode_stereo(data, self.frame_size as i32, bits.backing_mut_ptr())
+        };
+        // The stereo information goes in-band ahead of the frame
+        let frame_start = bits.num_bits();
+        let result = unsafe {
+            speex_sys::speex_encode(
+                self.encoder_handle as *mut c_void,
+                data,
+                bits.backing_mut_ptr(),
+            )
+        };
+        self.make_report(result, bits, bits_before, bytes_before, frame_start)
+    }
+
+    /// Encode one frame of interleaved stereo audio into the given bits, using
+    /// an integer representation.
+    ///
+    /// See `encode_stereo` for details.
+    pub fn encode_stereo_int(
+        &mut self,
+        input: &[i16],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.check_stereo_frame_size(input.len())?;
+        let bits_before = bits.num_bits();
+        let bytes_before = bits.num_bytes();
+        self.scratch_int.copy_from_slice(input);
+        let data = self.scratch_int.as_mut_ptr();
+        unsafe {
+            speex_sys::speex_encode_stereo_int(data, self.frame_size as i32, bits.backing_mut_ptr())
+        };
+        let frame_start = bits.num_bits();
+        let result = unsafe {
+            speex_sys::speex_encode_int(
+                self.encoder_handle as *mut c_void,
+                data,
+                bits.backing_mut_ptr(),
+            )
+        };
+        self.make_report(result, bits, bits_before, bytes_before, frame_start)
+    }
+
+    /// Encode one frame of audio into the given bits without copying it first.
+    ///
+    /// The input must be exactly one frame (see `get_frame_size`) long. Speex
+    /// uses the input as working memory, so its contents are unspecified
+    /// afterwards.
+    pub fn encode_in_place(
+        &mut self,
+        input: &mut [f32],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.check_frame_size(input.len())?;
+        let bits_before = bits.num_bits();
+        let bytes_before = bits.num_bytes();
+        let input_ptr = input.as_mut_ptr();
+        let result = unsafe {
             speex_sys::speex_encode(
                 self.encoder_handle as *mut c_void,
                 input_ptr,
                 bits.backing_mut_ptr(),
-            );
-        }
+            )
+        };
+        self.make_report(result, bits, bits_before, bytes_before, bits_before)
     }
 
-    /// Encode one frame of audio into the given bits, using an integer
-    /// representation.
-    pub fn encode_int(&mut self, input: &mut [i16], bits: &mut SpeexBits) {
+    /// Encode one frame of audio into the given bits without copying it first,
+    /// using an integer representation.
+    ///
+    /// The input must be exactly one frame (see `get_frame_size`) long. Speex
+    /// may use the input as working memory, so its contents are unspecified
+    /// afterwards.
+    pub fn encode_int_in_place(
+        &mut self,
+        input: &mut [i16],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        self.check_frame_size(input.len())?;
+        let bits_before = bits.num_bits();
+        let bytes_before = bits.num_bytes();
         let bits_ptr = bits.backing_mut_ptr();
         let input_ptr = input.as_mut_ptr();
-        unsafe {
-            speex_sys::speex_encode_int(self.encoder_handle as *mut c_void, input_ptr, bits_ptr);
-        }
+        let result = unsafe {
+            speex_sys::speex_encode_int(self.encoder_handle as *mut c_void, input_ptr, bits_ptr)
+        };
+        self.make_report(result, bits, bits_before, bytes_before, bits_before)
     }
 }
 
 impl SpeexEncoder<NbMode> {
     /// Create a new narrowband encoder.
     pub fn new() -> SpeexEncoder<NbMode> {
-        let mode = ModeId::NarrowBand.get_mode();
-        let encoder_handle = unsafe { SpeexEncoderHandle::create(mode) };
-        Self {
-            encoder_handle,
-            mode,
-            _phantom: PhantomData,
-        }
+        Self::create(ModeId::NarrowBand)
     }
 
     /// Sets the submode to use for encoding.
-    pub fn set_submode(&mut self, submode: NbSubmodeId) {
-        self.set_low_submode_internal(submode);
+    pub fn set_submode(&mut self, submode: NbSubmodeId) -> Result<(), ControlError> {
+        self.set_low_submode_internal(submode)
     }
 
     /// Gets the submode currently in use for encoding.
-    pub fn get_submode(&mut self) -> NbSubmodeId {
+    pub fn get_submode(&mut self) -> Result<NbSubmodeId, Error> {
         self.get_low_submode_internal()
     }
 }
@@ -174,32 +540,26 @@ impl Default for SpeexEncoder<NbMode> {
 impl SpeexEncoder<WbMode> {
     /// Create a new wideband encoder.
     pub fn new() -> SpeexEncoder<WbMode> {
-        let mode = ModeId::WideBand.get_mode();
-        let encoder_handle = unsafe { SpeexEncoderHandle::create(mode) };
-        Self {
-            encoder_handle,
-            mode,
-            _phantom: PhantomData,
-        }
+        Self::create(ModeId::WideBand)
     }
 
     /// Sets the submode of the narrowband part of the encoder.
-    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) {
-        self.set_low_submode_internal(low_mode);
+    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) -> Result<(), ControlError> {
+        self.set_low_submode_internal(low_mode)
     }
 
     /// Gets the submode of the narrowband part of the encoder.
-    pub fn get_low_submode(&mut self) -> NbSubmodeId {
+    pub fn get_low_submode(&mut self) -> Result<NbSubmodeId, Error> {
         self.get_low_submode_internal()
     }
 
     /// Sets the submode of the wideband part of the encoder.
-    pub fn set_high_submode(&mut self, high_mode: WbSubmodeId) {
-        self.set_high_submode_internal(high_mode);
+    pub fn set_high_submode(&mut self, high_mode: WbSubmodeId) -> Result<(), ControlError> {
+        self.set_high_submode_internal(high_mode)
     }
 
     /// Gets the submode of the wideband part of the encoder.
-    pub fn get_high_submode(&mut self) -> WbSubmodeId {
+    pub fn get_high_submode(&mut self) -> Result<WbSubmodeId, Error> {
         self.get_high_submode_internal()
     }
 }
@@ -213,22 +573,16 @@ impl Default for SpeexEncoder<WbMode> {
 impl SpeexEncoder<UwbMode> {
     /// Create a new ultra-wideband encoder.
     pub fn new() -> SpeexEncoder<UwbMode> {
-        let mode = ModeId::UltraWideBand.get_mode();
-        let encoder_handle = unsafe { SpeexEncoderHandle::create(mode) };
-        Self {
-            encoder_handle,
-            mode,
-            _phantom: PhantomData,
-        }
+        Self::create(ModeId::UltraWideBand)
     }
 
     /// Sets the submode of the narrowband part of the encoder.
-    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) {
-        self.set_low_submode_internal(low_mode);
+    pub fn set_low_submode(&mut self, low_mode: NbSubmodeId) -> Result<(), ControlError> {
+        self.set_low_submode_internal(low_mode)
     }
 
     /// Gets the submode of the narrowband part of the encoder.
-    pub fn get_low_submode(&mut self) -> NbSubmodeId {
+    pub fn get_low_submode(&mut self) -> Result<NbSubmodeId, Error> {
         self.get_low_submode_internal()
     }
 }
@@ -259,32 +613,88 @@ impl DynamicEncoder {
     shared_functions!(DynamicEncoder);
 
     /// Sets the analysis complexity of the encoder.
-    pub fn set_complexity(&mut self, complexity: i32) {
+    pub fn set_complexity(&mut self, complexity: i32) -> Result<(), ControlError> {
         dynamic_mapping!(self, DynamicEncoder, inner => inner.set_complexity(complexity))
     }
 
     /// Gets the analysis complexity of the encoder.
-    pub fn get_complexity(&mut self) -> i32 {
+    pub fn get_complexity(&mut self) -> Result<i32, ControlError> {
         dynamic_mapping!(self, DynamicEncoder, inner => inner.get_complexity())
     }
 
+    /// Sets whether Discontinuous Transmission is enabled or not
+    ///
+    /// DTX only has an effect when VBR or VAD is also enabled.
+    pub fn set_dtx(&mut self, dtx: bool) -> Result<(), ControlError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.set_dtx(dtx))
+    }
+
+    /// Gets whether Discontinuous Transmission is enabled or not
+    pub fn get_dtx(&mut self) -> Result<bool, ControlError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.get_dtx())
+    }
+
+    /// Gets the relative quality of the last encoded frame, as estimated by
+    /// the encoder. This is -1 unless VBR or VAD is enabled.
+    pub fn get_relative_quality(&mut self) -> Result<f32, ControlError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.get_relative_quality())
+    }
+
     /// Encode one frame of audio into the given bits.
-    pub fn encode(&mut self, input: &mut [f32], bits: &mut SpeexBits) {
-        match self {
-            DynamicEncoder::Nb(inner) => inner.encode(input, bits),
-            DynamicEncoder::Wb(inner) => inner.encode(input, bits),
-            DynamicEncoder::Uwb(inner) => inner.encode(input, bits),
-        }
+    pub fn encode(
+        &mut self,
+        input: &[f32],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.encode(input, bits))
     }
 
     /// Encode one frame of audio into the given bits, using an integer
     /// representation.
-    pub fn encode_int(&mut self, input: &mut [i16], bits: &mut SpeexBits) {
-        match self {
-            DynamicEncoder::Nb(inner) => inner.encode_int(input, bits),
-            DynamicEncoder::Wb(inner) => inner.encode_int(input, bits),
-            DynamicEncoder::Uwb(inner) => inner.encode_int(input, bits),
-        }
+    pub fn encode_int(
+        &mut self,
+        input: &[i16],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.encode_int(input, bits))
+    }
+
+    /// Encode one frame of interleaved stereo audio into the given bits.
+    pub fn encode_stereo(
+        &mut self,
+        input: &[f32],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.encode_stereo(input, bits))
+    }
+
+    /// Encode one frame of interleaved stereo audio into the given bits, using
+    /// an integer representation.
+    pub fn encode_stereo_int(
+        &mut self,
+        input: &[i16],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.encode_stereo_int(input, bits))
+    }
+
+    /// Encode one frame of audio into the given bits without copying it first.
+    pub fn encode_in_place(
+        &mut self,
+        input: &mut [f32],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.encode_in_place(input, bits))
+    }
+
+    /// Encode one frame of audio into the given bits without copying it first,
+    /// using an integer representation.
+    pub fn encode_int_in_place(
+        &mut self,
+        input: &mut [i16],
+        bits: &mut SpeexBits,
+    ) -> Result<EncodeReport, EncoderError> {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.encode_int_in_place(input, bits))
     }
 
     pub fn new(mode: ModeId) -> DynamicEncoder {
@@ -295,6 +705,26 @@ impl DynamicEncoder {
         }
     }
 
+    /// Creates an encoder for the stream described by the header, with its
+    /// sampling rate and VBR setting applied.
+    ///
+    /// The frames per packet aren't applied, as the encoder only ever
+    /// encodes one frame at a time. `StreamEncoder::from_header` packs that
+    /// many frames into each packet.
+    pub fn from_header(header: &SpeexHeader) -> Result<DynamicEncoder, Error> {
+        let mut encoder = DynamicEncoder::new(header.mode_id()?);
+        encoder.set_sampling_rate(header.rate() as i32)?;
+        if header.vbr() {
+            encoder.set_vbr(true)?;
+        }
+        Ok(encoder)
+    }
+
+    /// Gets the mode of the encoder
+    pub fn mode_id(&self) -> ModeId {
+        dynamic_mapping!(self, DynamicEncoder, inner => inner.mode_id())
+    }
+
     pub fn into_nb(self) -> Option<SpeexEncoder<NbMode>> {
         match self {
             DynamicEncoder::Nb(nb) => Some(nb),
@@ -317,6 +747,23 @@ impl DynamicEncoder {
     }
 }
 
+impl From<SpeexEncoder<NbMode>> for DynamicEncoder {
+    fn from(value: SpeexEncoder<NbMode>) -> Self {
+        DynamicEncoder::Nb(value)
+    }
+}
+
+impl From<SpeexEncoder<WbMode>> for DynamicEncoder {
+    fn from(value: SpeexEncoder<WbMode>) -> Self {
+        DynamicEncoder::Wb(value)
+    }
+}
+
+impl From<SpeexEncoder<UwbMode>> for DynamicEncoder {
+    fn from(value: SpeexEncoder<UwbMode>) -> Self {
+        DynamicEncoder::Uwb(value)
+    }
+}
 
 #[cfg(test)]
 mod test {
@@ -327,21 +774,14 @@ mod test {
             #[test]
             fn $name() {
                 let mut encoder = SpeexEncoder::<WbMode>::new();
-                encoder.$set($value);
-                let result = encoder.$get();
+                encoder.$set($value).unwrap();
+                let result = encoder.$get().unwrap();
 
                 assert_eq!(result, $value);
             }
         };
     }
 
-    set_get_test!(
-        set_get_high_submode,
-        set_high_submode,
-        get_high_submode,
-        WbSubmodeId::NoQuantize
-    );
-
     set_get_test!(set_get_vbr, set_vbr, get_vbr, true);
 
     set_get_test!(set_get_vbr_quality, set_vbr_quality, get_vbr_quality, 8.0);
@@ -353,7 +793,7 @@ mod test {
     #[test]
     fn set_quality() {
         let mut encoder = SpeexEncoder::<WbMode>::new();
-        encoder.set_quality(10);
+        encoder.set_quality(10).unwrap();
     }
 
     set_get_test!(set_get_bitrate, set_bitrate, get_bitrate, 3950);
@@ -368,16 +808,201 @@ mod test {
     #[test]
     fn get_frame_size() {
         let mut encoder = SpeexEncoder::<WbMode>::new();
-        encoder.get_frame_size();
+        encoder.get_frame_size().unwrap();
+    }
+
+    #[test]
+    fn set_high_submode_but_cannot_get() {
+        // The wideband encoder accepts SPEEX_SET_HIGH_MODE but has no handler for
+        // SPEEX_GET_HIGH_MODE
+        let mut encoder = SpeexEncoder::<WbMode>::new();
+        encoder.set_high_submode(WbSubmodeId::NoQuantize).unwrap();
+        let result = encoder.get_high_submode();
+
+        assert_eq!(
+            result,
+            Err(Error::Control(ControlError::UnknownRequest(
+                speex_sys::SPEEX_GET_HIGH_MODE
+            )))
+        );
+    }
+
+    #[test]
+    fn dynamic_encoder_forwards_errors() {
+        let mut encoder = DynamicEncoder::new(ModeId::WideBand);
+        encoder.set_vbr(true).unwrap();
+        assert_eq!(encoder.get_vbr(), Ok(true));
+        encoder.set_complexity(4).unwrap();
+        assert_eq!(encoder.get_complexity(), Ok(4));
+    }
+
+    set_get_test!(set_get_dtx, set_dtx, get_dtx, true);
+
+    #[test]
+    fn dtx_skips_silent_frames() {
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        encoder.set_vad(true).unwrap();
+        encoder.set_dtx(true).unwrap();
+        let frame_size = encoder.get_frame_size().unwrap() as usize;
+
+        let statuses: Vec<DtxStatus> = (0..10)
+            .map(|_| {
+                let mut bits = SpeexBits::new();
+                let input = vec![0i16; frame_size];
+                encoder.encode_int(&input, &mut bits).unwrap().status
+            })
+            .collect();
+
+        assert_eq!(statuses[0], DtxStatus::Transmit);
+        assert!(statuses.contains(&DtxStatus::Skip));
+    }
+
+    #[test]
+    fn no_skipped_frames_without_dtx() {
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        encoder.set_vad(true).unwrap();
+        let frame_size = encoder.get_frame_size().unwrap() as usize;
+
+        for _ in 0..10 {
+            let mut bits = SpeexBits::new();
+            let input = vec![0.0f32; frame_size];
+            let report = encoder.encode(&input, &mut bits).unwrap();
+            assert!(report.needs_transmission());
+        }
     }
 
     #[test]
     fn encodes_frame_without_segfault() {
         let mut encoder = SpeexEncoder::<NbMode>::new();
         let mut bits = SpeexBits::new();
-        let frame_size = encoder.get_frame_size();
+        let frame_size = encoder.get_frame_size().unwrap();
         let mut input = vec![23i16; frame_size as usize];
 
-        encoder.encode_int(&mut input, &mut bits);
+        encoder.encode_int_in_place(&mut input, &mut bits).unwrap();
+    }
+
+    #[test]
+    fn reports_appended_bits() {
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        encoder.set_quality(8).unwrap();
+        let mut bits = SpeexBits::new();
+        let frame_size = encoder.get_frame_size().unwrap() as usize;
+
+        let input = vec![100i16; frame_size];
+        let first = encoder.encode_int(&input, &mut bits).unwrap();
+        let second = encoder.encode_int(&input, &mut bits).unwrap();
+
+        // 15 kbps at 50 frames per second
+        assert_eq!(first.bits, 300);
+        assert_eq!(first.bytes, 38);
+        assert_eq!(first.submode, Some(NbSubmodeId::High));
+        assert_eq!(first.high_submode, None);
+        assert_eq!(first.bitrate, 15000);
+        assert_eq!(first.relative_quality, -1.0);
+        assert_eq!(second.bits, 300);
+        assert_eq!(second.bytes, 37);
+        assert_eq!(bits.num_bits(), 600);
+    }
+
+    #[test]
+    fn reports_vbr_submode() {
+        let mut encoder = SpeexEncoder::<WbMode>::new();
+        encoder.set_vbr(true).unwrap();
+        let mut bits = SpeexBits::new();
+        let frame_size = encoder.get_frame_size().unwrap() as usize;
+
+        let input = vec![0i16; frame_size];
+        let report = encoder.encode_int(&input, &mut bits).unwrap();
+
+        assert!(report.relative_quality >= 0.0);
+        assert_eq!(report.bits, bits.num_bits());
+        assert!(report.submode.is_some());
+    }
+
+    #[test]
+    fn reports_high_submode() {
+        let input: Vec<i16> = (0..640)
+            .map(|i| ((i as f32 * 0.3).sin() * 3000.0) as i16)
+            .collect();
+        for submode in [
+            WbSubmodeId::NoQuantize,
+            WbSubmodeId::QuantizedLow,
+            WbSubmodeId::QuantizedMedium,
+            WbSubmodeId::QuantizedHigh,
+        ] {
+            let mut encoder = SpeexEncoder::<WbMode>::new();
+            encoder.set_high_submode(submode).unwrap();
+            let mut bits = SpeexBits::new();
+            let mono = encoder.encode_int(&input[..320], &mut bits).unwrap();
+            let stereo = encoder.encode_stereo_int(&input, &mut bits).unwrap();
+
+            assert_eq!(mono.high_submode, Some(submode));
+            assert_eq!(stereo.high_submode, Some(submode));
+        }
+
+        let mut encoder = SpeexEncoder::<UwbMode>::new();
+        let mut bits = SpeexBits::new();
+        let report = encoder.encode_int(&input, &mut bits).unwrap();
+        assert_eq!(report.high_submode, Some(WbSubmodeId::QuantizedMedium));
+
+        // Silence under DTX leaves out the high band
+        let mut encoder = SpeexEncoder::<WbMode>::new();
+        encoder.set_vbr(true).unwrap();
+        encoder.set_dtx(true).unwrap();
+        let skipped = (0..20)
+            .map(|_| encoder.encode_int(&[0; 320], &mut bits).unwrap())
+            .find(|report| !report.needs_transmission())
+            .unwrap();
+        assert_eq!((skipped.submode, skipped.high_submode), (None, None));
+    }
+
+    #[test]
+    fn rejects_wrong_frame_size() {
+        let mut encoder = SpeexEncoder::<WbMode>::new();
+        let mut bits = SpeexBits::new();
+
+        let short = vec![0.0f32; 319];
+        assert_eq!(
+            encoder.encode(&short, &mut bits),
+            Err(EncoderError::WrongFrameSize {
+                expected: 320,
+                actual: 319
+            })
+        );
+        let mut long = vec![0i16; 321];
+        assert_eq!(
+            encoder.encode_int_in_place(&mut long, &mut bits),
+            Err(EncoderError::WrongFrameSize {
+                expected: 320,
+                actual: 321
+            })
+        );
+        assert_eq!(bits.num_bits(), 0);
+    }
+
+    #[test]
+    fn encode_leaves_input_untouched() {
+        let mut encoder = DynamicEncoder::new(ModeId::NarrowBand);
+        let mut bits = SpeexBits::new();
+        let input: Vec<f32> = (0..160).map(|i| (i as f32 * 0.1).sin() * 8000.0).collect();
+        let copy = input.clone();
+
+        encoder.encode(&input, &mut bits).unwrap();
+
+        assert_eq!(input, copy);
+    }
+
+    #[test]
+    fn creates_from_header() {
+        let header = crate::SpeexHeaderBuilder::new(12000, 1, ModeId::WideBand)
+            .vbr(true)
+            .build()
+            .unwrap();
+
+        let mut encoder = DynamicEncoder::from_header(&header).unwrap();
+
+        assert_eq!(encoder.mode_id(), ModeId::WideBand);
+        assert_eq!(encoder.get_sampling_rate(), Ok(12000));
+        assert_eq!(encoder.get_vbr(), Ok(true));
     }
 }
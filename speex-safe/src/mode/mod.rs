@@ -12,10 +12,18 @@ use std::error::Error;
 use std::ffi::c_void;
 use std::fmt::Display;
 
-pub use decoder::{DynamicDecoder, SpeexDecoder};
-pub use encoder::{DynamicEncoder, SpeexEncoder};
+pub use decoder::{DecoderError, DynamicDecoder, SpeexDecoder};
+pub use encoder::{DtxStatus, DynamicEncoder, EncodeReport, EncoderError, SpeexEncoder};
 use speex_sys::{SpeexMode, SPEEX_MODEID_NB, SPEEX_MODEID_UWB, SPEEX_MODEID_WB};
 
+use crate::ConversionError;
+
+/// Request for whether the decoder is currently in DTX mode.
+///
+/// This is internal to libspeex (defined in `libspeex/modes.h`) so it isn't
+/// part of the generated bindings.
+pub(crate) const SPEEX_GET_DTX_STATUS: i32 = 103;
+
 /// Possible modes for the encoder and decoder.
 #[repr(i32)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -25,13 +33,15 @@ pub enum ModeId {
     UltraWideBand = SPEEX_MODEID_UWB,
 }
 
-impl From<i32> for ModeId {
-    fn from(value: i32) -> Self {
+impl TryFrom<i32> for ModeId {
+    type Error = ConversionError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
-            SPEEX_MODEID_NB => ModeId::NarrowBand,
-            SPEEX_MODEID_WB => ModeId::WideBand,
-            SPEEX_MODEID_UWB => ModeId::UltraWideBand,
-            _ => panic!("Invalid mode id"),
+            SPEEX_MODEID_NB => Ok(ModeId::NarrowBand),
+            SPEEX_MODEID_WB => Ok(ModeId::WideBand),
+            SPEEX_MODEID_UWB => Ok(ModeId::UltraWideBand),
+            _ => Err(ConversionError::InvalidMode(value)),
         }
     }
 }
@@ -61,18 +71,20 @@ pub enum NbSubmodeId {
     ExtremeHigh = 7,
 }
 
-impl From<i32> for NbSubmodeId {
-    fn from(value: i32) -> Self {
+impl TryFrom<i32> for NbSubmodeId {
+    type Error = ConversionError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
-            1 => NbSubmodeId::VocoderLike,
-            2 => NbSubmodeId::VeryLow,
-            3 => NbSubmodeId::Low,
-            4 => NbSubmodeId::Medium,
-            5 => NbSubmodeId::High,
-            6 => NbSubmodeId::VeryHigh,
-            7 => NbSubmodeId::ExtremeHigh,
-            8 => NbSubmodeId::ExtremeLow,
-            _ => panic!("Invalid submode id"),
+            1 => Ok(NbSubmodeId::VocoderLike),
+            2 => Ok(NbSubmodeId::VeryLow),
+            3 => Ok(NbSubmodeId::Low),
+            4 => Ok(NbSubmodeId::Medium),
+            5 => Ok(NbSubmodeId::High),
+            6 => Ok(NbSubmodeId::VeryHigh),
+            7 => Ok(NbSubmodeId::ExtremeHigh),
+            8 => Ok(NbSubmodeId::ExtremeLow),
+            _ => Err(ConversionError::InvalidSubmode(value)),
         }
     }
 }
@@ -91,14 +103,16 @@ pub enum WbSubmodeId {
     QuantizedHigh = 4,
 }
 
-impl From<i32> for WbSubmodeId {
-    fn from(value: i32) -> Self {
+impl TryFrom<i32> for WbSubmodeId {
+    type Error = ConversionError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
-            1 => WbSubmodeId::NoQuantize,
-            2 => WbSubmodeId::QuantizedLow,
-            3 => WbSubmodeId::QuantizedMedium,
-            4 => WbSubmodeId::QuantizedHigh,
-            _ => panic!("Invalid submode id"),
+            1 => Ok(WbSubmodeId::NoQuantize),
+            2 => Ok(WbSubmodeId::QuantizedLow),
+            3 => Ok(WbSubmodeId::QuantizedMedium),
+            4 => Ok(WbSubmodeId::QuantizedHigh),
+            _ => Err(ConversionError::InvalidSubmode(value)),
         }
     }
 }
@@ -113,11 +127,13 @@ pub enum UwbSubmodeId {
     Only = WbSubmodeId::NoQuantize as i32,
 }
 
-impl From<i32> for UwbSubmodeId {
-    fn from(value: i32) -> Self {
+impl TryFrom<i32> for UwbSubmodeId {
+    type Error = ConversionError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
-            1 => UwbSubmodeId::Only,
-            _ => panic!("Invalid submode id"),
+            1 => Ok(UwbSubmodeId::Only),
+            _ => Err(ConversionError::InvalidSubmode(value)),
         }
     }
 }
@@ -157,6 +173,9 @@ pub enum ControlError {
     /// The parameter passed to the control function was invalid (and probably
     /// caused a segfault, making this error unreachable)
     InvalidParameter,
+    /// The control function returned an error code that is not documented
+    /// The parameter is the code that was returned
+    UnexpectedReturn(i32),
 }
 
 impl Display for ControlError {
@@ -169,6 +188,9 @@ impl Display for ControlError {
                 )
             }
             ControlError::InvalidParameter => write!(f, "Invalid parameter"),
+            ControlError::UnexpectedReturn(code) => {
+                write!(f, "Unexpected return code from a control function ({code})")
+            }
         }
     }
 }
@@ -187,16 +209,20 @@ mod private {
 /// `ctl` is the only function that needs to be implemented, and is used to call
 /// the control functions of the underlying speex library.
 ///
+/// Not every request is supported by every encoder/decoder (decoders have no
+/// notion of VBR, for example), so every function returns a `ControlError`
+/// when the underlying library rejects the request rather than panicking.
+///
 /// This trait is sealed, and cannot be implemented outside of this crate.
 pub trait ControlFunctions: private::Sealed {
     /// Internal function used to convert the error codes returned by the
     /// control function into a result type
-    fn check_error(err_code: i32, param: Option<i32>) -> Result<(), ControlError> {
+    fn check_error(err_code: i32, request: i32) -> Result<(), ControlError> {
         match err_code {
             0 => Ok(()),
-            -1 => Err(ControlError::UnknownRequest(param.unwrap())),
+            -1 => Err(ControlError::UnknownRequest(request)),
             -2 => Err(ControlError::InvalidParameter),
-            _ => panic!("Unknown error code passed to make_error(), this is a bug"),
+            _ => Err(ControlError::UnexpectedReturn(err_code)),
         }
     }
 
@@ -210,234 +236,207 @@ pub trait ControlFunctions: private::Sealed {
     unsafe fn ctl(&mut self, request: i32, ptr: *mut c_void) -> Result<(), ControlError>;
 
     /// Gets the frame size (in samples) of the encoder/decoder
-    fn get_frame_size(&mut self) -> i32 {
+    fn get_frame_size(&mut self) -> Result<i32, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_FRAME_SIZE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_FRAME_SIZE, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// Sets whether Variable BitRate is enabled or not
-    fn set_vbr(&mut self, vbr: bool) {
+    fn set_vbr(&mut self, vbr: bool) -> Result<(), ControlError> {
         let state = if vbr { 1 } else { 0 };
         let ptr = &state as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_VBR, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_VBR, ptr) }
     }
 
     /// Gets whether Variable BitRate is enabled or not
-    fn get_vbr(&mut self) -> bool {
+    fn get_vbr(&mut self) -> Result<bool, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_VBR, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_VBR, ptr)?;
         }
-        state != 0
+        Ok(state != 0)
     }
 
     /// Sets the VBR quality of the encoder/decoder
     ///
     /// The value should be between 0 and 10, with 10 being the highest quality.
-    fn set_vbr_quality(&mut self, quality: f32) {
+    fn set_vbr_quality(&mut self, quality: f32) -> Result<(), ControlError> {
         let ptr = &quality as *const f32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_VBR_QUALITY, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_VBR_QUALITY, ptr) }
     }
 
     /// Gets the VBR quality of the encoder/decoder
-    fn get_vbr_quality(&mut self) -> f32 {
+    fn get_vbr_quality(&mut self) -> Result<f32, ControlError> {
         let mut state = 0.0;
         let ptr = &mut state as *mut f32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_VBR_QUALITY, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_VBR_QUALITY, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// Sets whether Voice Activity Detection is enabled or not
-    fn set_vad(&mut self, vad: bool) {
+    fn set_vad(&mut self, vad: bool) -> Result<(), ControlError> {
         let state = if vad { 1 } else { 0 };
         let ptr = &state as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_VAD, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_VAD, ptr) }
     }
 
     /// Gets whether Voice Activity Detection is enabled or not
-    fn get_vad(&mut self) -> bool {
+    fn get_vad(&mut self) -> Result<bool, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_VAD, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_VAD, ptr)?;
         }
-        state != 0
+        Ok(state != 0)
     }
 
     /// Sets the Average BitRate of the encoder/decoder
-    fn set_abr(&mut self, abr: i32) {
+    fn set_abr(&mut self, abr: i32) -> Result<(), ControlError> {
         let ptr = &abr as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_ABR, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_ABR, ptr) }
     }
 
     /// Gets the Average BitRate of the encoder/decoder
-    fn get_abr(&mut self) -> i32 {
+    fn get_abr(&mut self) -> Result<i32, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_ABR, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_ABR, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// Sets the overall quality of the encoder/decoder
     /// The value should be between 0 and 10, with 10 being the highest quality.
     /// Default is 8.
-    fn set_quality(&mut self, quality: i32) {
+    fn set_quality(&mut self, quality: i32) -> Result<(), ControlError> {
         let ptr = &quality as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_QUALITY, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_QUALITY, ptr) }
     }
 
     /// Sets the current bitrate of the encoder/decoder
-    fn set_bitrate(&mut self, bitrate: i32) {
+    fn set_bitrate(&mut self, bitrate: i32) -> Result<(), ControlError> {
         let ptr = &bitrate as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_BITRATE, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_BITRATE, ptr) }
     }
 
     /// Gets the current bitrate of the encoder/decoder
-    fn get_bitrate(&mut self) -> i32 {
+    fn get_bitrate(&mut self) -> Result<i32, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_BITRATE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_BITRATE, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// Sets the sampling rate used for bitrate computation
-    fn set_sampling_rate(&mut self, samplingrate: i32) {
+    fn set_sampling_rate(&mut self, samplingrate: i32) -> Result<(), ControlError> {
         let ptr = &samplingrate as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_SAMPLING_RATE, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_SAMPLING_RATE, ptr) }
     }
 
     /// Gets the sampling rate used for bitrate computation
-    fn get_sampling_rate(&mut self) -> i32 {
+    fn get_sampling_rate(&mut self) -> Result<i32, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_SAMPLING_RATE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_SAMPLING_RATE, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// resets the encoder/decoder memories to zero
-    fn reset_state(&mut self) {
-        unsafe {
-            self.ctl(speex_sys::SPEEX_RESET_STATE, std::ptr::null_mut())
-                .unwrap();
-        }
+    fn reset_state(&mut self) -> Result<(), ControlError> {
+        unsafe { self.ctl(speex_sys::SPEEX_RESET_STATE, std::ptr::null_mut()) }
     }
 
     /// Sets whether submode encoding is done in each frame
     ///
     /// Note that false breaks the specification for the format
-    fn set_submode_encoding(&mut self, submode: bool) {
+    fn set_submode_encoding(&mut self, submode: bool) -> Result<(), ControlError> {
         let state = if submode { 1 } else { 0 };
         let ptr = &state as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_SUBMODE_ENCODING, ptr)
-                .unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_SUBMODE_ENCODING, ptr) }
     }
 
     /// Gets whether submode encoding is enabled or not
-    fn get_submode_encoding(&mut self) -> bool {
+    fn get_submode_encoding(&mut self) -> Result<bool, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_SUBMODE_ENCODING, ptr)
-                .unwrap();
+            self.ctl(speex_sys::SPEEX_GET_SUBMODE_ENCODING, ptr)?;
         }
-        state != 0
+        Ok(state != 0)
     }
 
     /// Gets the lookahead value currently in use by the encoder/decoder
     ///
     /// Sum the lookahead of a Speex decoder and the lookahead of a Speex
     /// encoder to get the total lookahead.
-    fn get_lookahead(&mut self) -> i32 {
+    fn get_lookahead(&mut self) -> Result<i32, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_LOOKAHEAD, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_LOOKAHEAD, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// Sets tuning for Packet-Loss Concealment (expected loss rate)
-    fn set_plc_tuning(&mut self, tuning: i32) {
+    fn set_plc_tuning(&mut self, tuning: i32) -> Result<(), ControlError> {
         let ptr = &tuning as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_PLC_TUNING, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_PLC_TUNING, ptr) }
     }
 
     /// Gets current Packet-Loss Concealment tuning value
-    fn get_plc_tuning(&mut self) -> i32 {
+    fn get_plc_tuning(&mut self) -> Result<i32, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_PLC_TUNING, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_PLC_TUNING, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// Sets the max bit-rate allowed in VBR mode
-    fn set_vbr_max_bitrate(&mut self, max_bitrate: i32) {
+    fn set_vbr_max_bitrate(&mut self, max_bitrate: i32) -> Result<(), ControlError> {
         let ptr = &max_bitrate as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_VBR_MAX_BITRATE, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_VBR_MAX_BITRATE, ptr) }
     }
 
     /// Gets the max bit-rate allowed in VBR mode
-    fn get_vbr_max_bitrate(&mut self) -> i32 {
+    fn get_vbr_max_bitrate(&mut self) -> Result<i32, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_VBR_MAX_BITRATE, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_VBR_MAX_BITRATE, ptr)?;
         }
-        state
+        Ok(state)
     }
 
     /// Enables or disables highpass filtering of the input/output
-    fn set_highpass(&mut self, highpass: bool) {
+    fn set_highpass(&mut self, highpass: bool) -> Result<(), ControlError> {
         let state = if highpass { 1 } else { 0 };
         let ptr = &state as *const i32 as *mut c_void;
-        unsafe {
-            self.ctl(speex_sys::SPEEX_SET_HIGHPASS, ptr).unwrap();
-        }
+        unsafe { self.ctl(speex_sys::SPEEX_SET_HIGHPASS, ptr) }
     }
 
     /// Gets whether highpass filtering of the input/output is enabled
-    fn get_highpass(&mut self) -> bool {
+    fn get_highpass(&mut self) -> Result<bool, ControlError> {
         let mut state = 0;
         let ptr = &mut state as *mut i32 as *mut c_void;
         unsafe {
-            self.ctl(speex_sys::SPEEX_GET_HIGHPASS, ptr).unwrap();
+            self.ctl(speex_sys::SPEEX_GET_HIGHPASS, ptr)?;
         }
-        state != 0
+        Ok(state != 0)
     }
 }
 
@@ -456,93 +455,93 @@ macro_rules! dynamic_mapping {
 macro_rules! shared_functions {
     ($enum_name:ident) => {
         /// Gets the frame size (in samples) of the encoder/decoder
-        pub fn get_frame_size(&mut self) -> i32 {
+        pub fn get_frame_size(&mut self) -> Result<i32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_frame_size())
         }
 
         /// Sets whether Variable BitRate is enabled or not
-        pub fn set_vbr(&mut self, vbr: bool) {
+        pub fn set_vbr(&mut self, vbr: bool) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_vbr(vbr))
         }
 
         /// Gets whether Variable BitRate is enabled or not
-        pub fn get_vbr(&mut self) -> bool {
+        pub fn get_vbr(&mut self) -> Result<bool, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_vbr())
         }
 
         /// Sets the VBR quality of the encoder/decoder
         ///
         /// The value should be between 0 and 10, with 10 being the highest quality.
-        pub fn set_vbr_quality(&mut self, quality: f32) {
+        pub fn set_vbr_quality(&mut self, quality: f32) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_vbr_quality(quality))
         }
 
         /// Gets the VBR quality of the encoder/decoder
-        pub fn get_vbr_quality(&mut self) -> f32 {
+        pub fn get_vbr_quality(&mut self) -> Result<f32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_vbr_quality())
         }
 
         /// Sets whether Voice Activity Detection is enabled or not
-        pub fn set_vad(&mut self, vad: bool) {
+        pub fn set_vad(&mut self, vad: bool) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_vad(vad))
         }
 
         /// Gets whether Voice Activity Detection is enabled or not
-        pub fn get_vad(&mut self) -> bool {
+        pub fn get_vad(&mut self) -> Result<bool, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_vad())
         }
 
         /// Sets the Average BitRate of the encoder/decoder
-        pub fn set_abr(&mut self, abr: i32) {
+        pub fn set_abr(&mut self, abr: i32) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_abr(abr))
         }
 
         /// Gets the Average BitRate of the encoder/decoder
-        pub fn get_abr(&mut self) -> i32 {
+        pub fn get_abr(&mut self) -> Result<i32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_abr())
         }
 
         /// Sets the overall quality of the encoder/decoder
         /// The value should be between 0 and 10, with 10 being the highest quality.
         /// Default is 8.
-        pub fn set_quality(&mut self, quality: i32) {
+        pub fn set_quality(&mut self, quality: i32) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_quality(quality))
         }
 
         /// Sets the current bitrate of the encoder/decoder
-        pub fn set_bitrate(&mut self, bitrate: i32) {
+        pub fn set_bitrate(&mut self, bitrate: i32) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_bitrate(bitrate))
         }
 
         /// Gets the current bitrate of the encoder/decoder
-        pub fn get_bitrate(&mut self) -> i32 {
+        pub fn get_bitrate(&mut self) -> Result<i32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_bitrate())
         }
 
         /// Sets the sampling rate used for bitrate computation
-        pub fn set_sampling_rate(&mut self, samplingrate: i32) {
+        pub fn set_sampling_rate(&mut self, samplingrate: i32) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_sampling_rate(samplingrate))
         }
 
         /// Gets the sampling rate used for bitrate computation
-        pub fn get_sampling_rate(&mut self) -> i32 {
+        pub fn get_sampling_rate(&mut self) -> Result<i32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_sampling_rate())
         }
 
         /// resets the encoder/decoder memories to zero
-        pub fn reset_state(&mut self) {
+        pub fn reset_state(&mut self) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.reset_state())
         }
 
         /// Sets whether submode encoding is done in each frame
         ///
         /// Note that false breaks the specification for the format
-        pub fn set_submode_encoding(&mut self, submode: bool) {
+        pub fn set_submode_encoding(&mut self, submode: bool) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_submode_encoding(submode))
         }
 
         /// Gets whether submode encoding is enabled or not
-        pub fn get_submode_encoding(&mut self) -> bool {
+        pub fn get_submode_encoding(&mut self) -> Result<bool, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_submode_encoding())
         }
 
@@ -550,37 +549,37 @@ macro_rules! shared_functions {
         ///
         /// Sum the lookahead of a Speex decoder and the lookahead of a Speex
         /// encoder to get the total lookahead.
-        pub fn get_lookahead(&mut self) -> i32 {
+        pub fn get_lookahead(&mut self) -> Result<i32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_lookahead())
         }
 
         /// Sets tuning for Packet-Loss Concealment (expected loss rate)
-        pub fn set_plc_tuning(&mut self, tuning: i32) {
+        pub fn set_plc_tuning(&mut self, tuning: i32) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_plc_tuning(tuning))
         }
 
         /// Gets current Packet-Loss Concealment tuning value
-        pub fn get_plc_tuning(&mut self) -> i32 {
+        pub fn get_plc_tuning(&mut self) -> Result<i32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_plc_tuning())
         }
 
         /// Sets the max bit-rate allowed in VBR mode
-        pub fn set_vbr_max_bitrate(&mut self, max_bitrate: i32) {
+        pub fn set_vbr_max_bitrate(&mut self, max_bitrate: i32) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_vbr_max_bitrate(max_bitrate))
         }
 
         /// Gets the max bit-rate allowed in VBR mode
-        pub fn get_vbr_max_bitrate(&mut self) -> i32 {
+        pub fn get_vbr_max_bitrate(&mut self) -> Result<i32, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_vbr_max_bitrate())
         }
 
         /// Enables or disables highpass filtering of the input/output
-        pub fn set_highpass(&mut self, highpass: bool) {
+        pub fn set_highpass(&mut self, highpass: bool) -> Result<(), ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.set_highpass(highpass))
         }
 
         /// Gets whether highpass filtering of the input/output is enabled
-        pub fn get_highpass(&mut self) -> bool {
+        pub fn get_highpass(&mut self) -> Result<bool, ControlError> {
             dynamic_mapping!(self, $enum_name, inner => inner.get_highpass())
         }
     };
@@ -604,3 +603,53 @@ impl CoderMode for WbMode {}
 /// This is a marker type used to specify the mode of the de/encoder.
 pub enum UwbMode {}
 impl CoderMode for UwbMode {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Error, SpeexEncoder};
+
+    #[test]
+    fn mode_id_try_from() {
+        assert_eq!(ModeId::try_from(1), Ok(ModeId::WideBand));
+        assert_eq!(ModeId::try_from(3), Err(ConversionError::InvalidMode(3)));
+        assert_eq!(ModeId::try_from(-1), Err(ConversionError::InvalidMode(-1)));
+    }
+
+    #[test]
+    fn submode_id_try_from() {
+        assert_eq!(NbSubmodeId::try_from(8), Ok(NbSubmodeId::ExtremeLow));
+        assert_eq!(
+            NbSubmodeId::try_from(0),
+            Err(ConversionError::InvalidSubmode(0))
+        );
+        assert_eq!(WbSubmodeId::try_from(4), Ok(WbSubmodeId::QuantizedHigh));
+        assert_eq!(
+            WbSubmodeId::try_from(5),
+            Err(ConversionError::InvalidSubmode(5))
+        );
+        assert_eq!(UwbSubmodeId::try_from(1), Ok(UwbSubmodeId::Only));
+        assert_eq!(
+            UwbSubmodeId::try_from(2),
+            Err(ConversionError::InvalidSubmode(2))
+        );
+    }
+
+    #[test]
+    fn check_error_does_not_panic_on_unknown_codes() {
+        let result = SpeexEncoder::<NbMode>::check_error(-3, speex_sys::SPEEX_GET_VBR);
+        assert_eq!(result, Err(ControlError::UnexpectedReturn(-3)));
+    }
+
+    #[test]
+    fn errors_convert_into_crate_error() {
+        fn fails() -> Result<ModeId, Error> {
+            Ok(ModeId::try_from(7)?)
+        }
+
+        assert_eq!(
+            fails(),
+            Err(Error::Conversion(ConversionError::InvalidMode(7)))
+        );
+    }
+}
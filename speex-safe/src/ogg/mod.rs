@@ -0,0 +1,118 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+//! Reading and writing speex streams in Ogg files (`.spx`).
+//!
+//! Only the parts of the Ogg container speex needs are implemented: a single
+//! logical stream is read out of a file, and any other multiplexed streams
+//! are ignored.
+
+pub(crate) mod page;
+pub(crate) mod reader;
+pub(crate) mod writer;
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+pub use reader::OggSpeexReader;
+pub use writer::OggSpeexWriter;
+
+use crate::{CommentsError, ControlError, DecoderError, EncoderError, Error, HeaderError};
+
+/// Error type for reading and writing Ogg speex files
+#[derive(Debug)]
+pub enum OggError {
+    /// The underlying reader or writer failed
+    Io(io::Error),
+    /// Data that should be an Ogg page doesn't start with `OggS`
+    BadCapturePattern,
+    /// The page is in an Ogg version this crate doesn't understand
+    /// The parameter is the version that was found
+    UnsupportedVersion(u8),
+    /// The page checksum doesn't match its contents
+    BadChecksum,
+    /// The file ended partway through a page
+    TruncatedPage,
+    /// The stream ended before the speex header and comment packets
+    MissingHeaders,
+    /// A packet continues across pages for longer than any packet should
+    PacketTooLarge,
+    /// The speex data in the stream was invalid
+    Speex(Error),
+}
+
+impl Display for OggError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OggError::Io(err) => write!(f, "{err}"),
+            OggError::BadCapturePattern => write!(f, "Data is not an Ogg page"),
+            OggError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported Ogg page version ({version})")
+            }
+            OggError::BadChecksum => write!(f, "Ogg page checksum doesn't match"),
+            OggError::TruncatedPage => write!(f, "Ogg page is truncated"),
+            OggError::MissingHeaders => write!(f, "Stream ended before the speex headers"),
+            OggError::PacketTooLarge => write!(f, "Ogg packet is too large"),
+            OggError::Speex(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for OggError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OggError::Io(err) => Some(err),
+            OggError::Speex(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OggError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::UnexpectedEof => OggError::TruncatedPage,
+            _ => OggError::Io(value),
+        }
+    }
+}
+
+impl From<Error> for OggError {
+    fn from(value: Error) -> Self {
+        OggError::Speex(value)
+    }
+}
+
+impl From<ControlError> for OggError {
+    fn from(value: ControlError) -> Self {
+        OggError::Speex(value.into())
+    }
+}
+
+impl From<EncoderError> for OggError {
+    fn from(value: EncoderError) -> Self {
+        OggError::Speex(value.into())
+    }
+}
+
+impl From<DecoderError> for OggError {
+    fn from(value: DecoderError) -> Self {
+        OggError::Speex(value.into())
+    }
+}
+
+impl From<HeaderError> for OggError {
+    fn from(value: HeaderError) -> Self {
+        OggError::Speex(value.into())
+    }
+}
+
+impl From<CommentsError> for OggError {
+    fn from(value: CommentsError) -> Self {
+        OggError::Speex(value.into())
+    }
+}
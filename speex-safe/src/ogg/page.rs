@@ -0,0 +1,652 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::ogg::OggError;
+
+pub(crate) const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+/// Size of a page header up to the segment table
+pub(crate) const HEADER_SIZE: usize = 27;
+/// The page continues a packet started on the previous page
+pub(crate) const FLAG_CONTINUED: u8 = 0x01;
+/// The page is the first of its logical stream
+pub(crate) const FLAG_BEGIN_OF_STREAM: u8 = 0x02;
+/// The page is the last of its logical stream
+pub(crate) const FLAG_END_OF_STREAM: u8 = 0x04;
+
+const CRC_OFFSET: usize = 22;
+
+/// Largest packet that is reassembled from continued pages. Far more than any
+/// speex packet, with room for a comment header holding cover art.
+pub(crate) const MAX_PACKET_SIZE: usize = 1 << 24;
+
+/// CRC lookup table for the Ogg checksum (polynomial 0x04c11db7, no
+/// reflection, zero initial value and no final xor).
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = (index as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+/// A single Ogg page.
+///
+/// `lacing` is the segment table: each packet is split into 255 byte
+/// segments, ended by a segment shorter than 255 bytes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Page {
+    pub(crate) flags: u8,
+    pub(crate) granule_position: i64,
+    pub(crate) serial: u32,
+    pub(crate) sequence: u32,
+    pub(crate) lacing: Vec<u8>,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Page {
+    pub(crate) fn is_continued(&self) -> bool {
+        self.flags & FLAG_CONTINUED != 0
+    }
+
+    pub(crate) fn is_end_of_stream(&self) -> bool {
+        self.flags & FLAG_END_OF_STREAM != 0
+    }
+
+    /// Gets the size of the page once serialized
+    pub(crate) fn len(&self) -> usize {
+        HEADER_SIZE + self.lacing.len() + self.data.len()
+    }
+
+    /// Parses the page at the start of `bytes`, returning it along with the
+    /// number of bytes it took up.
+    pub(crate) fn parse(bytes: &[u8]) -> Result<(Page, usize), OggError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(OggError::TruncatedPage);
+        }
+        if &bytes[0..4] != CAPTURE_PATTERN {
+            return Err(OggError::BadCapturePattern);
+        }
+        if bytes[4] != 0 {
+            return Err(OggError::UnsupportedVersion(bytes[4]));
+        }
+        let segments = bytes[26] as usize;
+        let lacing = bytes
+            .get(HEADER_SIZE..HEADER_SIZE + segments)
+            .ok_or(OggError::TruncatedPage)?;
+        let data_start = HEADER_SIZE + segments;
+        let len = data_start + lacing.iter().map(|&lace| lace as usize).sum::<usize>();
+        if bytes.len() < len {
+            return Err(OggError::TruncatedPage);
+        }
+
+        let expected = u32::from_le_bytes(bytes[CRC_OFFSET..CRC_OFFSET + 4].try_into().unwrap());
+        let mut checked = bytes[..len].to_vec();
+        checked[CRC_OFFSET..CRC_OFFSET + 4].fill(0);
+        if crc32(&checked) != expected {
+            return Err(OggError::BadChecksum);
+        }
+
+        let page = Page {
+            flags: bytes[5],
+            granule_position: i64::from_le_bytes(bytes[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(bytes[14..18].try_into().unwrap()),
+            sequence: u32::from_le_bytes(bytes[18..22].try_into().unwrap()),
+            lacing: lacing.to_vec(),
+            data: bytes[data_start..len].to_vec(),
+        };
+        Ok((page, len))
+    }
+
+    /// Reads the next page from `reader`.
+    ///
+    /// Returns `None` if the reader is at its end.
+    pub(crate) fn read_from(reader: &mut impl Read) -> Result<Option<Page>, OggError> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        let mut filled = 0;
+        while filled < HEADER_SIZE {
+            match reader.read(&mut bytes[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(OggError::TruncatedPage),
+                read => filled += read,
+            }
+        }
+        if &bytes[0..4] != CAPTURE_PATTERN {
+            return Err(OggError::BadCapturePattern);
+        }
+
+        let segments = bytes[26] as usize;
+        bytes.resize(HEADER_SIZE + segments, 0);
+        reader.read_exact(&mut bytes[HEADER_SIZE..])?;
+        let data_len: usize = bytes[HEADER_SIZE..].iter().map(|&lace| lace as usize).sum();
+        let data_start = bytes.len();
+        bytes.resize(data_start + data_len, 0);
+        reader.read_exact(&mut bytes[data_start..])?;
+
+        Page::parse(&bytes).map(|(page, _)| Some(page))
+    }
+
+    /// Serializes the page, filling in its checksum.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.lacing.len() + self.data.len());
+        bytes.extend_from_slice(CAPTURE_PATTERN);
+        bytes.push(0);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&self.granule_position.to_le_bytes());
+        bytes.extend_from_slice(&self.serial.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.push(self.lacing.len() as u8);
+        bytes.extend_from_slice(&self.lacing);
+        bytes.extend_from_slice(&self.data);
+        let crc = crc32(&bytes);
+        bytes[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// A packet reassembled from one or more pages
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Packet {
+    pub(crate) data: Vec<u8>,
+    /// The granule position of the page this packet ended on, if it is the
+    /// last packet to end on that page
+    pub(crate) granule_position: Option<i64>,
+    /// Whether this is the last packet of the stream
+    pub(crate) end_of_stream: bool,
+}
+
+/// Reassembles the packets of the first logical stream found in a reader.
+pub(crate) struct PacketReader<R> {
+    reader: R,
+    /// Bytes of pages read, relative to where the reader started
+    position: u64,
+    serial: Option<u32>,
+    sequence: Option<u32>,
+    partial: Vec<u8>,
+    /// The rest of a packet that can't be reassembled is being skipped
+    skipping: bool,
+    packets: VecDeque<Packet>,
+    ended: bool,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            position: 0,
+            serial: None,
+            sequence: None,
+            partial: Vec::new(),
+            skipping: false,
+            packets: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    pub(crate) fn serial(&self) -> Option<u32> {
+        self.serial
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Gets the offset of the next page, relative to where the reader
+    /// started.
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Gets the next complete packet, or `None` once the stream has ended.
+    pub(crate) fn next_packet(&mut self) -> Result<Option<Packet>, OggError> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(Some(packet));
+            }
+            if self.ended {
+                return Ok(None);
+            }
+            match Page::read_from(&mut self.reader)? {
+                Some(page) => {
+                    self.position += page.len() as u64;
+                    self.push_page(page)?;
+                }
+                None => self.ended = true,
+            }
+        }
+    }
+
+    fn push_page(&mut self, page: Page) -> Result<(), OggError> {
+        if *self.serial.get_or_insert(page.serial) != page.serial {
+            return Ok(());
+        }
+        let follows = self.sequence.map(|sequence| sequence.wrapping_add(1)) == Some(page.sequence);
+        self.sequence = Some(page.sequence);
+        if !follows || !page.is_continued() {
+            self.partial.clear();
+        }
+        // A packet continued from a page we never saw can't be reassembled,
+        // and one that is being skipped stays skipped until it ends
+        if !page.is_continued() {
+            self.skipping = false;
+        } else if !follows {
+            self.skipping = true;
+        }
+
+        let completed_before = self.packets.len();
+        let mut oversized = false;
+        let mut offset = 0;
+        for &lace in &page.lacing {
+            let segment = &page.data[offset..offset + lace as usize];
+            offset += lace as usize;
+            if !self.skipping {
+                self.partial.extend_from_slice(segment);
+                if self.partial.len() > MAX_PACKET_SIZE {
+                    self.partial = Vec::new();
+                    self.skipping = true;
+                    oversized = true;
+                }
+            }
+            if lace < 255 {
+                if self.skipping {
+                    self.skipping = false;
+                } else {
+                    self.packets.push_back(Packet {
+                        data: std::mem::take(&mut self.partial),
+                        granule_position: None,
+                        end_of_stream: false,
+                    });
+                }
+            }
+        }
+        if self.packets.len() > completed_before {
+            let last = self.packets.back_mut().unwrap();
+            last.granule_position = (page.granule_position != -1).then_some(page.granule_position);
+            last.end_of_stream = page.is_end_of_stream();
+        }
+        if page.is_end_of_stream() {
+            self.ended = true;
+        }
+        if oversized {
+            return Err(OggError::PacketTooLarge);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> PacketReader<R> {
+    /// Moves to the page at `position` (relative to where the reader started),
+    /// dropping everything buffered.
+    pub(crate) fn seek_to(&mut self, position: u64) -> Result<(), OggError> {
+        let start = self.reader.stream_position()? - self.position;
+        self.reader.seek(SeekFrom::Start(start + position))?;
+        self.position = position;
+        self.sequence = None;
+        self.partial.clear();
+        self.packets.clear();
+        self.ended = false;
+        Ok(())
+    }
+
+    /// Moves past the page at `position`, so the next packet is the first one
+    /// to end after it. A packet continued from that page is kept whole.
+    pub(crate) fn seek_past(&mut self, position: u64) -> Result<(), OggError> {
+        self.seek_to(position)?;
+        if let Some(page) = Page::read_from(&mut self.reader)? {
+            self.position += page.len() as u64;
+            self.push_page(page)?;
+        }
+        self.packets.clear();
+        Ok(())
+    }
+}
+
+/// Lays packets out into the pages of a single logical stream.
+///
+/// Packets are buffered into a page until it is flushed, or until the page's
+/// segment table is full, in which case the packet continues on the next
+/// page.
+pub(crate) struct PacketWriter<W> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+    granule_position: i64,
+    continued: bool,
+    completed: bool,
+}
+
+impl<W: Write> PacketWriter<W> {
+    pub(crate) fn new(writer: W, serial: u32) -> Self {
+        Self {
+            writer,
+            serial,
+            sequence: 0,
+            lacing: Vec::new(),
+            data: Vec::new(),
+            granule_position: -1,
+            continued: false,
+            completed: false,
+        }
+    }
+
+    pub(crate) fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Gets the number of bytes of packet data in the page being built
+    pub(crate) fn buffered_bytes(&self) -> usize {
+        self.data.len()
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Adds a packet to the page being built.
+    ///
+    /// If the packet is the last of the stream, it is written out straight
+    /// away on a page marked as the end of the stream.
+    pub(crate) fn write_packet(
+        &mut self,
+        packet: &[u8],
+        granule_position: i64,
+        end_of_stream: bool,
+    ) -> Result<(), OggError> {
+        let mut rest = packet;
+        let mut first_segment = true;
+        loop {
+            if self.lacing.len() == 255 {
+                self.write_page(!first_segment, false)?;
+            }
+            let len = rest.len().min(255);
+            self.lacing.push(len as u8);
+            self.data.extend_from_slice(&rest[..len]);
+            rest = &rest[len..];
+            first_segment = false;
+            if len < 255 {
+                break;
+            }
+        }
+        self.granule_position = granule_position;
+        self.completed = true;
+        if end_of_stream {
+            self.write_page(false, true)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the page being built as the last of the stream, even if it
+    /// holds no packets.
+    pub(crate) fn end_stream(&mut self, granule_position: i64) -> Result<(), OggError> {
+        self.granule_position = granule_position;
+        self.completed = true;
+        self.write_page(false, true)
+    }
+
+    /// Writes out the page being built, if it holds anything.
+    pub(crate) fn flush_page(&mut self) -> Result<(), OggError> {
+        if self.lacing.is_empty() {
+            return Ok(());
+        }
+        self.write_page(false, false)
+    }
+
+    fn write_page(&mut self, next_continued: bool, end_of_stream: bool) -> Result<(), OggError> {
+        let mut flags = 0;
+        if self.continued {
+            flags |= FLAG_CONTINUED;
+        }
+        if self.sequence == 0 {
+            flags |= FLAG_BEGIN_OF_STREAM;
+        }
+        if end_of_stream {
+            flags |= FLAG_END_OF_STREAM;
+        }
+        let page = Page {
+            flags,
+            // Only packets that end on a page count towards its position
+            granule_position: if self.completed {
+                self.granule_position
+            } else {
+                -1
+            },
+            serial: self.serial,
+            sequence: self.sequence,
+            lacing: std::mem::take(&mut self.lacing),
+            data: std::mem::take(&mut self.data),
+        };
+        self.writer.write_all(&page.to_bytes())?;
+        self.sequence += 1;
+        self.continued = next_continued;
+        self.completed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Lays the packets out as pages of the given serial, one page per
+    /// packet, with the granule position given for each.
+    pub(crate) fn pages_for(serial: u32, packets: &[(Vec<u8>, i64)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (sequence, (packet, granule_position)) in packets.iter().enumerate() {
+            let mut lacing = vec![255; packet.len() / 255];
+            lacing.push((packet.len() % 255) as u8);
+            let mut flags = 0;
+            if sequence == 0 {
+                flags |= FLAG_BEGIN_OF_STREAM;
+            }
+            if sequence == packets.len() - 1 {
+                flags |= FLAG_END_OF_STREAM;
+            }
+            let page = Page {
+                flags,
+                granule_position: *granule_position,
+                serial,
+                sequence: sequence as u32,
+                lacing,
+                data: packet.clone(),
+            };
+            bytes.extend_from_slice(&page.to_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn computes_ogg_crc() {
+        assert_eq!(crc32(b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn page_round_trips() {
+        let page = Page {
+            flags: FLAG_BEGIN_OF_STREAM,
+            granule_position: 1234,
+            serial: 0xDEAD_BEEF,
+            sequence: 7,
+            lacing: vec![255, 10],
+            data: vec![3; 265],
+        };
+        let bytes = page.to_bytes();
+
+        assert_eq!(Page::parse(&bytes).unwrap(), (page.clone(), bytes.len()));
+        assert_eq!(Page::read_from(&mut &bytes[..]).unwrap(), Some(page));
+    }
+
+    #[test]
+    fn rejects_corrupted_page() {
+        let page = Page {
+            flags: 0,
+            granule_position: 0,
+            serial: 1,
+            sequence: 0,
+            lacing: vec![4],
+            data: vec![1, 2, 3, 4],
+        };
+        let mut bytes = page.to_bytes();
+        bytes[30] ^= 0xFF;
+
+        assert!(matches!(Page::parse(&bytes), Err(OggError::BadChecksum)));
+        assert!(matches!(
+            Page::read_from(&mut &bytes[..10]),
+            Err(OggError::TruncatedPage)
+        ));
+        assert!(matches!(
+            Page::read_from(&mut &[0; HEADER_SIZE][..]),
+            Err(OggError::BadCapturePattern)
+        ));
+    }
+
+    #[test]
+    fn reassembles_packets_across_pages() {
+        let packet: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let first = Page {
+            flags: FLAG_BEGIN_OF_STREAM,
+            granule_position: -1,
+            serial: 5,
+            sequence: 0,
+            lacing: vec![255, 255],
+            data: packet[..510].to_vec(),
+        };
+        let other_stream = Page {
+            flags: FLAG_BEGIN_OF_STREAM,
+            granule_position: 0,
+            serial: 6,
+            sequence: 0,
+            lacing: vec![1],
+            data: vec![0],
+        };
+        let second = Page {
+            flags: FLAG_CONTINUED | FLAG_END_OF_STREAM,
+            granule_position: 320,
+            serial: 5,
+            sequence: 1,
+            lacing: vec![90, 2],
+            data: [&packet[510..], &[7, 7][..]].concat(),
+        };
+        let bytes = [first.to_bytes(), other_stream.to_bytes(), second.to_bytes()].concat();
+        let mut reader = PacketReader::new(&bytes[..]);
+
+        assert_eq!(
+            reader.next_packet().unwrap(),
+            Some(Packet {
+                data: packet,
+                granule_position: None,
+                end_of_stream: false,
+            })
+        );
+        assert_eq!(
+            reader.next_packet().unwrap(),
+            Some(Packet {
+                data: vec![7, 7],
+                granule_position: Some(320),
+                end_of_stream: true,
+            })
+        );
+        assert_eq!(reader.next_packet().unwrap(), None);
+        assert_eq!(reader.serial(), Some(5));
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        let pages = MAX_PACKET_SIZE / (255 * 255) + 1;
+        let mut bytes = Vec::new();
+        for sequence in 0..pages as u32 {
+            let page = Page {
+                flags: if sequence == 0 {
+                    FLAG_BEGIN_OF_STREAM
+                } else {
+                    FLAG_CONTINUED
+                },
+                granule_position: -1,
+                serial: 9,
+                sequence,
+                lacing: vec![255; 255],
+                data: vec![7; 255 * 255],
+            };
+            bytes.extend_from_slice(&page.to_bytes());
+        }
+        let last = Page {
+            flags: FLAG_CONTINUED | FLAG_END_OF_STREAM,
+            granule_position: 200,
+            serial: 9,
+            sequence: pages as u32,
+            lacing: vec![0, 1],
+            data: vec![4],
+        };
+        bytes.extend_from_slice(&last.to_bytes());
+
+        let mut reader = PacketReader::new(&bytes[..]);
+        assert!(matches!(
+            reader.next_packet(),
+            Err(OggError::PacketTooLarge)
+        ));
+        assert_eq!(reader.next_packet().unwrap().unwrap().data, [4]);
+        assert_eq!(reader.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn splits_long_packets_across_pages() {
+        let long: Vec<u8> = (0..255 * 300).map(|i| (i % 251) as u8).collect();
+        let mut writer = PacketWriter::new(Vec::new(), 9);
+        writer.write_packet(&[1, 2, 3], 0, false).unwrap();
+        writer.flush_page().unwrap();
+        writer.write_packet(&long, 100, false).unwrap();
+        writer.write_packet(&[4], 200, true).unwrap();
+        let bytes = writer.into_inner();
+
+        let mut pages = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let (page, len) = Page::parse(&bytes[offset..]).unwrap();
+            pages.push(page);
+            offset += len;
+        }
+        let flags: Vec<u8> = pages.iter().map(|page| page.flags).collect();
+        let granules: Vec<i64> = pages.iter().map(|page| page.granule_position).collect();
+        assert_eq!(
+            flags,
+            [FLAG_BEGIN_OF_STREAM, 0, FLAG_CONTINUED | FLAG_END_OF_STREAM]
+        );
+        assert_eq!(granules, [0, -1, 200]);
+
+        let mut reader = PacketReader::new(&bytes[..]);
+        assert_eq!(reader.next_packet().unwrap().unwrap().data, [1, 2, 3]);
+        assert_eq!(reader.next_packet().unwrap().unwrap().data, long);
+        assert_eq!(reader.next_packet().unwrap().unwrap().data, [4]);
+        assert_eq!(reader.next_packet().unwrap(), None);
+    }
+}
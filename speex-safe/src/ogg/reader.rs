@@ -0,0 +1,630 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use crate::ogg::page::{PacketReader, Page, CAPTURE_PATTERN, HEADER_SIZE};
+use crate::ogg::OggError;
+use crate::resampler::Sample;
+use crate::{DynamicDecoder, DynamicEncoder, SpeexComments, SpeexHeader, SpeexStereoState};
+
+/// Frames decoded before the seek target to settle the decoder state
+const PREROLL_FRAMES: u64 = 4;
+
+/// Largest possible page: a full segment table of full segments
+const MAX_PAGE_SIZE: u64 = HEADER_SIZE as u64 + 255 + 255 * 255;
+
+/// How far back from the end of the file to look for the last page at a time
+const SCAN_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Reader for speex streams stored in Ogg files (`.spx`).
+///
+/// The speex header and comment packets are read when the reader is created,
+/// and a decoder is set up to match. Decoded audio is interleaved when the
+/// stream is stereo.
+///
+/// The granule positions in the stream are used to trim the output, so it
+/// holds exactly the samples that were encoded: the encoder delay is dropped
+/// from the start, and padding from the last frame is dropped from the end.
+///
+/// An output rate set on the decoder (see `SpeexDecoder::set_output_rate`)
+/// is applied after trimming. Positions are always counted at the rate of
+/// the stream.
+pub struct OggSpeexReader<R> {
+    packets: PacketReader<R>,
+    header: SpeexHeader,
+    comments: SpeexComments,
+    decoder: DynamicDecoder,
+    channels: usize,
+    /// Encoder delay of a stream encoded by libspeex in this mode
+    lookahead: usize,
+    pending: Vec<f32>,
+    /// Offset of the first audio page, relative to where the reader started
+    data_start: u64,
+    /// Position of the first pending sample
+    position: u64,
+    /// Samples still to be dropped to reach the position seeked to
+    discard: u64,
+    started: bool,
+}
+
+impl<R: Read> OggSpeexReader<R> {
+    /// Creates a reader, reading the stream headers from `reader`.
+    pub fn new(reader: R) -> Result<Self, OggError> {
+        let mut packets = PacketReader::new(reader);
+        let header = packets.next_packet()?.ok_or(OggError::MissingHeaders)?;
+        let header = SpeexHeader::parse(&header.data)?;
+        let comments = packets.next_packet()?.ok_or(OggError::MissingHeaders)?;
+        let comments = SpeexComments::parse(&comments.data)?;
+        for _ in 0..header.extra_headers() {
+            packets.next_packet()?.ok_or(OggError::MissingHeaders)?;
+        }
+
+        let data_start = packets.position();
+
+        let mut decoder = DynamicDecoder::from_header(&header)?;
+        let channels = header.num_channels() as usize;
+        if channels == 2 {
+            decoder.set_stereo_state(SpeexStereoState::new())?;
+        }
+        let lookahead = DynamicEncoder::new(decoder.mode_id()).get_lookahead()? as usize;
+        Ok(Self {
+            packets,
+            header,
+            comments,
+            decoder,
+            channels,
+            lookahead,
+            pending: Vec::new(),
+            data_start,
+            position: 0,
+            discard: 0,
+            started: false,
+        })
+    }
+
+    /// Gets the header of the stream
+    pub fn header(&self) -> &SpeexHeader {
+        &self.header
+    }
+
+    /// Gets the comments of the stream
+    pub fn comments(&self) -> &SpeexComments {
+        &self.comments
+    }
+
+    /// Gets the sample rate of the stream
+    pub fn sample_rate(&self) -> u32 {
+        self.header.rate()
+    }
+
+    /// Gets the number of channels in the stream, 1 or 2
+    pub fn channels(&self) -> u32 {
+        self.channels as u32
+    }
+
+    /// Gets the decoder, e.g. to turn on perceptual enhancement or set an
+    /// output rate
+    pub fn decoder_mut(&mut self) -> &mut DynamicDecoder {
+        &mut self.decoder
+    }
+
+    /// Gets the position of the next sample to be read, in samples per
+    /// channel from the start of the stream
+    pub fn position(&self) -> u64 {
+        self.position + self.discard
+    }
+
+    /// Decodes the next part of the stream, appending the samples to `out`.
+    ///
+    /// Returns the number of samples (per channel, at the output rate)
+    /// appended, which is only 0 once the end of the stream is reached.
+    pub fn decode(&mut self, out: &mut Vec<f32>) -> Result<usize, OggError> {
+        loop {
+            let Some(packet) = self.packets.next_packet()? else {
+                // Without a final granule position there is nothing to trim
+                let read = self.take_pending(out)?;
+                return Ok(read + self.decoder.flush_output(out));
+            };
+            self.decoder.decode_packet_unresampled(
+                &packet.data,
+                &mut self.pending,
+                self.channels,
+            )?;
+            if let Some(granule_position) = packet.granule_position {
+                self.trim_pending(granule_position.max(0) as u64, packet.end_of_stream);
+                let read = self.take_pending(out)?;
+                if read > 0 {
+                    return Ok(read);
+                }
+            }
+        }
+    }
+
+    /// Decodes the next part of the stream as i16, appending the samples to
+    /// `out`.
+    ///
+    /// See `decode` for details.
+    pub fn decode_int(&mut self, out: &mut Vec<i16>) -> Result<usize, OggError> {
+        let mut samples = Vec::new();
+        let read = self.decode(&mut samples)?;
+        out.extend(samples.into_iter().map(i16::from_f32));
+        Ok(read)
+    }
+
+    /// Decodes the rest of the stream.
+    pub fn decode_to_end(&mut self) -> Result<Vec<f32>, OggError> {
+        let mut out = Vec::new();
+        while self.decode(&mut out)? > 0 {}
+        Ok(out)
+    }
+
+    /// Drops the samples beyond what the granule position says the stream
+    /// holds so far.
+    ///
+    /// The first page with a granule position tells how much encoder delay to
+    /// skip at the start. The last page tells how much padding to skip at the
+    /// end.
+    fn trim_pending(&mut self, granule_position: u64, end_of_stream: bool) {
+        let first = !self.started;
+        self.started = true;
+        let pending = self.pending.len() / self.channels;
+        let excess = (self.position + pending as u64).saturating_sub(granule_position);
+        let excess = excess.min(pending as u64) as usize;
+        let delay = match (first, end_of_stream) {
+            (true, false) => excess,
+            // When the whole stream fits on one page the delay can't be told
+            // apart from the padding, so assume the stream came from libspeex
+            (true, true) => self.lookahead.min(excess),
+            (false, _) => 0,
+        };
+        let padding = if end_of_stream { excess - delay } else { 0 };
+        self.pending.truncate((pending - padding) * self.channels);
+        self.pending.drain(..delay * self.channels);
+    }
+
+    fn take_pending(&mut self, out: &mut Vec<f32>) -> Result<usize, OggError> {
+        let pending = self.pending.len() / self.channels;
+        let discarded = (self.discard as usize).min(pending);
+        self.pending.drain(..discarded * self.channels);
+        self.discard -= discarded as u64;
+        self.position += discarded as u64;
+
+        self.position += (pending - discarded) as u64;
+        let read = self
+            .decoder
+            .resample_output(&self.pending, out, self.channels)?;
+        self.pending.clear();
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> OggSpeexReader<R> {
+    /// Gets the total number of samples (per channel) in the stream, from the
+    /// granule position of its last page.
+    ///
+    /// This looks at the end of the file, then returns to where the reader
+    /// was.
+    pub fn total_samples(&mut self) -> Result<u64, OggError> {
+        let serial = self.packets.serial();
+        let reader = self.packets.get_mut();
+        let position = reader.stream_position()?;
+        let last = last_granule_position(reader, serial);
+        reader.seek(SeekFrom::Start(position))?;
+        Ok(last?.unwrap_or(0).max(0) as u64)
+    }
+
+    /// Gets the total duration of the stream.
+    ///
+    /// See `total_samples` for details.
+    pub fn duration(&mut self) -> Result<Duration, OggError> {
+        let rate = self.sample_rate();
+        if rate == 0 {
+            return Ok(Duration::ZERO);
+        }
+        let samples = self.total_samples()?;
+        Ok(Duration::from_secs_f64(samples as f64 / rate as f64))
+    }
+
+    /// Moves to the given time in the stream.
+    ///
+    /// See `seek_to_sample` for details.
+    pub fn seek(&mut self, time: Duration) -> Result<(), OggError> {
+        let sample = time.as_secs_f64() * self.sample_rate() as f64;
+        self.seek_to_sample(sample.round() as u64)
+    }
+
+    /// Moves to the given sample (per channel) in the stream, so the next
+    /// sample decoded is that one.
+    ///
+    /// The page to resume from is found by bisecting the file on granule
+    /// position. The decoder is reset, and a few frames before the target are
+    /// decoded and thrown away so its state has settled by the time the target
+    /// is reached. Seeking past the end of the stream leaves the reader at the
+    /// end.
+    pub fn seek_to_sample(&mut self, sample: u64) -> Result<(), OggError> {
+        let preroll = PREROLL_FRAMES * self.decoder.get_frame_size()? as u64;
+        let serial = self.packets.serial();
+        let position = self.packets.position();
+        let reader = self.packets.get_mut();
+        let start = reader.stream_position()? - position;
+        let end = reader.seek(SeekFrom::End(0))?;
+        let page = find_page_before(
+            reader,
+            serial,
+            start + self.data_start,
+            end,
+            sample.saturating_sub(preroll),
+        );
+        reader.seek(SeekFrom::Start(start + position))?;
+        let page = page?;
+
+        self.decoder.reset_state()?;
+        self.decoder.reset_output();
+        if self.channels == 2 {
+            self.decoder.set_stereo_state(SpeexStereoState::new())?;
+        }
+        self.pending.clear();
+        match page {
+            // Every packet ending after this page starts at its granule
+            // position
+            Some((offset, granule_position)) => {
+                self.packets.seek_past(offset - start)?;
+                self.position = granule_position as u64;
+                self.started = true;
+            }
+            // Too close to the start, so decode from the beginning, where
+            // the encoder delay is still to be trimmed
+            None => {
+                self.packets.seek_to(self.data_start)?;
+                self.position = 0;
+                self.started = false;
+            }
+        }
+        self.discard = sample - self.position;
+        Ok(())
+    }
+}
+
+/// Finds the granule position of the last page of the stream, scanning
+/// backwards from the end of the file.
+fn last_granule_position(
+    reader: &mut (impl Read + Seek),
+    serial: Option<u32>,
+) -> Result<Option<i64>, OggError> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut end = len;
+    while end > 0 {
+        let start = end.saturating_sub(SCAN_CHUNK_SIZE);
+        let last = pages_between(reader, start, end, len)?
+            .into_iter()
+            .rfind(|(_, page)| is_timed_page(page, serial));
+        if let Some((_, page)) = last {
+            return Ok(Some(page.granule_position));
+        }
+        end = start;
+    }
+    Ok(None)
+}
+
+/// Finds the last page of the stream between `start` and `end` with a granule
+/// position no later than `target`, by bisection.
+///
+/// Returns the offset of the page and its granule position.
+fn find_page_before(
+    reader: &mut (impl Read + Seek),
+    serial: Option<u32>,
+    mut start: u64,
+    mut end: u64,
+    target: u64,
+) -> Result<Option<(u64, i64)>, OggError> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut best = None;
+    while start < end {
+        let middle = start + (end - start) / 2;
+        match first_timed_page(reader, serial, middle, end, len)? {
+            Some((offset, page)) if page.granule_position as u64 <= target => {
+                best = Some((offset, page.granule_position));
+                start = offset + page.len() as u64;
+            }
+            _ => end = middle,
+        }
+    }
+    Ok(best)
+}
+
+/// Finds the first page of the stream starting between `start` and `end`
+/// with a granule position.
+fn first_timed_page(
+    reader: &mut (impl Read + Seek),
+    serial: Option<u32>,
+    mut start: u64,
+    end: u64,
+    len: u64,
+) -> Result<Option<(u64, Page)>, OggError> {
+    while start < end {
+        let chunk_end = (start + SCAN_CHUNK_SIZE).min(end);
+        let first = pages_between(reader, start, chunk_end, len)?
+            .into_iter()
+            .find(|(_, page)| is_timed_page(page, serial));
+        if first.is_some() {
+            return Ok(first);
+        }
+        start = chunk_end;
+    }
+    Ok(None)
+}
+
+fn is_timed_page(page: &Page, serial: Option<u32>) -> bool {
+    serial.is_none_or(|serial| serial == page.serial) && page.granule_position != -1
+}
+
+/// Reads every page that starts between `start` and `end` in a file of `len`
+/// bytes, along with its offset.
+///
+/// Anything that isn't a valid page is skipped, so `start` doesn't have to be
+/// on a page boundary.
+fn pages_between(
+    reader: &mut (impl Read + Seek),
+    start: u64,
+    end: u64,
+    len: u64,
+) -> Result<Vec<(u64, Page)>, OggError> {
+    // Read past the end so a page starting before it is whole
+    let read_end = (end + MAX_PAGE_SIZE).min(len);
+    let mut bytes = vec![0; (read_end - start) as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut bytes)?;
+
+    let limit = (end - start) as usize;
+    let mut pages = Vec::new();
+    let mut offset = 0;
+    while offset < limit {
+        let Some(found) = bytes[offset..limit]
+            .windows(CAPTURE_PATTERN.len())
+            .position(|window| window == CAPTURE_PATTERN)
+        else {
+            break;
+        };
+        offset += found;
+        match Page::parse(&bytes[offset..]) {
+            Ok((page, page_len)) => {
+                pages.push((start + offset as u64, page));
+                offset += page_len;
+            }
+            // Either not actually a page, or a damaged one
+            Err(_) => offset += 1,
+        }
+    }
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::ogg::page::test::pages_for;
+    use crate::test_util::{noise, sine};
+    use crate::{
+        Error,
+        ModeId,
+        NbMode,
+        OggSpeexWriter,
+        SpeexBits,
+        SpeexEncoder,
+        SpeexHeaderBuilder,
+        StreamEncoder,
+    };
+
+    /// Builds a mono narrowband file of `frames` frames, with the final
+    /// granule position cut short by `trimmed` samples.
+    fn mono_file(frames: usize, trimmed: i64) -> (Vec<u8>, i64) {
+        let header = SpeexHeaderBuilder::new(8000, 1, ModeId::NarrowBand)
+            .build()
+            .unwrap();
+        let mut comments = SpeexComments::new("test");
+        comments.add_tag("CALLER_ID", "1234").unwrap();
+        let mut stream = StreamEncoder::from_header(&header).unwrap();
+        let lookahead = stream.encoder_mut().get_lookahead().unwrap() as i64;
+
+        let mut packets = vec![(header.to_bytes(), 0), (comments.to_bytes(), 0)];
+        let audio = stream.push(&sine(160 * frames, 1)).unwrap();
+        for (index, packet) in audio.into_iter().enumerate() {
+            let granule_position = (index as i64 + 1) * 160 - lookahead;
+            packets.push((packet, granule_position));
+        }
+        packets.last_mut().unwrap().1 -= trimmed;
+        let total = packets.last().unwrap().1;
+        (pages_for(0x1234, &packets), total)
+    }
+
+    #[test]
+    fn reads_mono_file() {
+        let (file, total) = mono_file(10, 0);
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+
+        assert_eq!(reader.sample_rate(), 8000);
+        assert_eq!(reader.channels(), 1);
+        assert_eq!(reader.comments().get("caller_id"), Some("1234"));
+        assert_eq!(reader.total_samples().unwrap(), total as u64);
+
+        let samples = reader.decode_to_end().unwrap();
+        assert_eq!(samples.len() as i64, total);
+        assert_eq!(reader.position(), total as u64);
+        assert!(samples.iter().any(|&sample| sample.abs() > 1000.0));
+        assert_eq!(reader.decode(&mut Vec::new()).unwrap(), 0);
+    }
+
+    #[test]
+    fn trims_padding_from_last_page() {
+        let (file, total) = mono_file(10, 100);
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+
+        let mut samples = Vec::new();
+        while reader.decode_int(&mut samples).unwrap() > 0 {}
+
+        assert_eq!(samples.len() as i64, total);
+        assert_eq!(
+            reader.duration().unwrap(),
+            Duration::from_secs_f64(total as f64 / 8000.0)
+        );
+    }
+
+    #[test]
+    fn reads_stereo_file() {
+        let header = SpeexHeaderBuilder::new(8000, 2, ModeId::NarrowBand)
+            .build()
+            .unwrap();
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        let mut packets = vec![
+            (header.to_bytes(), 0),
+            (SpeexComments::default().to_bytes(), 0),
+        ];
+        let input = sine(160 * 4, 2);
+        for (index, frame) in input.chunks(320).enumerate() {
+            let mut bits = SpeexBits::new();
+            encoder.encode_stereo(frame, &mut bits).unwrap();
+            packets.push((bits.take_packet(), (index as i64 + 1) * 160));
+        }
+        let mut reader = OggSpeexReader::new(Cursor::new(pages_for(7, &packets))).unwrap();
+
+        let samples = reader.decode_to_end().unwrap();
+
+        assert_eq!(reader.channels(), 2);
+        assert_eq!(samples.len(), 160 * 4 * 2);
+        assert_eq!(reader.total_samples().unwrap(), 160 * 4);
+    }
+
+    #[test]
+    fn rejects_bad_streams() {
+        let header = SpeexHeaderBuilder::new(8000, 1, ModeId::NarrowBand)
+            .build()
+            .unwrap();
+        let only_header = pages_for(1, &[(header.to_bytes(), 0)]);
+        assert!(matches!(
+            OggSpeexReader::new(&only_header[..]),
+            Err(OggError::MissingHeaders)
+        ));
+
+        let not_speex = pages_for(1, &[(vec![0; 80], 0), (vec![0; 8], 0)]);
+        assert!(matches!(
+            OggSpeexReader::new(&not_speex[..]),
+            Err(OggError::Speex(Error::Header(_)))
+        ));
+
+        let (mut file, _) = mono_file(4, 0);
+        let len = file.len();
+        file[len - 1] ^= 0xFF;
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+        assert!(matches!(reader.decode_to_end(), Err(OggError::BadChecksum)));
+    }
+
+    /// Writes three seconds of a tone over many pages, returning the file and
+    /// its samples decoded straight through.
+    fn long_file() -> (Vec<u8>, Vec<f32>) {
+        let header = SpeexHeaderBuilder::new(8000, 1, ModeId::NarrowBand)
+            .frames_per_packet(3)
+            .build()
+            .unwrap();
+        let mut writer =
+            OggSpeexWriter::new(Vec::new(), &header, &SpeexComments::default()).unwrap();
+        writer.set_max_page_samples(Some(1000));
+        // Noise keeps the predictor from carrying the signal on its own, as
+        // with speech, so it settles within the pre-roll
+        let mut seed = 1;
+        let input: Vec<f32> = sine(8000 * 3, 1)
+            .into_iter()
+            .zip(noise(8000 * 3, 2000.0, &mut seed))
+            .map(|(sample, noise)| sample * 0.5 + noise)
+            .collect();
+        writer.write(&input).unwrap();
+        let file = writer.finish().unwrap();
+        let samples = OggSpeexReader::new(Cursor::new(file.clone()))
+            .unwrap()
+            .decode_to_end()
+            .unwrap();
+        (file, samples)
+    }
+
+    #[test]
+    fn seeks_to_exact_sample() {
+        let (file, samples) = long_file();
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+
+        for target in [12345, 100, 23990, 7000, 0] {
+            reader.seek_to_sample(target as u64).unwrap();
+            assert_eq!(reader.position(), target as u64);
+
+            let mut out = Vec::new();
+            while out.len() < 480 && reader.decode(&mut out).unwrap() > 0 {}
+            let expected = &samples[target..];
+            let len = out.len().min(expected.len());
+            assert!(len >= expected.len().min(480));
+
+            // The decoder state is rebuilt from the pre-roll rather than
+            // carried over, so only expect it to be close
+            let error: f32 = out[..len]
+                .iter()
+                .zip(&expected[..len])
+                .map(|(a, b)| (a - b).abs())
+                .sum::<f32>()
+                / len as f32;
+            let level: f32 = expected[..len].iter().map(|a| a.abs()).sum::<f32>() / len as f32;
+            assert!(error < level * 0.1, "error {error} at {target}");
+        }
+    }
+
+    #[test]
+    fn seeks_back_to_start() {
+        let (file, samples) = long_file();
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+        reader.decode(&mut Vec::new()).unwrap();
+
+        reader.seek(Duration::ZERO).unwrap();
+        assert_eq!(reader.position(), 0);
+        let out = reader.decode_to_end().unwrap();
+
+        // Only the frames right after the reset differ from a fresh decoder
+        assert_eq!(out.len(), samples.len());
+        assert_eq!(out[1000..], samples[1000..]);
+    }
+
+    #[test]
+    fn seeks_by_time_and_past_end() {
+        let (file, samples) = long_file();
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+
+        reader.seek(Duration::from_millis(2500)).unwrap();
+        assert_eq!(reader.position(), 20000);
+        assert_eq!(reader.decode_to_end().unwrap().len(), samples.len() - 20000);
+
+        reader.seek(Duration::from_secs(10)).unwrap();
+        assert_eq!(reader.decode(&mut Vec::new()).unwrap(), 0);
+    }
+
+    #[test]
+    fn resamples_after_trimming() {
+        let (file, samples) = long_file();
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+        reader
+            .decoder_mut()
+            .set_output_rate(Some(16000), 3)
+            .unwrap();
+
+        // Trimmed at the stream rate, with the held back tail at the end
+        let out = reader.decode_to_end().unwrap();
+        assert_eq!(out.len(), samples.len() * 2);
+        assert_eq!(reader.position(), samples.len() as u64);
+
+        // Nothing from before the seek is left in the resampler
+        reader.seek_to_sample(20000).unwrap();
+        let out = reader.decode_to_end().unwrap();
+        assert_eq!(out.len(), (samples.len() - 20000) * 2);
+    }
+}
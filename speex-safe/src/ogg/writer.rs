@@ -0,0 +1,327 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::io::Write;
+
+use crate::ogg::page::PacketWriter;
+use crate::ogg::OggError;
+use crate::{DynamicEncoder, SpeexComments, SpeexHeader, StreamEncoder};
+
+/// Amount of packet data a page is filled with before it is written, the
+/// same as libogg uses
+const PAGE_FILL_BYTES: usize = 4096;
+
+/// Writer for speex streams stored in Ogg files (`.spx`).
+///
+/// The header and comment packets are written when the writer is created,
+/// each on its own page. Audio is then encoded into packets as described by
+/// the header, with granule positions set the same way `speexenc` does, so the
+/// encoder delay (`get_lookahead`) can be trimmed off again when decoding.
+///
+/// `finish` must be called once all audio is written, to encode the last
+/// frames and mark the end of the stream.
+pub struct OggSpeexWriter<W: Write> {
+    packets: PacketWriter<W>,
+    stream: StreamEncoder,
+    lookahead: u64,
+    samples_written: u64,
+    packets_encoded: u64,
+    /// The last packet encoded, held back until it is known whether it ends
+    /// the stream
+    held: Option<(Vec<u8>, i64)>,
+    max_page_samples: Option<u64>,
+    page_start: i64,
+}
+
+impl<W: Write> OggSpeexWriter<W> {
+    /// Creates a writer with a random serial number, writing the header and
+    /// comments to `writer`.
+    ///
+    /// The random serial lets streams from separate files be told apart when
+    /// chained or multiplexed.
+    pub fn new(
+        writer: W,
+        header: &SpeexHeader,
+        comments: &SpeexComments,
+    ) -> Result<Self, OggError> {
+        Self::with_serial(writer, header, comments, crate::random_u64() as u32)
+    }
+
+    /// Creates a writer with the given serial number, writing the header and
+    /// comments to `writer`.
+    pub fn with_serial(
+        writer: W,
+        header: &SpeexHeader,
+        comments: &SpeexComments,
+        serial: u32,
+    ) -> Result<Self, OggError> {
+        let mut stream = StreamEncoder::from_header(header)?;
+        let lookahead = stream.encoder_mut().get_lookahead()? as u64;
+
+        let mut packets = PacketWriter::new(writer, serial);
+        packets.write_packet(&header.to_bytes(), 0, false)?;
+        packets.flush_page()?;
+        packets.write_packet(&comments.to_bytes(), 0, false)?;
+        packets.flush_page()?;
+
+        Ok(Self {
+            packets,
+            stream,
+            lookahead,
+            samples_written: 0,
+            packets_encoded: 0,
+            held: None,
+            max_page_samples: None,
+            page_start: 0,
+        })
+    }
+
+    /// Gets the serial number of the stream
+    pub fn serial(&self) -> u32 {
+        self.packets.serial()
+    }
+
+    /// Gets the underlying encoder, e.g. to change its settings mid-stream
+    pub fn encoder_mut(&mut self) -> &mut DynamicEncoder {
+        self.stream.encoder_mut()
+    }
+
+    /// Gets the number of samples (per channel) written so far
+    pub fn samples_written(&self) -> u64 {
+        self.samples_written
+    }
+
+    /// Sets how many samples a page can cover before it is written.
+    ///
+    /// By default pages are only written once they hold 4096 bytes, which
+    /// can take several seconds at low bitrates. Setting this bounds the
+    /// delay when the file is being streamed as it is written.
+    pub fn set_max_page_samples(&mut self, max_page_samples: Option<u64>) {
+        self.max_page_samples = max_page_samples;
+    }
+
+    /// Encodes audio into the stream, interleaved if the stream is stereo.
+    pub fn write(&mut self, input: &[f32]) -> Result<(), OggError> {
+        self.samples_written += (input.len() / self.stream.channels()) as u64;
+        for packet in self.stream.push(input)? {
+            self.queue_packet(packet)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes audio into the stream, using an integer representation.
+    ///
+    /// See `write` for details.
+    pub fn write_int(&mut self, input: &[i16]) -> Result<(), OggError> {
+        self.samples_written += (input.len() / self.stream.channels()) as u64;
+        for packet in self.stream.push_int(input)? {
+            self.queue_packet(packet)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out every packet encoded so far, even if the page isn't full.
+    ///
+    /// Input that doesn't make up a whole packet yet stays buffered.
+    pub fn flush_page(&mut self) -> Result<(), OggError> {
+        if let Some((packet, granule_position)) = self.held.take() {
+            self.packets
+                .write_packet(&packet, granule_position, false)?;
+            self.page_start = granule_position;
+        }
+        self.packets.flush_page()?;
+        self.packets.get_mut().flush()?;
+        Ok(())
+    }
+
+    /// Encodes the rest of the stream and writes the final page, returning
+    /// the underlying writer.
+    ///
+    /// The last frame is padded with silence so that the encoder delay is
+    /// covered, and the final granule position is set to the exact number of
+    /// samples written.
+    pub fn finish(mut self) -> Result<W, OggError> {
+        let frame_size = self.stream.frame_size() as u64;
+        let frames = (self.samples_written + self.lookahead).div_ceil(frame_size);
+        let padding = frames * frame_size - self.samples_written;
+        let silence = vec![0.0; padding as usize * self.stream.channels()];
+        for packet in self.stream.push(&silence)? {
+            self.queue_packet(packet)?;
+        }
+        for packet in self.stream.flush()? {
+            self.queue_packet(packet)?;
+        }
+
+        let total = self.samples_written as i64;
+        match self.held.take() {
+            Some((packet, _)) => self.packets.write_packet(&packet, total, true)?,
+            None => self.packets.end_stream(total)?,
+        }
+        self.packets.get_mut().flush()?;
+        Ok(self.packets.into_inner())
+    }
+
+    fn queue_packet(&mut self, packet: Vec<u8>) -> Result<(), OggError> {
+        self.packets_encoded += 1;
+        let samples_per_packet =
+            (self.stream.frame_size() * self.stream.frames_per_packet()) as u64;
+        let granule_position =
+            (self.packets_encoded * samples_per_packet) as i64 - self.lookahead as i64;
+        let Some((packet, granule_position)) = self.held.replace((packet, granule_position)) else {
+            return Ok(());
+        };
+
+        self.packets
+            .write_packet(&packet, granule_position, false)?;
+        let page_full = self.packets.buffered_bytes() >= PAGE_FILL_BYTES;
+        let page_long = self
+            .max_page_samples
+            .is_some_and(|max| (granule_position - self.page_start) as u64 >= max);
+        if page_full || page_long {
+            self.packets.flush_page()?;
+            self.page_start = granule_position;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::ogg::page::{Page, FLAG_BEGIN_OF_STREAM, FLAG_END_OF_STREAM};
+    use crate::test_util::sine;
+    use crate::{DynamicDecoder, ModeId, OggSpeexReader, SpeexHeaderBuilder};
+
+    fn pages(bytes: &[u8]) -> Vec<Page> {
+        let mut pages = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let (page, len) = Page::parse(&bytes[offset..]).unwrap();
+            pages.push(page);
+            offset += len;
+        }
+        pages
+    }
+
+    #[test]
+    fn round_trips_through_reader() {
+        let header = SpeexHeaderBuilder::new(8000, 1, ModeId::NarrowBand)
+            .frames_per_packet(2)
+            .build()
+            .unwrap();
+        let input = sine(1234, 1);
+
+        // Decoding the raw packets and dropping the encoder delay has to give
+        // the same samples
+        let mut stream = StreamEncoder::from_header(&header).unwrap();
+        let lookahead = stream.encoder_mut().get_lookahead().unwrap() as usize;
+        let mut packets = stream.push(&input).unwrap();
+        packets.extend(stream.push(&vec![0.0; 2 * 160]).unwrap());
+        packets.extend(stream.flush().unwrap());
+        let mut decoder = DynamicDecoder::from_header(&header).unwrap();
+        let mut expected = Vec::new();
+        for packet in &packets {
+            decoder.decode_packet(packet, &mut expected, None).unwrap();
+        }
+        let expected = &expected[lookahead..lookahead + 1234];
+
+        // Both with everything on one page, and spread over several
+        for max_page_samples in [None, Some(320)] {
+            let mut writer =
+                OggSpeexWriter::new(Vec::new(), &header, &SpeexComments::default()).unwrap();
+            writer.set_max_page_samples(max_page_samples);
+            for chunk in input.chunks(100) {
+                writer.write(chunk).unwrap();
+            }
+            let file = writer.finish().unwrap();
+
+            let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+            assert_eq!(reader.total_samples().unwrap(), 1234);
+            assert_eq!(reader.decode_to_end().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn marks_stream_boundaries() {
+        let header = SpeexHeaderBuilder::new(16000, 1, ModeId::WideBand)
+            .build()
+            .unwrap();
+        let mut writer =
+            OggSpeexWriter::with_serial(Vec::new(), &header, &SpeexComments::default(), 42)
+                .unwrap();
+        writer.write_int(&vec![500; 320 * 50]).unwrap();
+        let pages = pages(&writer.finish().unwrap());
+
+        assert!(pages.len() >= 3);
+        assert!(pages.iter().all(|page| page.serial == 42));
+        assert_eq!(pages[0].flags, FLAG_BEGIN_OF_STREAM);
+        assert_eq!(pages[0].lacing.len(), 1);
+        assert_eq!(pages[1].flags, 0);
+        let last = pages.last().unwrap();
+        assert_eq!(last.flags, FLAG_END_OF_STREAM);
+        assert_eq!(last.granule_position, 320 * 50);
+        let granules: Vec<i64> = pages[2..]
+            .iter()
+            .map(|page| page.granule_position)
+            .collect();
+        assert!(granules.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn picks_random_serials() {
+        let header = SpeexHeader::new(8000, 1, ModeId::NarrowBand).unwrap();
+        let comments = SpeexComments::default();
+        let first = OggSpeexWriter::new(Vec::new(), &header, &comments).unwrap();
+        let second = OggSpeexWriter::new(Vec::new(), &header, &comments).unwrap();
+
+        assert_ne!(first.serial(), second.serial());
+    }
+
+    #[test]
+    fn limits_page_duration() {
+        let header = SpeexHeaderBuilder::new(8000, 1, ModeId::NarrowBand)
+            .build()
+            .unwrap();
+        let comments = SpeexComments::default();
+        let input = vec![0.0; 160 * 40];
+
+        let mut writer = OggSpeexWriter::new(Vec::new(), &header, &comments).unwrap();
+        writer.write(&input).unwrap();
+        let default_pages = pages(&writer.finish().unwrap()).len();
+
+        let mut writer = OggSpeexWriter::new(Vec::new(), &header, &comments).unwrap();
+        writer.set_max_page_samples(Some(160 * 4));
+        writer.write(&input).unwrap();
+        let short_pages = pages(&writer.finish().unwrap());
+
+        assert_eq!(default_pages, 3);
+        assert!(short_pages.len() >= 10);
+        for pair in short_pages[2..].windows(2) {
+            assert!(pair[1].granule_position - pair[0].granule_position <= 160 * 4);
+        }
+    }
+
+    #[test]
+    fn writes_stereo() {
+        let header = SpeexHeaderBuilder::new(8000, 2, ModeId::NarrowBand)
+            .build()
+            .unwrap();
+        let mut writer =
+            OggSpeexWriter::new(Vec::new(), &header, &SpeexComments::default()).unwrap();
+        writer.write(&sine(1000, 2)).unwrap();
+        writer.flush_page().unwrap();
+        let file = writer.finish().unwrap();
+
+        let mut reader = OggSpeexReader::new(Cursor::new(file)).unwrap();
+        let output = reader.decode_to_end().unwrap();
+
+        assert_eq!(reader.channels(), 2);
+        assert_eq!(output.len(), 1000 * 2);
+    }
+}
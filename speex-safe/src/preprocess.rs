@@ -0,0 +1,640 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::f32::consts::PI;
+use std::fmt::{Display, Formatter};
+
+use crate::echo::EchoCanceller;
+use crate::fft::Fft;
+use crate::resampler::Sample;
+use crate::ModeId;
+
+/// Frames used to get a first noise estimate
+const STARTUP_FRAMES: u32 = 20;
+
+/// Frames the minimum of the smoothed spectrum is tracked over
+const MIN_WINDOW_FRAMES: u32 = 100;
+
+/// How far above the tracked minimum a bin has to be to count as speech
+const SPEECH_RATIO: f32 = 3.0;
+
+/// Smoothing of the noise estimate in bins without speech
+const NOISE_SMOOTHING: f32 = 0.95;
+
+/// Weight of the previous frame in the a priori SNR (decision directed)
+const PRIOR_WEIGHT: f32 = 0.98;
+
+/// Share of the previous frame's clean power that carries over as late
+/// reverberation, and how quickly that dies away
+const REVERB_LEVEL: f32 = 0.3;
+const REVERB_DECAY: f32 = 0.4;
+
+/// Speech probability to start, and to keep, detecting speech
+const VAD_PROB_START: f32 = 0.35;
+const VAD_PROB_CONTINUE: f32 = 0.20;
+
+/// Fastest the AGC gain may rise and fall, in dB per second
+const AGC_MAX_INCREASE_DB: f32 = 12.0;
+const AGC_MAX_DECREASE_DB: f32 = 40.0;
+
+/// Error type for preprocessing a frame.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PreprocessError {
+    /// The input did not contain exactly one frame of samples
+    WrongFrameSize { expected: usize, actual: usize },
+    /// The echo canceller is for frames of a different size
+    EchoMismatch { expected: usize, actual: usize },
+}
+
+impl Display for PreprocessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreprocessError::WrongFrameSize { expected, actual } => {
+                write!(
+                    f,
+                    "Input has {actual} samples but the frame size is {expected}"
+                )
+            }
+            PreprocessError::EchoMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Echo canceller has a frame size of {actual} but the preprocessor has \
+                     {expected}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+/// Preprocessor to clean up audio before it is encoded: noise suppression,
+/// dereverberation, automatic gain control and voice activity detection.
+///
+/// This is the same set of features as speexdsp's `speex_preprocess_*`,
+/// implemented in Rust. Audio is processed a frame at a time, sized to the
+/// mode it will be encoded in, so each processed frame can go straight to
+/// `SpeexEncoder::encode_int`. The spectral processing uses overlapping
+/// windows, which delays the output by a frame.
+///
+/// Denoising is on by default, and the rest is off. Voice activity is always
+/// detected, and reported for every frame. Echo left over by an
+/// `EchoCanceller` is suppressed when frames are run with `run_with_echo`.
+pub struct Preprocessor {
+    frame_size: usize,
+    sample_rate: u32,
+    fft: Fft,
+    window: Vec<f32>,
+    /// The previous input frame, followed by the current one
+    input: Vec<f32>,
+    /// Second half of the last processed window, added to the next one
+    overlap: Vec<f32>,
+    spectrum: Vec<(f32, f32)>,
+
+    denoise: bool,
+    noise_suppress: i32,
+    dereverb: bool,
+    agc: bool,
+    agc_level: f32,
+    agc_max_gain: i32,
+    echo_suppress: i32,
+    echo_suppress_active: i32,
+
+    frames: u32,
+    /// Per bin state, over the bins from DC to the Nyquist frequency
+    noise: Vec<f32>,
+    smoothed: Vec<f32>,
+    minimum: Vec<f32>,
+    minimum_window: Vec<f32>,
+    previous_clean: Vec<f32>,
+    reverb: Vec<f32>,
+    echo: Vec<f32>,
+
+    speech_probability: f32,
+    speech: bool,
+    loudness: f32,
+    agc_gain: f32,
+}
+
+impl Preprocessor {
+    /// Creates a preprocessor for frames of the given mode, at its native
+    /// sample rate.
+    pub fn new(mode: ModeId) -> Self {
+        let sample_rate = match mode {
+            ModeId::NarrowBand => 8000,
+            ModeId::WideBand => 16000,
+            ModeId::UltraWideBand => 32000,
+        };
+        let frame_size = mode.get_frame_size() as usize;
+        let window_size = frame_size * 2;
+        let fft = Fft::new(window_size.next_power_of_two());
+        let bins = fft.len() / 2 + 1;
+        // Sine window, used for analysis and synthesis, so the squares of
+        // overlapping halves sum to one
+        let window = (0..window_size)
+            .map(|i| (PI * (i as f32 + 0.5) / window_size as f32).sin())
+            .collect();
+        Self {
+            frame_size,
+            sample_rate,
+            spectrum: vec![(0.0, 0.0); fft.len()],
+            fft,
+            window,
+            input: vec![0.0; window_size],
+            overlap: vec![0.0; frame_size],
+            denoise: true,
+            noise_suppress: -15,
+            dereverb: false,
+            agc: false,
+            agc_level: 8000.0,
+            agc_max_gain: 30,
+            echo_suppress: -40,
+            echo_suppress_active: -15,
+            frames: 0,
+            noise: vec![0.0; bins],
+            smoothed: vec![0.0; bins],
+            minimum: vec![0.0; bins],
+            minimum_window: vec![0.0; bins],
+            previous_clean: vec![0.0; bins],
+            reverb: vec![0.0; bins],
+            echo: vec![0.0; bins],
+            speech_probability: 0.0,
+            speech: false,
+            loudness: 0.0,
+            agc_gain: 1.0,
+        }
+    }
+
+    /// Gets the number of samples in a frame
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Sets whether noise is suppressed
+    pub fn set_denoise(&mut self, denoise: bool) {
+        self.denoise = denoise;
+    }
+
+    /// Gets whether noise is suppressed
+    pub fn get_denoise(&self) -> bool {
+        self.denoise
+    }
+
+    /// Sets how far noise is suppressed, in (negative) dB. The default is
+    /// -15.
+    pub fn set_noise_suppress(&mut self, db: i32) {
+        self.noise_suppress = db.min(0);
+    }
+
+    /// Gets how far noise is suppressed, in dB
+    pub fn get_noise_suppress(&self) -> i32 {
+        self.noise_suppress
+    }
+
+    /// Sets whether reverberation is suppressed
+    pub fn set_dereverb(&mut self, dereverb: bool) {
+        self.dereverb = dereverb;
+    }
+
+    /// Gets whether reverberation is suppressed
+    pub fn get_dereverb(&self) -> bool {
+        self.dereverb
+    }
+
+    /// Sets whether automatic gain control is on
+    pub fn set_agc(&mut self, agc: bool) {
+        self.agc = agc;
+    }
+
+    /// Gets whether automatic gain control is on
+    pub fn get_agc(&self) -> bool {
+        self.agc
+    }
+
+    /// Sets the RMS level speech is brought to by the AGC, on the i16 scale.
+    /// The default is 8000.
+    pub fn set_agc_level(&mut self, level: f32) {
+        self.agc_level = level.max(1.0);
+    }
+
+    /// Gets the RMS level speech is brought to by the AGC
+    pub fn get_agc_level(&self) -> f32 {
+        self.agc_level
+    }
+
+    /// Sets the most the AGC may amplify, in dB. The default is 30.
+    pub fn set_agc_max_gain(&mut self, db: i32) {
+        self.agc_max_gain = db.max(0);
+    }
+
+    /// Gets the most the AGC may amplify, in dB
+    pub fn get_agc_max_gain(&self) -> i32 {
+        self.agc_max_gain
+    }
+
+    /// Sets how far residual echo is suppressed, in (negative) dB. The
+    /// default is -40.
+    pub fn set_echo_suppress(&mut self, db: i32) {
+        self.echo_suppress = db.min(0);
+    }
+
+    /// Gets how far residual echo is suppressed, in dB
+    pub fn get_echo_suppress(&self) -> i32 {
+        self.echo_suppress
+    }
+
+    /// Sets how far residual echo is suppressed while the near end talks, in
+    /// (negative) dB. The default is -15.
+    pub fn set_echo_suppress_active(&mut self, db: i32) {
+        self.echo_suppress_active = db.min(0);
+    }
+
+    /// Gets how far residual echo is suppressed while the near end talks, in
+    /// dB
+    pub fn get_echo_suppress_active(&self) -> i32 {
+        self.echo_suppress_active
+    }
+
+    /// Gets the gain the AGC applied to the last frame, in dB
+    pub fn get_agc_gain(&self) -> f32 {
+        20.0 * self.agc_gain.log10()
+    }
+
+    /// Gets the probability that the last frame held speech, from 0 to 1
+    pub fn get_speech_probability(&self) -> f32 {
+        self.speech_probability
+    }
+
+    /// Processes a frame in place, returning whether it holds speech.
+    ///
+    /// `frame` has to be exactly `frame_size` samples long.
+    pub fn run(&mut self, frame: &mut [f32]) -> Result<bool, PreprocessError> {
+        self.process(frame, None)
+    }
+
+    /// Processes a frame of i16 samples in place, returning whether it holds
+    /// speech.
+    ///
+    /// See `run` for details.
+    pub fn run_int(&mut self, frame: &mut [i16]) -> Result<bool, PreprocessError> {
+        self.process_int(frame, None)
+    }
+
+    /// Processes a frame that `echo` just output, suppressing the echo it
+    /// left over along with everything else, and returns whether it holds
+    /// speech.
+    ///
+    /// `frame` has to be exactly `frame_size` samples long, and `echo` has to
+    /// be for the same mode.
+    pub fn run_with_echo(
+        &mut self,
+        frame: &mut [f32],
+        echo: &EchoCanceller,
+    ) -> Result<bool, PreprocessError> {
+        self.process(frame, Some(echo))
+    }
+
+    /// Processes a frame of i16 samples that `echo` just output.
+    ///
+    /// See `run_with_echo` for details.
+    pub fn run_int_with_echo(
+        &mut self,
+        frame: &mut [i16],
+        echo: &EchoCanceller,
+    ) -> Result<bool, PreprocessError> {
+        self.process_int(frame, Some(echo))
+    }
+
+    fn process(
+        &mut self,
+        frame: &mut [f32],
+        echo: Option<&EchoCanceller>,
+    ) -> Result<bool, PreprocessError> {
+        if frame.len() != self.frame_size {
+            return Err(PreprocessError::WrongFrameSize {
+                expected: self.frame_size,
+                actual: frame.len(),
+            });
+        }
+        match echo {
+            Some(echo) => {
+                if echo.frame_size() != self.frame_size {
+                    return Err(PreprocessError::EchoMismatch {
+                        expected: self.frame_size,
+                        actual: echo.frame_size(),
+                    });
+                }
+                // Let the estimate fall away gradually
+                for (estimate, &residual) in self.echo.iter_mut().zip(echo.residual_echo()) {
+                    *estimate = (0.6 * *estimate).max(residual);
+                }
+            }
+            None => self.echo.fill(0.0),
+        }
+
+        self.input.copy_within(self.frame_size.., 0);
+        self.input[self.frame_size..].copy_from_slice(frame);
+
+        for (i, value) in self.spectrum.iter_mut().enumerate() {
+            let sample = self.input.get(i).map_or(0.0, |x| x * self.window[i]);
+            *value = (sample, 0.0);
+        }
+        self.fft.forward(&mut self.spectrum);
+        let gains = self.analyse();
+        let len = self.fft.len();
+        for (bin, &gain) in gains.iter().enumerate() {
+            let (re, im) = self.spectrum[bin];
+            self.spectrum[bin] = (re * gain, im * gain);
+            if bin > 0 && bin < len - bin {
+                let (re, im) = self.spectrum[len - bin];
+                self.spectrum[len - bin] = (re * gain, im * gain);
+            }
+        }
+        self.fft.inverse(&mut self.spectrum);
+
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = self.overlap[i] + self.spectrum[i].0 * self.window[i];
+            let j = i + self.frame_size;
+            self.overlap[i] = self.spectrum[j].0 * self.window[j];
+        }
+
+        if self.agc {
+            self.apply_agc(frame);
+        }
+        Ok(self.speech)
+    }
+
+    fn process_int(
+        &mut self,
+        frame: &mut [i16],
+        echo: Option<&EchoCanceller>,
+    ) -> Result<bool, PreprocessError> {
+        let mut samples: Vec<f32> = frame.iter().map(|&sample| sample as f32).collect();
+        let speech = self.process(&mut samples, echo)?;
+        for (sample, processed) in frame.iter_mut().zip(samples) {
+            *sample = i16::from_f32(processed);
+        }
+        Ok(speech)
+    }
+
+    /// Updates the noise estimate and speech detection from the spectrum of
+    /// the current window, and works out the gain for each bin.
+    fn analyse(&mut self) -> Vec<f32> {
+        let bins = self.noise.len();
+        let power: Vec<f32> = self.spectrum[..bins]
+            .iter()
+            .map(|(re, im)| re * re + im * im)
+            .collect();
+
+        self.frames += 1;
+        let startup = self.frames <= STARTUP_FRAMES;
+        let restart_minimum = self.frames.is_multiple_of(MIN_WINDOW_FRAMES);
+        for (bin, &power) in power.iter().enumerate() {
+            let smoothed = 0.7 * self.smoothed[bin] + 0.3 * power;
+            self.smoothed[bin] = smoothed;
+            if startup {
+                // Average everything heard so far to get going
+                let frames = self.frames as f32;
+                self.noise[bin] += (power - self.noise[bin]) / frames;
+                self.minimum[bin] = smoothed;
+                self.minimum_window[bin] = smoothed;
+                continue;
+            }
+            // Track the minimum over a sliding window, so the estimate can
+            // rise again when the noise gets louder
+            if restart_minimum {
+                self.minimum[bin] = self.minimum_window[bin].min(smoothed);
+                self.minimum_window[bin] = smoothed;
+            } else {
+                self.minimum[bin] = self.minimum[bin].min(smoothed);
+                self.minimum_window[bin] = self.minimum_window[bin].min(smoothed);
+            }
+            if smoothed < SPEECH_RATIO * self.minimum[bin] {
+                self.noise[bin] =
+                    NOISE_SMOOTHING * self.noise[bin] + (1.0 - NOISE_SMOOTHING) * power;
+            }
+        }
+
+        let echo_suppress = if self.speech {
+            self.echo_suppress_active
+        } else {
+            self.echo_suppress
+        } as f32;
+        let mut gains = vec![1.0; bins];
+        let mut likelihood = 0.0;
+        for (bin, &power) in power.iter().enumerate() {
+            let noise = self.noise[bin].max(1e-3);
+            let echo = self.echo[bin];
+            let mut interference = noise + echo;
+            if self.dereverb {
+                self.reverb[bin] =
+                    REVERB_DECAY * self.reverb[bin] + REVERB_LEVEL * self.previous_clean[bin];
+                interference += self.reverb[bin];
+            }
+            let posterior = (power / interference).min(1e4);
+            let prior = PRIOR_WEIGHT * self.previous_clean[bin] / interference
+                + (1.0 - PRIOR_WEIGHT) * (posterior - 1.0).max(0.0);
+            let gain = prior / (1.0 + prior);
+            likelihood += posterior * gain - (1.0 + prior).ln();
+
+            if self.denoise || self.dereverb || echo > 0.0 {
+                // Noise and echo each have their own floor, mixed by how much
+                // of each there is
+                let floor_db =
+                    (self.noise_suppress as f32 * noise + echo_suppress * echo) / (noise + echo);
+                gains[bin] = gain.max(10f32.powf(floor_db / 20.0));
+            }
+            self.previous_clean[bin] = gain * gain * power;
+        }
+
+        let likelihood = (likelihood / bins as f32).max(0.0);
+        self.speech_probability = if startup {
+            0.0
+        } else {
+            1.0 - (-likelihood).exp()
+        };
+        let threshold = if self.speech {
+            VAD_PROB_CONTINUE
+        } else {
+            VAD_PROB_START
+        };
+        self.speech = self.speech_probability > threshold;
+        gains
+    }
+
+    /// Moves the gain towards bringing speech to the target level, within
+    /// the rate limits, and applies it
+    fn apply_agc(&mut self, frame: &mut [f32]) {
+        let energy = frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32;
+        if self.speech {
+            if self.loudness == 0.0 {
+                self.loudness = energy;
+            }
+            self.loudness = 0.9 * self.loudness + 0.1 * energy;
+        }
+
+        let frame_seconds = self.frame_size as f32 / self.sample_rate as f32;
+        let max_gain = 10f32.powf(self.agc_max_gain as f32 / 20.0);
+        let target = if self.loudness > 0.0 {
+            (self.agc_level / self.loudness.sqrt()).min(max_gain)
+        } else {
+            self.agc_gain
+        };
+        let max_step_up = 10f32.powf(AGC_MAX_INCREASE_DB * frame_seconds / 20.0);
+        let max_step_down = 10f32.powf(-AGC_MAX_DECREASE_DB * frame_seconds / 20.0);
+        let previous = self.agc_gain;
+        self.agc_gain = target.clamp(previous * max_step_down, previous * max_step_up);
+
+        // Ramp across the frame to avoid steps
+        let step = (self.agc_gain - previous) / frame.len() as f32;
+        for (i, sample) in frame.iter_mut().enumerate() {
+            let gain = previous + step * (i + 1) as f32;
+            *sample = (*sample * gain).clamp(-32767.0, 32767.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{energy, noise};
+    use crate::{NbMode, SpeexBits, SpeexEncoder};
+
+    fn tone(len: usize, level: f32) -> Vec<f32> {
+        (0..len)
+            .map(|i| (i as f32 * 2.0 * PI * 440.0 / 8000.0).sin() * level)
+            .collect()
+    }
+
+    /// Runs the signal through in frames, returning the output and which
+    /// frames held speech
+    fn process(preprocessor: &mut Preprocessor, input: &[f32]) -> (Vec<f32>, Vec<bool>) {
+        let mut output = input.to_vec();
+        let speech = output
+            .chunks_exact_mut(preprocessor.frame_size())
+            .map(|frame| preprocessor.run(frame).unwrap())
+            .collect();
+        (output, speech)
+    }
+
+    #[test]
+    fn suppresses_noise_and_keeps_speech() {
+        let mut seed = 1;
+        let mut input = noise(8000 * 3, 1000.0, &mut seed);
+        // A tone from 1.5 to 2.5 seconds
+        for (sample, tone) in input[12000..20000].iter_mut().zip(tone(8000, 8000.0)) {
+            *sample += tone;
+        }
+        let mut preprocessor = Preprocessor::new(ModeId::NarrowBand);
+        let (output, speech) = process(&mut preprocessor, &input);
+
+        // Output is a frame behind
+        let noise_before = energy(&input[4000..11000]);
+        let noise_after = energy(&output[4160..11160]);
+        let reduction = 10.0 * (noise_before / noise_after).log10();
+        assert!(reduction > 10.0, "noise only down {reduction} dB");
+
+        let tone_before = energy(&input[14000..19000]);
+        let tone_after = energy(&output[14160..19160]);
+        assert!((tone_after / tone_before) > 0.8);
+
+        assert!(speech[80..120].iter().filter(|&&speech| speech).count() > 35);
+        assert!(speech[30..70].iter().all(|&speech| !speech));
+        assert!(speech[135..].iter().filter(|&&speech| speech).count() < 3);
+    }
+
+    #[test]
+    fn brings_speech_to_agc_level() {
+        let mut preprocessor = Preprocessor::new(ModeId::NarrowBand);
+        preprocessor.set_denoise(false);
+        preprocessor.set_agc(true);
+        preprocessor.set_agc_level(4000.0);
+        let mut seed = 7;
+        let mut input = noise(8000 * 8, 10.0, &mut seed);
+        for (sample, tone) in input[8000..].iter_mut().zip(tone(8000 * 7, 500.0)) {
+            *sample += tone;
+        }
+        let (output, _) = process(&mut preprocessor, &input);
+
+        let level = energy(&output[8000 * 7..]).sqrt();
+        assert!((3500.0..4500.0).contains(&level), "level {level}");
+        assert!((preprocessor.get_agc_gain() - 21.0).abs() < 1.5);
+
+        // The gain can't jump more than the rate limit allows
+        let quiet = energy(&output[8000..8000 + 160]).sqrt();
+        assert!(quiet < 500.0 * 1.2, "jumped to {quiet}");
+    }
+
+    #[test]
+    fn dereverb_shortens_tails() {
+        let mut seed = 3;
+        let background = noise(8000 * 3, 50.0, &mut seed);
+        let mut input = background.clone();
+        // Bursts of tone, each followed by an exponentially decaying tail
+        for start in (8000..24000).step_by(4000) {
+            let burst = noise(4000, 8000.0, &mut seed);
+            for (i, sample) in input[start..start + 4000].iter_mut().enumerate() {
+                let envelope = if i < 800 {
+                    1.0
+                } else {
+                    (-(i as f32 - 800.0) / 600.0).exp()
+                };
+                *sample += burst[i] * envelope;
+            }
+        }
+
+        let tail = |dereverb: bool| {
+            let mut preprocessor = Preprocessor::new(ModeId::NarrowBand);
+            preprocessor.set_denoise(false);
+            preprocessor.set_dereverb(dereverb);
+            let (output, _) = process(&mut preprocessor, &input);
+            (8000..24000)
+                .step_by(4000)
+                .map(|start| energy(&output[start + 1200..start + 2400]))
+                .sum::<f32>()
+        };
+
+        assert!(tail(true) < tail(false) * 0.7);
+    }
+
+    #[test]
+    fn feeds_encoder() {
+        let mut preprocessor = Preprocessor::new(ModeId::NarrowBand);
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        let mut bits = SpeexBits::new();
+        let mut frame = vec![0i16; preprocessor.frame_size()];
+        for chunk in tone(1600, 4000.0).chunks(160) {
+            for (sample, &tone) in frame.iter_mut().zip(chunk) {
+                *sample = tone as i16;
+            }
+            preprocessor.run_int(&mut frame).unwrap();
+            encoder.encode_int(&frame, &mut bits).unwrap();
+        }
+
+        assert!(bits.num_bytes() > 0);
+    }
+
+    #[test]
+    fn rejects_wrong_frame_size() {
+        let mut preprocessor = Preprocessor::new(ModeId::WideBand);
+
+        assert_eq!(
+            preprocessor.run(&mut [0.0; 160]),
+            Err(PreprocessError::WrongFrameSize {
+                expected: 320,
+                actual: 160
+            })
+        );
+        assert_eq!(
+            preprocessor.run_int(&mut [0; 321]),
+            Err(PreprocessError::WrongFrameSize {
+                expected: 320,
+                actual: 321
+            })
+        );
+    }
+}
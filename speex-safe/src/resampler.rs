@@ -0,0 +1,507 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use std::f64::consts::PI;
+
+use crate::ControlError;
+
+/// Half the filter length in input samples, for each quality level
+const HALF_LENGTHS: [usize; 11] = [4, 8, 16, 24, 32, 40, 48, 64, 80, 96, 128];
+
+/// Filter cutoff relative to the Nyquist frequency, for each quality level
+const CUTOFFS: [f64; 11] = [
+    0.800, 0.860, 0.880, 0.895, 0.920, 0.922, 0.940, 0.940, 0.945, 0.950, 0.975,
+];
+
+/// Kaiser window shape, for each quality level. Higher values trade a wider
+/// transition band for more stopband attenuation.
+const KAISER_BETAS: [f64; 11] = [5.0, 6.0, 6.0, 8.0, 8.0, 8.0, 10.0, 10.0, 10.0, 12.0, 12.0];
+
+/// Largest filter bank that is computed up front, in coefficients. Rates with
+/// very many phases compute their coefficients as they go instead.
+const MAX_TABLE_SIZE: usize = 1 << 20;
+
+/// Sample formats the resampler can convert between
+pub(crate) trait Sample: Copy + Default {
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+impl Sample for i16 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(value: f32) -> Self {
+        // Float to int casts saturate, which clips anything out of range
+        value.round() as i16
+    }
+}
+
+/// Converts audio between sample rates, with a polyphase windowed sinc
+/// filter.
+///
+/// This is used to feed speex from audio at rates other than 8, 16 or 32 kHz,
+/// and to play it back at them. Input of any length can be given, interleaved
+/// if there is more than one channel. Input that ends partway through a frame
+/// of samples is held back until the rest of the frame arrives. Output is
+/// aligned with the input: the first output sample is at the time of the first
+/// input sample, and `flush` produces the rest once there is no more input.
+///
+/// The quality goes from 0 to 10, and sets the filter length and cutoff, in
+/// the same way as speexdsp's resampler. 3 is a good default for speech,
+/// higher levels cost more CPU for less aliasing.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    in_rate: u32,
+    out_rate: u32,
+    channels: usize,
+    quality: u32,
+    /// Output samples per `down` input samples, the ratio in lowest terms
+    up: u64,
+    down: u64,
+    half_length: usize,
+    cutoff: f64,
+    beta: f64,
+    /// Filter coefficients for each of the `up` phases, if computed up front
+    table: Option<Vec<f32>>,
+    /// Input not needed anymore is dropped, per channel
+    history: Vec<Vec<f32>>,
+    /// Index of the first sample in `history`
+    history_start: u64,
+    /// Samples of a frame only partly given so far, interleaved
+    partial: Vec<f32>,
+    input_len: u64,
+    output_len: u64,
+}
+
+impl Resampler {
+    /// Quality level suited to speech, as used by speexdsp for VoIP
+    pub const QUALITY_DEFAULT: u32 = 3;
+    /// Highest quality level
+    pub const QUALITY_MAX: u32 = 10;
+
+    /// Creates a resampler from `in_rate` to `out_rate`.
+    ///
+    /// Returns `ControlError::InvalidParameter` if either rate or `channels`
+    /// is 0, or `quality` is above `QUALITY_MAX`.
+    pub fn new(
+        in_rate: u32,
+        out_rate: u32,
+        channels: usize,
+        quality: u32,
+    ) -> Result<Self, ControlError> {
+        Self::check_parameters(in_rate, out_rate, channels, quality)?;
+
+        let divisor = gcd(in_rate as u64, out_rate as u64);
+        let up = out_rate as u64 / divisor;
+        let down = in_rate as u64 / divisor;
+        let quality_index = quality as usize;
+        let mut half_length = HALF_LENGTHS[quality_index];
+        let mut cutoff = CUTOFFS[quality_index];
+        if down > up {
+            // Filter out everything above the new Nyquist frequency, with a
+            // filter long enough to keep the same transition band
+            cutoff *= up as f64 / down as f64;
+            half_length = (half_length as u64 * down).div_ceil(up) as usize;
+        }
+
+        let mut resampler = Self {
+            in_rate,
+            out_rate,
+            channels,
+            quality,
+            up,
+            down,
+            half_length,
+            cutoff,
+            beta: KAISER_BETAS[quality_index],
+            table: None,
+            history: vec![Vec::new(); channels],
+            history_start: 0,
+            partial: Vec::new(),
+            input_len: 0,
+            output_len: 0,
+        };
+        let table_size = up as usize * half_length * 2;
+        if table_size <= MAX_TABLE_SIZE {
+            let mut table = vec![0.0; table_size];
+            for (phase, filter) in table.chunks_mut(half_length * 2).enumerate() {
+                resampler.fill_filter(phase as u64, filter);
+            }
+            resampler.table = Some(table);
+        }
+        Ok(resampler)
+    }
+
+    /// Checks the parameters `new` would be given, for callers that create
+    /// the resampler later on
+    pub(crate) fn check_parameters(
+        in_rate: u32,
+        out_rate: u32,
+        channels: usize,
+        quality: u32,
+    ) -> Result<(), ControlError> {
+        if in_rate == 0 || out_rate == 0 || channels == 0 || quality > Self::QUALITY_MAX {
+            return Err(ControlError::InvalidParameter);
+        }
+        Ok(())
+    }
+
+    /// Gets the rate of the input
+    pub fn in_rate(&self) -> u32 {
+        self.in_rate
+    }
+
+    /// Gets the rate of the output
+    pub fn out_rate(&self) -> u32 {
+        self.out_rate
+    }
+
+    /// Gets the number of interleaved channels
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Gets the quality level
+    pub fn quality(&self) -> u32 {
+        self.quality
+    }
+
+    /// Resamples `input`, appending whatever output it completes to `out`.
+    ///
+    /// Returns the number of samples (per channel) appended.
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) -> usize {
+        self.partial.extend_from_slice(input);
+        let whole = self.partial.len() - self.partial.len() % self.channels;
+        for frame in self.partial[..whole].chunks_exact(self.channels) {
+            for (history, &sample) in self.history.iter_mut().zip(frame) {
+                history.push(sample);
+            }
+        }
+        self.partial.drain(..whole);
+        self.input_len += (whole / self.channels) as u64;
+        self.produce(self.input_len, out)
+    }
+
+    /// Resamples integer `input`, appending whatever output it completes to
+    /// `out`.
+    ///
+    /// See `process` for details.
+    pub fn process_int(&mut self, input: &[i16], out: &mut Vec<i16>) -> usize {
+        self.process_with(input, out, Self::process)
+    }
+
+    /// Produces the output still owed for the input so far, treating
+    /// anything after it as silence, then starts over. A partly given frame
+    /// is dropped.
+    ///
+    /// Returns the number of samples (per channel) appended.
+    pub fn flush(&mut self, out: &mut Vec<f32>) -> usize {
+        let end = self.input_len + self.half_length as u64;
+        let total = (self.input_len * self.up).div_ceil(self.down);
+        let produced = self.produce_until(end, total, out);
+        self.reset();
+        produced
+    }
+
+    /// Produces the output still owed for integer input.
+    ///
+    /// See `flush` for details.
+    pub fn flush_int(&mut self, out: &mut Vec<i16>) -> usize {
+        let mut output = Vec::new();
+        let produced = self.flush(&mut output);
+        out.extend(output.into_iter().map(i16::from_f32));
+        produced
+    }
+
+    /// Drops all buffered input, so the next input starts a new stream
+    pub fn reset(&mut self) {
+        for history in &mut self.history {
+            history.clear();
+        }
+        self.history_start = 0;
+        self.partial.clear();
+        self.input_len = 0;
+        self.output_len = 0;
+    }
+
+    pub(crate) fn process_with<S: Sample>(
+        &mut self,
+        input: &[S],
+        out: &mut Vec<S>,
+        process: fn(&mut Self, &[f32], &mut Vec<f32>) -> usize,
+    ) -> usize {
+        let input: Vec<f32> = input.iter().map(|&sample| sample.to_f32()).collect();
+        let mut output = Vec::new();
+        let produced = process(self, &input, &mut output);
+        out.extend(output.into_iter().map(S::from_f32));
+        produced
+    }
+
+    /// Produces every output sample whose filter only reaches input before
+    /// `available`
+    fn produce(&mut self, available: u64, out: &mut Vec<f32>) -> usize {
+        self.produce_until(available, u64::MAX, out)
+    }
+
+    fn produce_until(&mut self, available: u64, total: u64, out: &mut Vec<f32>) -> usize {
+        let taps = self.half_length * 2;
+        let mut filter = vec![0.0; taps];
+        let mut produced = 0;
+        while self.output_len < total {
+            let position = self.output_len * self.down;
+            let center = position / self.up;
+            if center + self.half_length as u64 >= available {
+                break;
+            }
+            let phase = position % self.up;
+            let filter = match &self.table {
+                Some(table) => &table[phase as usize * taps..(phase as usize + 1) * taps],
+                None => {
+                    self.fill_filter(phase, &mut filter);
+                    &filter
+                }
+            };
+
+            // The filter covers input from `center - half_length + 1` to
+            // `center + half_length`, with silence outside the stream
+            let first = center as i64 + 1 - self.half_length as i64;
+            for history in &self.history {
+                let mut sum = 0.0;
+                for (tap, &coefficient) in filter.iter().enumerate() {
+                    let index = first + tap as i64;
+                    if index < self.history_start as i64 {
+                        continue;
+                    }
+                    if let Some(&sample) = history.get((index - self.history_start as i64) as usize)
+                    {
+                        sum += sample * coefficient;
+                    }
+                }
+                out.push(sum);
+            }
+            self.output_len += 1;
+            produced += 1;
+        }
+
+        // Drop input that no later output needs
+        let next_center = self.output_len * self.down / self.up;
+        let keep_from = (next_center + 1).saturating_sub(self.half_length as u64);
+        if keep_from > self.history_start {
+            let drop = ((keep_from - self.history_start) as usize).min(self.history[0].len());
+            for history in &mut self.history {
+                history.drain(..drop);
+            }
+            self.history_start += drop as u64;
+        }
+        produced
+    }
+
+    /// Computes the filter for an output `phase / up` of the way between two
+    /// input samples, normalised so a constant signal passes unchanged
+    fn fill_filter(&self, phase: u64, filter: &mut [f32]) {
+        let offset = phase as f64 / self.up as f64;
+        let half_length = self.half_length as f64;
+        let mut coefficients = vec![0.0; filter.len()];
+        for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+            // Distance from the output to this input sample, in input samples
+            let distance = tap as f64 + 1.0 - half_length - offset;
+            *coefficient = self.cutoff
+                * sinc(self.cutoff * distance)
+                * kaiser(distance / half_length, self.beta);
+        }
+        let sum: f64 = coefficients.iter().sum();
+        for (value, coefficient) in filter.iter_mut().zip(coefficients) {
+            *value = (coefficient / sum) as f32;
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Kaiser window at `x` between -1 and 1
+fn kaiser(x: f64, beta: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    bessel_i0(beta * (1.0 - x * x).sqrt()) / bessel_i0(beta)
+}
+
+/// Modified Bessel function of the first kind, order 0
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= half / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Logarithmic sine sweep from 100 Hz to `end` Hz over `seconds`, sampled
+    /// at `rate`
+    fn sweep(rate: u32, end: f64, seconds: f64) -> Vec<f32> {
+        let len = (rate as f64 * seconds) as usize;
+        let ratio = (end / 100.0).ln();
+        (0..len)
+            .map(|i| {
+                let t = i as f64 / rate as f64;
+                let phase =
+                    2.0 * PI * 100.0 * seconds / ratio * ((t / seconds * ratio).exp() - 1.0);
+                (phase.sin() * 10000.0) as f32
+            })
+            .collect()
+    }
+
+    /// Signal to noise ratio in dB, leaving out the edges of the sweep
+    fn snr(output: &[f32], reference: &[f32]) -> f64 {
+        let edge = reference.len() / 20;
+        let range = edge..reference.len() - edge;
+        let signal: f64 = reference[range.clone()]
+            .iter()
+            .map(|&s| (s as f64).powi(2))
+            .sum();
+        let noise: f64 = output[range.clone()]
+            .iter()
+            .zip(&reference[range])
+            .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+            .sum();
+        10.0 * (signal / noise).log10()
+    }
+
+    fn resample(in_rate: u32, out_rate: u32, quality: u32, input: &[f32]) -> Vec<f32> {
+        let mut resampler = Resampler::new(in_rate, out_rate, 1, quality).unwrap();
+        let mut out = Vec::new();
+        resampler.process(input, &mut out);
+        resampler.flush(&mut out);
+        out
+    }
+
+    #[test]
+    fn matches_reference_sweep() {
+        // Sweeps stay below the cutoff of the lower rate at every quality
+        for (in_rate, out_rate) in [(48000, 8000), (44100, 16000), (8000, 48000), (16000, 44100)] {
+            let end = in_rate.min(out_rate) as f64 * 0.35;
+            let input = sweep(in_rate, end, 1.0);
+            let reference = sweep(out_rate, end, 1.0);
+
+            let low = snr(&resample(in_rate, out_rate, 0, &input), &reference);
+            let default = snr(
+                &resample(in_rate, out_rate, Resampler::QUALITY_DEFAULT, &input),
+                &reference,
+            );
+            let high = snr(&resample(in_rate, out_rate, 10, &input), &reference);
+
+            assert!(low > 30.0, "{in_rate} -> {out_rate}: {low} dB at quality 0");
+            assert!(
+                default > 80.0,
+                "{in_rate} -> {out_rate}: {default} dB at quality 3"
+            );
+            assert!(
+                high > 110.0,
+                "{in_rate} -> {out_rate}: {high} dB at quality 10"
+            );
+            assert!(low < default && default < high);
+        }
+    }
+
+    #[test]
+    fn removes_aliases_when_downsampling() {
+        // 6 kHz is above the Nyquist frequency of 8 kHz audio
+        let input: Vec<f32> = (0..48000)
+            .map(|i| (2.0 * PI * 6000.0 * i as f64 / 48000.0).sin() as f32 * 10000.0)
+            .collect();
+        let out = resample(48000, 8000, 5, &input);
+
+        let level = out[800..7200].iter().map(|s| s.abs()).fold(0.0, f32::max);
+        assert!(level < 10.0, "alias at {level}");
+    }
+
+    #[test]
+    fn streams_in_chunks() {
+        let input: Vec<f32> = sweep(44100, 4000.0, 0.2)
+            .chunks(2)
+            .flat_map(|pair| [pair[0], -pair[0]])
+            .collect();
+        let mut whole = Resampler::new(44100, 32000, 2, 4).unwrap();
+        let mut expected = Vec::new();
+        whole.process(&input, &mut expected);
+        whole.flush(&mut expected);
+
+        let mut chunked = Resampler::new(44100, 32000, 2, 4).unwrap();
+        let mut out = Vec::new();
+        // Chunks that split frames between the channels
+        for chunk in input.chunks(37) {
+            chunked.process(chunk, &mut out);
+        }
+        chunked.flush(&mut out);
+
+        assert_eq!(out, expected);
+        assert_eq!(out.len(), 2 * (input.len() / 2 * 320).div_ceil(441));
+        assert!(out.chunks(2).all(|pair| pair[0] == -pair[1]));
+    }
+
+    #[test]
+    fn handles_awkward_ratios() {
+        let input = vec![1000.0; 4800];
+        let mut resampler = Resampler::new(48000, 47999, 1, 2).unwrap();
+        assert!(resampler.table.is_none());
+        let mut out = Vec::new();
+        resampler.process(&input, &mut out);
+
+        assert!(out[100..]
+            .iter()
+            .all(|&sample| (sample - 1000.0).abs() < 0.1));
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        for (in_rate, out_rate, channels, quality) in [
+            (0, 8000, 1, 3),
+            (8000, 0, 1, 3),
+            (8000, 16000, 0, 3),
+            (8000, 16000, 1, Resampler::QUALITY_MAX + 1),
+        ] {
+            assert_eq!(
+                Resampler::new(in_rate, out_rate, channels, quality).err(),
+                Some(ControlError::InvalidParameter)
+            );
+        }
+    }
+}
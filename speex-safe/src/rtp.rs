@@ -0,0 +1,409 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+//! Carrying speex over RTP, as described by RFC 5574.
+//!
+//! The payload of a speex RTP packet is one or more frames exactly as the
+//! encoder wrote them, padded to a whole number of bytes with a 0 bit followed
+//! by 1 bits. That padding is the same as the terminator `SpeexBits` inserts,
+//! so packets from `StreamEncoder` can be sent as they are, and received
+//! payloads can be given straight to `SpeexDecoder::decode_packet`, which
+//! stops at the padding.
+
+use std::fmt::{Display, Formatter};
+
+use crate::{random_u64, ModeId, SpeexBits};
+
+/// The RTP version every packet must have
+const RTP_VERSION: u8 = 2;
+
+/// Size of the fixed part of the RTP header
+const HEADER_SIZE: usize = 12;
+
+const FLAG_PADDING: u8 = 0x20;
+const FLAG_EXTENSION: u8 = 0x10;
+const FLAG_MARKER: u8 = 0x80;
+
+/// Error type for parsing and writing RTP packets
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RtpError {
+    /// The packet is shorter than its header says it is
+    Truncated,
+    /// The packet is not RTP version 2
+    /// The parameter is the version that was found
+    UnsupportedVersion(u8),
+    /// The padding count is 0, or more than the payload holds
+    BadPadding,
+    /// The packet has more than the 15 CSRCs the header can count
+    /// The parameter is the number of CSRCs
+    TooManyCsrcs(usize),
+    /// The payload type doesn't fit in 7 bits
+    /// The parameter is the payload type
+    InvalidPayloadType(u8),
+}
+
+impl Display for RtpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RtpError::Truncated => write!(f, "RTP packet is truncated"),
+            RtpError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported RTP version ({version})")
+            }
+            RtpError::BadPadding => write!(f, "RTP packet has invalid padding"),
+            RtpError::TooManyCsrcs(count) => {
+                write!(f, "RTP packet has {count} CSRCs, more than 15")
+            }
+            RtpError::InvalidPayloadType(payload_type) => {
+                write!(f, "Invalid RTP payload type ({payload_type})")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RtpError {}
+
+/// A single RTP packet.
+///
+/// Header extensions are skipped when parsing and never written, as speex
+/// doesn't define any. RTP-level padding is removed when parsing, so
+/// `payload` only holds the speex frames.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RtpPacket {
+    /// Payload type, usually negotiated dynamically through SDP
+    pub payload_type: u8,
+    /// Set on the first packet of a talkspurt
+    pub marker: bool,
+    /// Goes up by one for every packet sent, wrapping around
+    pub sequence: u16,
+    /// Sampling instant of the first frame in the packet
+    pub timestamp: u32,
+    /// Identifies the stream the packet belongs to
+    pub ssrc: u32,
+    /// Sources mixed into the packet, at most 15
+    pub csrc: Vec<u32>,
+    /// The speex frames, padded to a whole byte
+    pub payload: Vec<u8>,
+}
+
+impl RtpPacket {
+    /// Parses a packet as received from the network.
+    pub fn parse(bytes: &[u8]) -> Result<Self, RtpError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(RtpError::Truncated);
+        }
+        let version = bytes[0] >> 6;
+        if version != RTP_VERSION {
+            return Err(RtpError::UnsupportedVersion(version));
+        }
+        let csrc_count = (bytes[0] & 0x0F) as usize;
+        let payload_type = bytes[1] & 0x7F;
+        let marker = bytes[1] & FLAG_MARKER != 0;
+        let sequence = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let timestamp = read_u32(bytes, 4);
+        let ssrc = read_u32(bytes, 8);
+
+        let mut offset = HEADER_SIZE + csrc_count * 4;
+        if bytes.len() < offset {
+            return Err(RtpError::Truncated);
+        }
+        let csrc = (0..csrc_count)
+            .map(|i| read_u32(bytes, HEADER_SIZE + i * 4))
+            .collect();
+
+        if bytes[0] & FLAG_EXTENSION != 0 {
+            if bytes.len() < offset + 4 {
+                return Err(RtpError::Truncated);
+            }
+            let words = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+            offset += 4 + words * 4;
+            if bytes.len() < offset {
+                return Err(RtpError::Truncated);
+            }
+        }
+
+        let mut end = bytes.len();
+        if bytes[0] & FLAG_PADDING != 0 {
+            // The last byte counts the padding bytes, including itself
+            let padding = bytes[end - 1] as usize;
+            if padding == 0 || padding > end - offset {
+                return Err(RtpError::BadPadding);
+            }
+            end -= padding;
+        }
+
+        Ok(Self {
+            payload_type,
+            marker,
+            sequence,
+            timestamp,
+            ssrc,
+            csrc,
+            payload: bytes[offset..end].to_vec(),
+        })
+    }
+
+    /// Serialises the packet, ready to be sent.
+    ///
+    /// Fails if there are more than 15 CSRCs, or the payload type doesn't fit
+    /// in 7 bits.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RtpError> {
+        if self.csrc.len() > 15 {
+            return Err(RtpError::TooManyCsrcs(self.csrc.len()));
+        }
+        if self.payload_type > 0x7F {
+            return Err(RtpError::InvalidPayloadType(self.payload_type));
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.csrc.len() * 4 + self.payload.len());
+        bytes.push(RTP_VERSION << 6 | self.csrc.len() as u8);
+        let marker = if self.marker { FLAG_MARKER } else { 0 };
+        bytes.push(marker | self.payload_type);
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.ssrc.to_be_bytes());
+        for csrc in &self.csrc {
+            bytes.extend_from_slice(&csrc.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+}
+
+/// Builds the RTP packets for a single speex stream.
+///
+/// The sequence number goes up by one for every packet, and the timestamp by
+/// the number of samples in it, which is the frame size of the mode for each
+/// frame. The RTP clock rate is the sample rate of the mode (8, 16 or 32 kHz).
+///
+/// The marker bit is set on the first packet, and on the first one after
+/// frames were skipped, e.g. because DTX had nothing to send.
+pub struct RtpPacketizer {
+    payload_type: u8,
+    ssrc: u32,
+    frame_size: u32,
+    sequence: u16,
+    timestamp: u32,
+    talkspurt: bool,
+}
+
+impl RtpPacketizer {
+    /// Creates a packetizer starting from a random sequence number and
+    /// timestamp, as RFC 3550 recommends.
+    pub fn new(mode: ModeId, payload_type: u8, ssrc: u32) -> Self {
+        let random = random_u64();
+        Self::with_start(
+            mode,
+            payload_type,
+            ssrc,
+            random as u16,
+            (random >> 32) as u32,
+        )
+    }
+
+    /// Creates a packetizer starting from the given sequence number and
+    /// timestamp.
+    pub fn with_start(
+        mode: ModeId,
+        payload_type: u8,
+        ssrc: u32,
+        sequence: u16,
+        timestamp: u32,
+    ) -> Self {
+        Self {
+            payload_type,
+            ssrc,
+            frame_size: mode.get_frame_size() as u32,
+            sequence,
+            timestamp,
+            talkspurt: true,
+        }
+    }
+
+    /// Gets the SSRC of the stream
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Gets the sequence number the next packet will have
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Gets the timestamp the next packet will have
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Wraps an encoded payload holding `frames` frames into a packet.
+    ///
+    /// The payload must already be padded to a whole byte, as the packets
+    /// from `StreamEncoder` are.
+    pub fn packetize(&mut self, payload: &[u8], frames: u32) -> RtpPacket {
+        let packet = RtpPacket {
+            payload_type: self.payload_type,
+            marker: self.talkspurt,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            ssrc: self.ssrc,
+            csrc: Vec::new(),
+            payload: payload.to_vec(),
+        };
+        self.talkspurt = false;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self
+            .timestamp
+            .wrapping_add(frames.wrapping_mul(self.frame_size));
+        packet
+    }
+
+    /// Wraps the `frames` frames encoded into `bits` into a packet, padding
+    /// them as RFC 5574 requires. The bits are reset afterwards.
+    pub fn packetize_bits(&mut self, bits: &mut SpeexBits, frames: u32) -> RtpPacket {
+        let payload = bits.take_packet();
+        self.packetize(&payload, frames)
+    }
+
+    /// Skips over `frames` frames that won't be sent, so the timestamp keeps
+    /// up with the audio. The next packet starts a new talkspurt.
+    pub fn skip(&mut self, frames: u32) {
+        self.timestamp = self
+            .timestamp
+            .wrapping_add(frames.wrapping_mul(self.frame_size));
+        self.talkspurt = true;
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DynamicDecoder, DynamicEncoder, StreamEncoder};
+
+    #[test]
+    fn packet_round_trips() {
+        let packet = RtpPacket {
+            payload_type: 97,
+            marker: true,
+            sequence: 0xFFFE,
+            timestamp: 0xDEAD_BEEF,
+            ssrc: 42,
+            csrc: vec![1, 2],
+            payload: vec![9, 8, 7],
+        };
+        let bytes = packet.to_bytes().unwrap();
+
+        assert_eq!(&bytes[..2], &[0x82, 0x80 | 97]);
+        assert_eq!(bytes.len(), 12 + 8 + 3);
+        assert_eq!(RtpPacket::parse(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_unwritable_headers() {
+        let mut packet = RtpPacket {
+            payload_type: 0x80,
+            marker: false,
+            sequence: 0,
+            timestamp: 0,
+            ssrc: 0,
+            csrc: Vec::new(),
+            payload: Vec::new(),
+        };
+        assert_eq!(packet.to_bytes(), Err(RtpError::InvalidPayloadType(0x80)));
+
+        packet.payload_type = 96;
+        packet.csrc = vec![0; 16];
+        assert_eq!(packet.to_bytes(), Err(RtpError::TooManyCsrcs(16)));
+    }
+
+    #[test]
+    fn strips_padding_and_extensions() {
+        let mut bytes = RtpPacket {
+            payload_type: 96,
+            marker: false,
+            sequence: 1,
+            timestamp: 160,
+            ssrc: 7,
+            csrc: Vec::new(),
+            payload: Vec::new(),
+        }
+        .to_bytes()
+        .unwrap();
+        bytes[0] |= FLAG_PADDING | FLAG_EXTENSION;
+        bytes.extend_from_slice(&[0xBE, 0xDE, 0, 1, 1, 2, 3, 4]);
+        bytes.extend_from_slice(&[5, 6, 0, 0, 3]);
+
+        let packet = RtpPacket::parse(&bytes).unwrap();
+        assert_eq!(packet.payload, vec![5, 6]);
+
+        *bytes.last_mut().unwrap() = 6;
+        assert_eq!(RtpPacket::parse(&bytes), Err(RtpError::BadPadding));
+        assert_eq!(RtpPacket::parse(&bytes[..10]), Err(RtpError::Truncated));
+        bytes[0] &= 0x3F;
+        assert_eq!(
+            RtpPacket::parse(&bytes),
+            Err(RtpError::UnsupportedVersion(0))
+        );
+    }
+
+    #[test]
+    fn advances_by_frame_size() {
+        let mut packetizer = RtpPacketizer::with_start(ModeId::WideBand, 96, 5, u16::MAX, 1000);
+
+        let first = packetizer.packetize(&[1], 2);
+        let second = packetizer.packetize(&[2], 2);
+        packetizer.skip(3);
+        let third = packetizer.packetize(&[3], 1);
+
+        assert_eq!(
+            [first.sequence, second.sequence, third.sequence],
+            [u16::MAX, 0, 1]
+        );
+        assert_eq!(
+            [first.timestamp, second.timestamp, third.timestamp],
+            [1000, 1640, 2280 + 960]
+        );
+        assert_eq!(
+            [first.marker, second.marker, third.marker],
+            [true, false, true]
+        );
+        assert_eq!(packetizer.timestamp(), 2280 + 960 + 320);
+    }
+
+    #[test]
+    fn carries_frames_to_decoder() {
+        let mut encoder = DynamicEncoder::new(ModeId::NarrowBand);
+        let mut bits = SpeexBits::new();
+        let input: Vec<f32> = (0..160).map(|i| (i as f32 * 0.1).sin() * 5000.0).collect();
+        encoder.encode(&input, &mut bits).unwrap();
+        encoder.encode(&input, &mut bits).unwrap();
+        let mut packetizer = RtpPacketizer::new(ModeId::NarrowBand, 97, 1);
+        let bytes = packetizer.packetize_bits(&mut bits, 2).to_bytes().unwrap();
+
+        let mut stream = StreamEncoder::new(DynamicEncoder::new(ModeId::NarrowBand), 3).unwrap();
+        let payload = stream
+            .push(&[input.clone(), input.clone(), input].concat())
+            .unwrap();
+        let streamed = packetizer.packetize(&payload[0], 3).to_bytes().unwrap();
+
+        let mut decoder = DynamicDecoder::new(ModeId::NarrowBand);
+        let mut out = Vec::new();
+        let packet = RtpPacket::parse(&bytes).unwrap();
+        assert_eq!(
+            decoder.decode_packet(&packet.payload, &mut out, None),
+            Ok(2)
+        );
+        let packet = RtpPacket::parse(&streamed).unwrap();
+        assert_eq!(
+            decoder.decode_packet(&packet.payload, &mut out, None),
+            Ok(3)
+        );
+        assert_eq!(out.len(), 160 * 5);
+    }
+}
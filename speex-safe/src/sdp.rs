@@ -0,0 +1,512 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+//! Describing speex RTP sessions in SDP, as described by RFC 5574.
+//!
+//! Only the attributes speex uses are handled: `a=rtpmap`, `a=fmtp`,
+//! `a=ptime` and `a=maxptime`. The parameters describe what the agent that
+//! sent them wants to receive, so the ones from the other side of a call
+//! configure the local encoder.
+//!
+//! Modes are written as the quoted list RFC 5574 specifies (`mode="4,any"`),
+//! and read in that form or as the repeated parameter of earlier drafts
+//! (`mode=4;mode=any`).
+
+use std::fmt::{Display, Formatter};
+
+use crate::{DynamicDecoder, DynamicEncoder, Error, ModeId};
+
+/// Length of a speex frame in milliseconds, in every mode
+const FRAME_MS: u32 = 20;
+
+/// Speex quality to use for each narrowband mode, from the table in RFC 5574.
+/// Index 0 is unused, as narrowband modes start at 1.
+const NB_MODE_QUALITY: [i32; 9] = [0, 0, 2, 3, 5, 7, 9, 10, 1];
+
+/// Highest mode number for wideband and ultra-wideband, where the mode is the
+/// speex quality
+const WB_MAX_MODE: u8 = 10;
+
+/// Error type for using a speex SDP description
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SdpError {
+    /// The clock rate isn't 8000, 16000 or 32000
+    /// The parameter is the rate that was found
+    UnsupportedRate(u32),
+}
+
+impl Display for SdpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SdpError::UnsupportedRate(rate) => write!(f, "Unsupported speex clock rate ({rate})"),
+        }
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+/// The `vbr` fmtp parameter
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SdpVbr {
+    Off,
+    On,
+    /// Constant bitrate, with silence sent as short frames
+    Vad,
+}
+
+/// A value of the `mode` fmtp parameter
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SdpMode {
+    /// A mode number, as in the tables of RFC 5574
+    Mode(u8),
+    /// Every mode can be decoded
+    Any,
+}
+
+/// The SDP description of one speex payload type.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SpeexSdp {
+    pub payload_type: u8,
+    /// The RTP clock rate, which is the sample rate of the mode
+    pub clock_rate: u32,
+    /// Milliseconds of audio in each packet
+    pub ptime: Option<u32>,
+    /// Most milliseconds of audio in any packet
+    pub maxptime: Option<u32>,
+    pub vbr: Option<SdpVbr>,
+    /// Whether silence should be filled with comfort noise. Speex silence
+    /// frames always carry the noise level, so this doesn't change any
+    /// encoder settings.
+    pub cng: Option<bool>,
+    /// Modes in order of preference
+    pub modes: Vec<SdpMode>,
+}
+
+impl SpeexSdp {
+    /// Creates a description of a payload type in the given mode, with every
+    /// parameter left out.
+    pub fn new(payload_type: u8, mode: ModeId) -> Self {
+        let clock_rate = match mode {
+            ModeId::NarrowBand => 8000,
+            ModeId::WideBand => 16000,
+            ModeId::UltraWideBand => 32000,
+        };
+        Self {
+            payload_type,
+            clock_rate,
+            ptime: None,
+            maxptime: None,
+            vbr: None,
+            cng: None,
+            modes: Vec::new(),
+        }
+    }
+
+    /// Gets the mode matching the clock rate
+    pub fn mode_id(&self) -> Result<ModeId, SdpError> {
+        match self.clock_rate {
+            8000 => Ok(ModeId::NarrowBand),
+            16000 => Ok(ModeId::WideBand),
+            32000 => Ok(ModeId::UltraWideBand),
+            rate => Err(SdpError::UnsupportedRate(rate)),
+        }
+    }
+
+    /// Gets the number of frames to send in each packet, from `ptime`.
+    ///
+    /// The speex RTP drafts say a ptime that isn't a multiple of 20 ms MUST be
+    /// ignored, and the default of one frame per packet used instead.
+    pub fn frames_per_packet(&self) -> usize {
+        match self.ptime {
+            Some(ptime) if ptime % FRAME_MS == 0 => (ptime / FRAME_MS).max(1) as usize,
+            _ => 1,
+        }
+    }
+
+    /// Parses the speex payload types out of an SDP media description (an
+    /// `m=` line and the attributes that follow it).
+    ///
+    /// Payload types are returned in the order of the `m=` line. Other codecs,
+    /// speex at a clock rate it doesn't support, and anything that can't be
+    /// parsed are skipped. `a=ptime` and `a=maxptime` apply to every payload
+    /// type.
+    pub fn parse_media(media: &str) -> Vec<Self> {
+        let mut order: Vec<u8> = Vec::new();
+        let mut formats: Vec<Self> = Vec::new();
+        let mut fmtps = Vec::new();
+        let mut ptime = None;
+        let mut maxptime = None;
+
+        for line in media.lines().map(str::trim) {
+            if let Some(media) = line.strip_prefix("m=") {
+                order = media
+                    .split_whitespace()
+                    .skip(3)
+                    .filter_map(|format| format.parse().ok())
+                    .collect();
+            } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+                let Some((payload_type, encoding)) = split_payload_type(rtpmap) else {
+                    continue;
+                };
+                let mut encoding = encoding.split('/');
+                let name = encoding.next().unwrap_or_default();
+                let Some(clock_rate) = encoding.next().and_then(parse_number) else {
+                    continue;
+                };
+                let mut format = Self::new(payload_type, ModeId::NarrowBand);
+                format.clock_rate = clock_rate;
+                if name.eq_ignore_ascii_case("speex") && format.mode_id().is_ok() {
+                    formats.push(format);
+                }
+            } else if let Some(fmtp) = line.strip_prefix("a=fmtp:") {
+                fmtps.extend(split_payload_type(fmtp));
+            } else if let Some(value) = line.strip_prefix("a=ptime:") {
+                ptime = parse_number(value).or(ptime);
+            } else if let Some(value) = line.strip_prefix("a=maxptime:") {
+                maxptime = parse_number(value).or(maxptime);
+            }
+        }
+
+        for (payload_type, parameters) in fmtps {
+            if let Some(format) = formats
+                .iter_mut()
+                .find(|format| format.payload_type == payload_type)
+            {
+                format.parse_fmtp(parameters);
+            }
+        }
+        for format in &mut formats {
+            format.ptime = ptime;
+            format.maxptime = maxptime;
+        }
+        formats.sort_by_key(|format| {
+            order
+                .iter()
+                .position(|&payload_type| payload_type == format.payload_type)
+                .unwrap_or(usize::MAX)
+        });
+        formats
+    }
+
+    /// Writes an SDP media description for the given payload types, with
+    /// lines ending in CRLF.
+    ///
+    /// `a=ptime` and `a=maxptime` are shared by every payload type, so they
+    /// are taken from the first one.
+    pub fn write_media(port: u16, formats: &[Self]) -> String {
+        let mut media = format!("m=audio {port} RTP/AVP");
+        for format in formats {
+            media += &format!(" {}", format.payload_type);
+        }
+        media += "\r\n";
+        for format in formats {
+            for attribute in format.attributes() {
+                media += &attribute;
+                media += "\r\n";
+            }
+        }
+        if let Some(first) = formats.first() {
+            if let Some(ptime) = first.ptime {
+                media += &format!("a=ptime:{ptime}\r\n");
+            }
+            if let Some(maxptime) = first.maxptime {
+                media += &format!("a=maxptime:{maxptime}\r\n");
+            }
+        }
+        media
+    }
+
+    /// Gets the `a=rtpmap` and, if any parameters are set, `a=fmtp`
+    /// attributes for the payload type.
+    pub fn attributes(&self) -> Vec<String> {
+        let mut attributes = vec![format!(
+            "a=rtpmap:{} speex/{}",
+            self.payload_type, self.clock_rate
+        )];
+        let fmtp = self.fmtp();
+        if !fmtp.is_empty() {
+            attributes.push(format!("a=fmtp:{} {fmtp}", self.payload_type));
+        }
+        attributes
+    }
+
+    /// Gets the fmtp parameters, separated by semicolons
+    pub fn fmtp(&self) -> String {
+        let mut parameters = Vec::new();
+        if !self.modes.is_empty() {
+            let modes: Vec<String> = self
+                .modes
+                .iter()
+                .map(|mode| {
+                    match mode {
+                        SdpMode::Mode(mode) => mode.to_string(),
+                        SdpMode::Any => "any".to_string(),
+                    }
+                })
+                .collect();
+            parameters.push(format!("mode=\"{}\"", modes.join(",")));
+        }
+        if let Some(vbr) = self.vbr {
+            let vbr = match vbr {
+                SdpVbr::Off => "off",
+                SdpVbr::On => "on",
+                SdpVbr::Vad => "vad",
+            };
+            parameters.push(format!("vbr={vbr}"));
+        }
+        if let Some(cng) = self.cng {
+            parameters.push(format!("cng={}", if cng { "on" } else { "off" }));
+        }
+        parameters.join(";")
+    }
+
+    /// Parses fmtp parameters into this description.
+    ///
+    /// Unknown parameters, and values a parameter can't have, are ignored as
+    /// RFC 4566 requires.
+    pub fn parse_fmtp(&mut self, parameters: &str) {
+        let parameters = parameters
+            .split(';')
+            .filter_map(|parameter| parameter.split_once('='));
+        for (key, value) in parameters {
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "mode" => {
+                    let modes = value.trim_matches('"').split(',').map(str::trim);
+                    self.modes.extend(modes.filter_map(|mode| {
+                        if mode.eq_ignore_ascii_case("any") {
+                            Some(SdpMode::Any)
+                        } else {
+                            mode.parse().ok().map(SdpMode::Mode)
+                        }
+                    }));
+                }
+                "vbr" => {
+                    self.vbr = match value {
+                        "off" => Some(SdpVbr::Off),
+                        "on" => Some(SdpVbr::On),
+                        "vad" => Some(SdpVbr::Vad),
+                        _ => self.vbr,
+                    }
+                }
+                "cng" => {
+                    self.cng = match value {
+                        "off" => Some(false),
+                        "on" => Some(true),
+                        _ => self.cng,
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Gets the speex quality for the first mode the clock rate supports.
+    ///
+    /// Without any mode, RFC 5574 says to use mode 3 for narrowband and mode
+    /// 8 otherwise.
+    pub fn quality(&self) -> Result<i32, SdpError> {
+        let narrowband = self.mode_id()? == ModeId::NarrowBand;
+        let mode = self.modes.iter().find_map(|mode| {
+            match *mode {
+                SdpMode::Mode(mode) if narrowband && (1..=8).contains(&mode) => Some(mode),
+                SdpMode::Mode(mode) if !narrowband && mode <= WB_MAX_MODE => Some(mode),
+                _ => None,
+            }
+        });
+        Ok(match (narrowband, mode) {
+            (true, mode) => NB_MODE_QUALITY[mode.unwrap_or(3) as usize],
+            (false, mode) => mode.unwrap_or(8) as i32,
+        })
+    }
+
+    /// Creates an encoder to send to the agent this description came from,
+    /// and a decoder for what it sends back.
+    ///
+    /// The encoder uses the preferred mode and the `vbr` setting. The decoder
+    /// expects at most as many frames in a packet as `maxptime` allows.
+    pub fn codec_pair(&self) -> Result<(DynamicEncoder, DynamicDecoder), Error> {
+        let mode = self.mode_id()?;
+        let quality = self.quality()?;
+
+        let mut encoder = DynamicEncoder::new(mode);
+        encoder.set_quality(quality)?;
+        match self.vbr.unwrap_or(SdpVbr::Off) {
+            SdpVbr::Off => {}
+            SdpVbr::On => {
+                encoder.set_vbr(true)?;
+                encoder.set_vbr_quality(quality as f32)?;
+            }
+            SdpVbr::Vad => encoder.set_vad(true)?,
+        }
+
+        let mut decoder = DynamicDecoder::new(mode);
+        let max_frames = self
+            .maxptime
+            .map(|maxptime| maxptime.div_ceil(FRAME_MS).max(1) as usize);
+        decoder.set_frames_per_packet(max_frames);
+        Ok((encoder, decoder))
+    }
+}
+
+fn split_payload_type(attribute: &str) -> Option<(u8, &str)> {
+    let (payload_type, rest) = attribute
+        .split_once(char::is_whitespace)
+        .unwrap_or((attribute, ""));
+    Some((payload_type.trim().parse().ok()?, rest.trim()))
+}
+
+fn parse_number(value: &str) -> Option<u32> {
+    value.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Parses a media description, checks it against the expected payload
+    /// type, and checks that writing it out reads back the same
+    fn check_media(media: &str, expected: SpeexSdp) {
+        let formats = SpeexSdp::parse_media(media);
+        assert_eq!(formats, [expected]);
+
+        let written = SpeexSdp::write_media(8008, &formats);
+        assert_eq!(SpeexSdp::parse_media(&written), formats);
+    }
+
+    /// Like `check_media`, for an fmtp line without the rest of the media
+    /// description
+    fn check_fmtp(parameters: &str, expected: SpeexSdp) {
+        let mut format = SpeexSdp::new(97, ModeId::NarrowBand);
+        format.parse_fmtp(parameters);
+        assert_eq!(format, expected);
+
+        let mut reparsed = SpeexSdp::new(97, ModeId::NarrowBand);
+        reparsed.parse_fmtp(&format.fmtp());
+        assert_eq!(reparsed, format);
+    }
+
+    // The examples below are from draft-herlein-speex-rtp-profile-03 and
+    // draft-ietf-avt-rtp-speex-00 under speex/doc. Where the drafts misspell
+    // the rtpmap line as `a=rtmap`, only the fmtp line is checked.
+
+    #[test]
+    fn reads_draft_mode_example() {
+        let mut expected = SpeexSdp::new(97, ModeId::NarrowBand);
+        expected.modes = vec![SdpMode::Mode(4)];
+
+        check_media(
+            "m=audio 8008 RTP/AVP 97\na=rtpmap:97 speex/8000\na=fmtp:97 mode=4",
+            expected,
+        );
+    }
+
+    #[test]
+    fn reads_draft_repeated_modes() {
+        let mut expected = SpeexSdp::new(97, ModeId::NarrowBand);
+        expected.modes = vec![SdpMode::Any, SdpMode::Mode(1)];
+
+        check_fmtp("mode=any;mode=1", expected);
+    }
+
+    #[test]
+    fn ignores_draft_penh() {
+        // Perceptual enhancement is up to the decoder, so it isn't kept
+        let mut expected = SpeexSdp::new(97, ModeId::NarrowBand);
+        expected.modes = vec![SdpMode::Any];
+
+        check_fmtp("mode=any;penh=1", expected);
+    }
+
+    #[test]
+    fn reads_draft_vbr_example() {
+        let mut expected = SpeexSdp::new(97, ModeId::NarrowBand);
+        expected.vbr = Some(SdpVbr::On);
+        expected.cng = Some(true);
+
+        check_fmtp("vbr=on;cng=on", expected);
+    }
+
+    #[test]
+    fn reads_draft_ptime_example() {
+        let mut expected = SpeexSdp::new(97, ModeId::NarrowBand);
+        expected.ptime = Some(40);
+        assert_eq!(expected.frames_per_packet(), 2);
+
+        check_media(
+            "m=audio 8008 RTP/AVP 97\na=rtpmap:97 speex/8000\na=ptime:40",
+            expected,
+        );
+    }
+
+    #[test]
+    fn ignores_ptime_off_frame_boundaries() {
+        let mut format = SpeexSdp::new(97, ModeId::NarrowBand);
+        for (ptime, frames) in [(None, 1), (Some(0), 1), (Some(30), 1), (Some(60), 3)] {
+            format.ptime = ptime;
+            assert_eq!(format.frames_per_packet(), frames, "ptime {ptime:?}");
+        }
+    }
+
+    #[test]
+    fn parses_parameters() {
+        let formats = SpeexSdp::parse_media(
+            "m=audio 5004 RTP/AVP 0 98 97\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:97 \
+             speex/8000\r\na=rtpmap:98 SPEEX/32000\r\na=fmtp:98 mode=any; vbr=vad \
+             ;foo=bar\r\na=ptime:50\r\n",
+        );
+
+        assert_eq!(formats.len(), 2);
+        assert_eq!(formats[0].payload_type, 98);
+        assert_eq!(formats[0].mode_id(), Ok(ModeId::UltraWideBand));
+        assert_eq!(formats[0].modes, vec![SdpMode::Any]);
+        assert_eq!(formats[0].vbr, Some(SdpVbr::Vad));
+        assert_eq!(formats[0].ptime, Some(50));
+        assert_eq!(formats[1].payload_type, 97);
+        assert_eq!(formats[1].fmtp(), "");
+    }
+
+    #[test]
+    fn skips_what_it_cant_use() {
+        let formats = SpeexSdp::parse_media(
+            "m=audio 5004 RTP/AVP 97 x 98 99 101\na=rtpmap:98 speex/44100\na=rtpmap:97 \
+             speex/8000\na=fmtp:97 vbr=maybe;mode=\"x,2\";cng\na=rtpmap:99 speex\na=rtpmap:101 \
+             telephone-event/8000\na=fmtp:101\na=fmtp:x mode=1\na=ptime:often",
+        );
+
+        assert_eq!(formats.len(), 1);
+        assert_eq!(formats[0].payload_type, 97);
+        assert_eq!(formats[0].vbr, None);
+        assert_eq!(formats[0].cng, None);
+        assert_eq!(formats[0].modes, [SdpMode::Mode(2)]);
+        assert_eq!(formats[0].ptime, None);
+    }
+
+    #[test]
+    fn configures_codecs() {
+        let mut format = SpeexSdp::new(97, ModeId::NarrowBand);
+        format.parse_fmtp("mode=\"9,4\";vbr=on");
+        format.maxptime = Some(60);
+        let (mut encoder, decoder) = format.codec_pair().unwrap();
+
+        assert_eq!(format.quality(), Ok(5));
+        assert!(encoder.get_vbr().unwrap());
+        assert_eq!(encoder.get_vbr_quality().unwrap(), 5.0);
+        assert_eq!(decoder.frames_per_packet(), Some(3));
+
+        let mut format = SpeexSdp::new(97, ModeId::WideBand);
+        format.vbr = Some(SdpVbr::Vad);
+        let (mut encoder, decoder) = format.codec_pair().unwrap();
+
+        assert_eq!(format.quality(), Ok(8));
+        assert_eq!(encoder.mode_id(), ModeId::WideBand);
+        assert!(!encoder.get_vbr().unwrap());
+        assert!(encoder.get_vad().unwrap());
+        assert_eq!(decoder.frames_per_packet(), None);
+        assert_eq!(SpeexSdp::new(97, ModeId::NarrowBand).quality(), Ok(3));
+    }
+}
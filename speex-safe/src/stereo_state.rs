@@ -8,25 +8,52 @@
 use speex_sys::SpeexStereoState as SysStereoState;
 
 /// Handling for speex stereo files.
+///
+/// Holds the stereo information last received in-band, used to expand
+/// decoded mono frames back to stereo. See `SpeexDecoder::set_stereo_state`.
+///
+/// The state is a plain struct, so it is allocated on the Rust side (the same
+/// as `SPEEX_STEREO_STATE_INIT` would in C) instead of with
+/// `speex_stereo_state_init`. Boxing it keeps its address stable for the
+/// decoder's in-band handler when the state is moved.
 pub struct SpeexStereoState {
-    backing: SysStereoState,
+    backing: Box<SysStereoState>,
 }
 
 impl SpeexStereoState {
     /// Creates a new SpeexStereoState.
     pub fn new() -> Self {
-        let backing = unsafe {
-            let ptr = speex_sys::speex_stereo_state_init();
-            *ptr
-        };
-
+        // Same values as SPEEX_STEREO_STATE_INIT
+        let backing = Box::new(SysStereoState {
+            balance: 1.0,
+            e_ratio: 0.5,
+            smooth_left: 1.0,
+            smooth_right: 1.0,
+            reserved1: 0.0,
+            reserved2: 0.0,
+        });
         Self { backing }
     }
 
+    pub(crate) fn backing_mut_ptr(&mut self) -> *mut SysStereoState {
+        self.backing.as_mut() as *mut SysStereoState
+    }
+
     /// Resets a SpeexStereoState to its original state.
     pub fn reset(&mut self) {
-        let ptr = &mut self.backing as *mut SysStereoState;
-        unsafe { speex_sys::speex_stereo_state_reset(ptr) }
+        unsafe { speex_sys::speex_stereo_state_reset(self.backing_mut_ptr()) }
+    }
+
+    /// Gets the left/right balance, as the ratio of the energy of the left
+    /// channel to the right one.
+    pub fn balance(&self) -> f32 {
+        self.backing.balance
+    }
+
+    /// Gets the ratio of the energy of the mono downmix to the combined energy
+    /// of both channels.
+    pub fn energy_ratio(&self) -> f32 {
+        self.backing.e_ratio
     }
 }
 
@@ -36,10 +63,27 @@ impl Default for SpeexStereoState {
     }
 }
 
-impl Drop for SpeexStereoState {
-    fn drop(&mut self) {
-        unsafe {
-            speex_sys::speex_stereo_state_destroy(&mut self.backing);
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn starts_balanced() {
+        let state = SpeexStereoState::new();
+
+        assert_eq!(state.balance(), 1.0);
+        assert_eq!(state.energy_ratio(), 0.5);
+    }
+
+    #[test]
+    fn reset_restores_initial_values() {
+        let mut state = SpeexStereoState::new();
+        state.backing.balance = 4.0;
+        state.backing.e_ratio = 0.25;
+
+        state.reset();
+
+        assert_eq!(state.balance(), 1.0);
+        assert_eq!(state.energy_ratio(), 0.5);
     }
 }
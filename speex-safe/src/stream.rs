@@ -0,0 +1,357 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+use crate::{ControlError, DynamicEncoder, EncoderError, Error, Resampler, SpeexBits, SpeexHeader};
+
+/// Encoder that takes PCM of any length and produces complete packets.
+///
+/// Input is buffered until a whole frame is available, and frames are grouped
+/// into packets of `frames_per_packet` frames each. This should match the
+/// `frames_per_packet` written into the `SpeexHeader` of the stream. Every
+/// packet ends with a terminator, so a decoder can tell where the frames stop
+/// even when the last packet is short.
+///
+/// Stereo input is interleaved, and is encoded with intensity stereo (see
+/// `SpeexEncoder::encode_stereo`).
+///
+/// Input at a rate other than the encoder's can be resampled on the way in,
+/// see `set_input_rate`.
+pub struct StreamEncoder {
+    encoder: DynamicEncoder,
+    bits: SpeexBits<'static>,
+    frame_size: usize,
+    channels: usize,
+    frames_per_packet: usize,
+    frames_in_packet: usize,
+    pending: Vec<f32>,
+    resampler: Option<Resampler>,
+}
+
+impl StreamEncoder {
+    /// Creates a new stream encoder wrapping the given encoder.
+    ///
+    /// Returns `ControlError::InvalidParameter` if `frames_per_packet` is 0.
+    pub fn new(
+        encoder: impl Into<DynamicEncoder>,
+        frames_per_packet: usize,
+    ) -> Result<Self, ControlError> {
+        Self::with_channels(encoder, frames_per_packet, 1)
+    }
+
+    /// Creates a new stream encoder for interleaved stereo input.
+    ///
+    /// Returns `ControlError::InvalidParameter` if `frames_per_packet` is 0.
+    pub fn new_stereo(
+        encoder: impl Into<DynamicEncoder>,
+        frames_per_packet: usize,
+    ) -> Result<Self, ControlError> {
+        Self::with_channels(encoder, frames_per_packet, 2)
+    }
+
+    fn with_channels(
+        encoder: impl Into<DynamicEncoder>,
+        frames_per_packet: usize,
+        channels: usize,
+    ) -> Result<Self, ControlError> {
+        if frames_per_packet == 0 || !(1..=2).contains(&channels) {
+            return Err(ControlError::InvalidParameter);
+        }
+        let mut encoder = encoder.into();
+        let frame_size = encoder.get_frame_size()? as usize;
+        Ok(Self {
+            encoder,
+            bits: SpeexBits::new(),
+            frame_size,
+            channels,
+            frames_per_packet,
+            frames_in_packet: 0,
+            pending: Vec::with_capacity(frame_size * channels),
+            resampler: None,
+        })
+    }
+
+    /// Creates a stream encoder for the stream described by the header, see
+    /// `DynamicEncoder::from_header`.
+    ///
+    /// A header with no frames per packet set gets one frame per packet.
+    /// Returns `ControlError::InvalidParameter` if the header has a channel
+    /// count other than 1 or 2.
+    pub fn from_header(header: &SpeexHeader) -> Result<Self, Error> {
+        let encoder = DynamicEncoder::from_header(header)?;
+        let frames_per_packet = (header.frames_per_packet() as usize).max(1);
+        let channels = header.num_channels() as usize;
+        Ok(Self::with_channels(encoder, frames_per_packet, channels)?)
+    }
+
+    /// Gets the underlying encoder, e.g. to change its settings mid-stream
+    pub fn encoder_mut(&mut self) -> &mut DynamicEncoder {
+        &mut self.encoder
+    }
+
+    /// Gets the size (in samples) of a single frame
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Gets the number of channels in the input, 1 or 2
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Gets the number of frames grouped into each packet
+    pub fn frames_per_packet(&self) -> usize {
+        self.frames_per_packet
+    }
+
+    /// Sets the sample rate of the input, resampling it to the encoder's
+    /// sampling rate (see `set_sampling_rate`) with the given quality (see
+    /// `Resampler`).
+    ///
+    /// Input already pushed at the previous rate should be flushed first.
+    ///
+    /// Returns `ControlError::InvalidParameter` if either rate is 0 or the
+    /// quality is above `Resampler::QUALITY_MAX`.
+    pub fn set_input_rate(&mut self, rate: u32, quality: u32) -> Result<(), ControlError> {
+        let encoder_rate = self.encoder.get_sampling_rate()?;
+        if encoder_rate <= 0 {
+            return Err(ControlError::InvalidParameter);
+        }
+        let encoder_rate = encoder_rate as u32;
+        self.resampler = if rate == encoder_rate {
+            Resampler::check_parameters(rate, rate, self.channels, quality)?;
+            None
+        } else {
+            Some(Resampler::new(rate, encoder_rate, self.channels, quality)?)
+        };
+        Ok(())
+    }
+
+    /// Gets the number of samples (per channel) buffered that don't make up a
+    /// whole frame yet
+    pub fn pending_samples(&self) -> usize {
+        self.pending.len() / self.channels
+    }
+
+    /// Buffers the input and encodes every complete frame.
+    ///
+    /// Returns all packets that were completed by this input, which may be
+    /// none.
+    pub fn push(&mut self, input: &[f32]) -> Result<Vec<Vec<u8>>, EncoderError> {
+        match &mut self.resampler {
+            Some(resampler) => {
+                let mut resampled = Vec::new();
+                resampler.process(input, &mut resampled);
+                self.encode_input(&resampled)
+            }
+            None => self.encode_input(input),
+        }
+    }
+
+    /// Buffers the input and encodes every complete frame, using an integer
+    /// representation.
+    ///
+    /// Returns all packets that were completed by this input, which may be
+    /// none.
+    pub fn push_int(&mut self, input: &[i16]) -> Result<Vec<Vec<u8>>, EncoderError> {
+        let input: Vec<f32> = input.iter().map(|&sample| sample as f32).collect();
+        self.push(&input)
+    }
+
+    /// Encodes whatever is left in the buffer, padding the last frame with
+    /// silence, and emits the final partial packet.
+    ///
+    /// When resampling, the rest of the resampled input is encoded first,
+    /// which can complete a packet before the final one. Returns no packets
+    /// if there was nothing left to send.
+    pub fn flush(&mut self) -> Result<Vec<Vec<u8>>, EncoderError> {
+        let mut packets = match &mut self.resampler {
+            Some(resampler) => {
+                let mut resampled = Vec::new();
+                resampler.flush(&mut resampled);
+                self.encode_input(&resampled)?
+            }
+            None => Vec::new(),
+        };
+        if !self.pending.is_empty() {
+            self.pending.resize(self.frame_size * self.channels, 0.0);
+            if let Some(packet) = self.encode_pending()? {
+                packets.push(packet);
+                return Ok(packets);
+            }
+        }
+        if self.frames_in_packet > 0 {
+            packets.push(self.finish_packet());
+        }
+        Ok(packets)
+    }
+
+    fn encode_input(&mut self, input: &[f32]) -> Result<Vec<Vec<u8>>, EncoderError> {
+        let mut packets = Vec::new();
+        let mut input = input;
+        let frame_len = self.frame_size * self.channels;
+        while !input.is_empty() {
+            let needed = frame_len - self.pending.len();
+            let (chunk, rest) = input.split_at(needed.min(input.len()));
+            self.pending.extend_from_slice(chunk);
+            input = rest;
+            if self.pending.len() == frame_len {
+                if let Some(packet) = self.encode_pending()? {
+                    packets.push(packet);
+                }
+            }
+        }
+        Ok(packets)
+    }
+
+    fn encode_pending(&mut self) -> Result<Option<Vec<u8>>, EncoderError> {
+        let result = if self.channels == 2 {
+            self.encoder.encode_stereo(&self.pending, &mut self.bits)
+        } else {
+            self.encoder.encode(&self.pending, &mut self.bits)
+        };
+        // Keep the frame buffered if it couldn't be encoded
+        result?;
+        self.pending.clear();
+        self.frames_in_packet += 1;
+        if self.frames_in_packet == self.frames_per_packet {
+            Ok(Some(self.finish_packet()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn finish_packet(&mut self) -> Vec<u8> {
+        self.frames_in_packet = 0;
+        self.bits.take_packet()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DecoderError, ModeId, NbMode, SpeexDecoder, SpeexEncoder, SpeexHeaderBuilder};
+
+    fn count_frames(packet: &[u8]) -> usize {
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        let mut bits = SpeexBits::new();
+        let mut packet = packet.to_vec();
+        bits.read_from(&mut packet);
+        let mut out = vec![0.0; 160];
+        let mut frames = 0;
+        loop {
+            match decoder.decode(&mut bits, &mut out) {
+                Ok(()) => frames += 1,
+                Err(DecoderError::EndOfStream) => return frames,
+                Err(err) => panic!("unexpected decode error: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn groups_frames_into_packets() {
+        let mut stream = StreamEncoder::new(SpeexEncoder::<NbMode>::new(), 2).unwrap();
+
+        let packets = stream.push_int(&vec![100i16; 160 * 5 + 40]).unwrap();
+
+        assert_eq!(packets.len(), 2);
+        assert_eq!(stream.pending_samples(), 40);
+        for packet in &packets {
+            assert_eq!(count_frames(packet), 2);
+        }
+    }
+
+    #[test]
+    fn buffers_across_pushes() {
+        let mut stream = StreamEncoder::new(DynamicEncoder::new(ModeId::NarrowBand), 1).unwrap();
+
+        assert!(stream.push(&[0.0; 100]).unwrap().is_empty());
+        let packets = stream.push(&[0.0; 100]).unwrap();
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(stream.pending_samples(), 40);
+    }
+
+    #[test]
+    fn rejects_zero_frames_per_packet() {
+        assert_eq!(
+            StreamEncoder::new(SpeexEncoder::<NbMode>::new(), 0).err(),
+            Some(ControlError::InvalidParameter)
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_channel_counts() {
+        for channels in [0, 3] {
+            let header = SpeexHeaderBuilder::new(8000, channels, ModeId::NarrowBand)
+                .build()
+                .unwrap();
+            assert_eq!(
+                StreamEncoder::from_header(&header).err(),
+                Some(Error::Control(ControlError::InvalidParameter))
+            );
+        }
+    }
+
+    #[test]
+    fn flush_emits_partial_packet() {
+        let mut stream = StreamEncoder::new(SpeexEncoder::<NbMode>::new(), 3).unwrap();
+
+        assert!(stream.push_int(&vec![100i16; 160 + 10]).unwrap().is_empty());
+        let packets = stream.flush().unwrap();
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(count_frames(&packets[0]), 2);
+        assert_eq!(stream.pending_samples(), 0);
+        assert!(stream.flush().unwrap().is_empty());
+    }
+
+    #[test]
+    fn resamples_input() {
+        let tone = |rate: f32, len: usize| -> Vec<f32> {
+            (0..len)
+                .map(|i| (i as f32 * 2.0 * std::f32::consts::PI * 300.0 / rate).sin() * 8000.0)
+                .collect()
+        };
+        let mut stream = StreamEncoder::new(DynamicEncoder::new(ModeId::NarrowBand), 2).unwrap();
+        assert_eq!(
+            stream.set_input_rate(0, 3),
+            Err(ControlError::InvalidParameter)
+        );
+        assert_eq!(
+            stream.set_input_rate(48000, 11),
+            Err(ControlError::InvalidParameter)
+        );
+        stream.set_input_rate(48000, 3).unwrap();
+
+        let mut packets = stream.push(&tone(48000.0, 48000 / 5 + 100)).unwrap();
+        packets.extend(stream.flush().unwrap());
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        let mut out = Vec::new();
+        for packet in &packets {
+            decoder.decode_packet(packet, &mut out, None).unwrap();
+        }
+
+        // 200 ms at 8 kHz, and a bit more, padded to whole frames
+        assert_eq!(out.len(), 160 * 11);
+        let level = out[800..1600].iter().map(|s| s.abs()).fold(0.0, f32::max);
+        assert!((6000.0..10000.0).contains(&level), "level {level}");
+    }
+
+    #[test]
+    fn encodes_interleaved_stereo() {
+        let header = crate::SpeexHeaderBuilder::new(8000, 2, ModeId::NarrowBand)
+            .build()
+            .unwrap();
+        let mut stream = StreamEncoder::from_header(&header).unwrap();
+
+        let packets = stream.push(&vec![100.0; 160 * 2 + 20]).unwrap();
+
+        assert_eq!(stream.channels(), 2);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(stream.pending_samples(), 10);
+    }
+}
@@ -0,0 +1,30 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+//! Signals shared by the tests
+
+/// A sine wave of `len` samples per channel, interleaved
+pub(crate) fn sine(len: usize, channels: usize) -> Vec<f32> {
+    (0..len * channels)
+        .map(|i| ((i / channels) as f32 * 0.05).sin() * 8000.0)
+        .collect()
+}
+
+/// Uniform noise from a fixed seed
+pub(crate) fn noise(len: usize, level: f32, seed: &mut u32) -> Vec<f32> {
+    (0..len)
+        .map(|_| {
+            *seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            ((*seed >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * level
+        })
+        .collect()
+}
+
+/// The mean energy of the samples
+pub(crate) fn energy(samples: &[f32]) -> f32 {
+    samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32
+}
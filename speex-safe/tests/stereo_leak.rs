@@ -0,0 +1,80 @@
+////////////////////////////////////////////////////////////////////////////////
+// Copyright (c) 2023.                                                         /
+// This Source Code Form is subject to the terms of the Mozilla Public License,/
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can    /
+// obtain one at http://mozilla.org/MPL/2.0/.                                  /
+////////////////////////////////////////////////////////////////////////////////
+
+//! Checks that stereo states don't leak. This lives in its own test binary
+//! because it has to replace the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use speex_safe::{NbMode, SpeexBits, SpeexDecoder, SpeexEncoder, SpeexStereoState};
+
+/// Counts live allocations per thread, so tests running in parallel don't see
+/// each other's allocations.
+struct CountingAllocator;
+
+thread_local! {
+    static LIVE_ALLOCATIONS: Cell<isize> = const { Cell::new(0) };
+}
+
+fn adjust_live(by: isize) {
+    let _ = LIVE_ALLOCATIONS.try_with(|live| live.set(live.get() + by));
+}
+
+fn live_allocations() -> isize {
+    LIVE_ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        adjust_live(1);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        adjust_live(-1);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn drops_without_leaking() {
+    let before = live_allocations();
+    for _ in 0..1000 {
+        let mut state = SpeexStereoState::new();
+        state.reset();
+        drop(state);
+    }
+
+    assert_eq!(before, live_allocations());
+}
+
+#[test]
+fn decoder_drops_its_state() {
+    let mut encoder = SpeexEncoder::<NbMode>::new();
+    let input: Vec<i16> = (0..320).map(|i| ((i % 40) * 100) as i16).collect();
+    let mut out = vec![0i16; 320];
+
+    let before = live_allocations();
+    for _ in 0..100 {
+        let mut decoder = SpeexDecoder::<NbMode>::new();
+        decoder.set_stereo_state(SpeexStereoState::new()).unwrap();
+        // Replacing the state has to drop the old one
+        decoder.set_stereo_state(SpeexStereoState::new()).unwrap();
+        let mut bits = SpeexBits::new();
+        encoder.encode_stereo_int(&input, &mut bits).unwrap();
+        bits.rewind();
+        decoder.decode_stereo_int(&mut bits, &mut out).unwrap();
+        drop(bits);
+        drop(decoder);
+    }
+
+    assert_eq!(before, live_allocations());
+}
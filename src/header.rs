@@ -15,7 +15,7 @@ pub struct Header {
 }
 
 impl Header {
-    pub const MAGIC: &'static [u8; 8] = b"Speex   ";
+    pub const MAGIC: &'static [u8; 8] = speex_safe::SpeexHeader::MAGIC;
 
     pub fn get_version_string() -> &'static str {
         "1.2rc2"
@@ -1,5 +1,7 @@
-mod bits;
-mod header;
+pub mod bits;
+pub mod header;
+
+pub use speex_safe::ConversionError;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Mode {
@@ -8,13 +10,15 @@ pub enum Mode {
     UltraWideband,
 }
 
-impl From<i32> for Mode {
-    fn from(value: i32) -> Self {
+impl TryFrom<i32> for Mode {
+    type Error = ConversionError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
         match value {
-            0 => Mode::Narrowband,
-            1 => Mode::Wideband,
-            2 => Mode::UltraWideband,
-            _ => panic!("Unexpected value for Mode: {}", value),
+            0 => Ok(Mode::Narrowband),
+            1 => Ok(Mode::Wideband),
+            2 => Ok(Mode::UltraWideband),
+            _ => Err(ConversionError::InvalidMode(value)),
         }
     }
 }
@@ -24,8 +28,13 @@ mod tests {
     use super::*;
 
     #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    fn mode_from_i32() {
+        assert_eq!(Mode::try_from(0), Ok(Mode::Narrowband));
+        assert_eq!(Mode::try_from(2), Ok(Mode::UltraWideband));
+    }
+
+    #[test]
+    fn mode_from_invalid_i32() {
+        assert_eq!(Mode::try_from(3), Err(ConversionError::InvalidMode(3)));
     }
 }